@@ -14,7 +14,7 @@ fn main() {
     }
     protobuf_codegen::Codegen::new()
         .out_dir("src/protos")
-        .inputs(&[PROTO_FILE])
+        .inputs([PROTO_FILE])
         .include("protobufs")
         .customize(Customize::default()
             // We have our own protos.rs mod file, no need for a duplicate:
@@ -2,12 +2,11 @@
 
 pub(crate) mod sqlite;
 
-use crate::protos::Item;
+use crate::protos::{Item, ItemType};
 use core::str::FromStr;
-use std::{fmt::Display, io::{Read, Seek, SeekFrom}, marker::PhantomData};
+use std::{fmt::Display, io::{Read, Seek, SeekFrom}, marker::PhantomData, ops::Range};
 use actix_web::web::Bytes;
 use anyhow::{Error, bail, format_err};
-use bs58;
 use futures::Stream;
 use serde::{Deserialize, de::{self, Visitor}};
 use sizedisplay::SizeDisplay;
@@ -40,6 +39,63 @@ pub trait Factory: Send + Sync
     /// Open a single Backend connection.
     /// It is recommended that Factory implementions use their own connection pooling.
     fn open(&self) -> Result<Box<dyn Backend>, Error>;
+
+    /// Events for items saved by any Backend opened from this Factory (or its clones).
+    fn item_events(&self) -> &ItemEvents;
+
+    /// Statistics about this Factory's connection pool.
+    fn pool_stats(&self) -> PoolStats;
+}
+
+/// See: [`Factory::pool_stats`]
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats {
+    /// Connections currently open, including idle ones.
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub max_size: u32,
+
+    /// How many times we've waited for a connection from the pool.
+    pub gets: u64,
+
+    /// The total time spent waiting for those connections.
+    pub wait: std::time::Duration,
+}
+
+/// How many events a slow subscriber can fall behind before it starts missing them.
+const ITEM_EVENTS_CAPACITY: usize = 1024;
+
+/// An in-process broadcast of newly-saved items.
+///
+/// Backends must [`publish`](Self::publish) once [`Backend::save_user_item`] has
+/// committed, so that the server can push new items to listening clients.
+#[derive(Clone)]
+pub struct ItemEvents {
+    sender: tokio::sync::broadcast::Sender<NewItem>,
+}
+
+impl ItemEvents {
+    pub fn new() -> Self {
+        let (sender, _) = tokio::sync::broadcast::channel(ITEM_EVENTS_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, item: NewItem) {
+        // Only fails if nobody is listening, which is fine.
+        let _ = self.sender.send(item);
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<NewItem> {
+        self.sender.subscribe()
+    }
+}
+
+/// An item that was just saved. See: [`ItemEvents`]
+#[derive(Debug, Clone)]
+pub struct NewItem {
+    pub user: UserID,
+    pub signature: Signature,
+    pub timestamp: Timestamp,
 }
 
 /// Dumb hack to make dyn Factory impl Cloneable
@@ -68,10 +124,13 @@ pub trait Backend
     /// home page, which have timestamps before `before`.
     /// Items are returned through callback, and will continue to be fetched while callback continues
     /// to return Ok(true).
-    fn homepage_items<'a>(
+    ///
+    /// If `item_types` is not empty, only items of those types are returned. (Likewise for the other list methods.)
+    fn homepage_items(
         &self, 
         time_span: TimeSpan,
-        callback: &'a mut dyn FnMut(ItemDisplayRow) -> Result<bool,Error>
+        item_types: &[ItemType],
+        callback: &mut dyn FnMut(ItemDisplayRow) -> Result<bool,Error>
     ) -> Result<(), Error>;
 
     /// Find the most recent items for a particular user
@@ -79,6 +138,7 @@ pub trait Backend
         &self,
         user: &UserID,
         time_span: TimeSpan,
+        item_types: &[ItemType],
         callback: RowCallback<'a, ItemRow>,
     ) -> Result<(), Error>;
 
@@ -88,20 +148,43 @@ pub trait Backend
         user: &UserID,
         signature: &Signature,
         before: Timestamp,
+        item_types: &[ItemType],
+        callback: RowCallback<'a, ItemRow>,
+    ) -> Result<(), Error>;
+
+    /// Find the most recent items that notify a user: replies to any of their items, and items that mention
+    /// their user ID. The user's own items are excluded.
+    fn user_notification_items<'a>(
+        &self,
+        user_id: &UserID,
+        time_span: TimeSpan,
+        item_types: &[ItemType],
         callback: RowCallback<'a, ItemRow>,
     ) -> Result<(), Error>;
 
     /// Find the most recent items from users followed by the given user ID. Includes the users's own items too.
+    ///
+    /// If `group` is given, only finds items from users the user follows in that FollowGroup (by name).
     fn user_feed_items<'a>(
         &self,
         user_id: &UserID,
         time_span: TimeSpan,
+        item_types: &[ItemType],
+        group: Option<&str>,
         callback: RowCallback<'a, ItemDisplayRow>,
     ) -> Result<(), Error>;
 
     /// Find one particular UserItem
     fn user_item(&self, user: &UserID, signature: &Signature) -> Result<Option<ItemRow>, Error>;
 
+    /// Find many particular UserItems at once, in no particular order.
+    /// Items we don't have are skipped.
+    fn user_items_by_id<'a>(
+        &self,
+        ids: &[(UserID, Signature)],
+        callback: RowCallback<'a, ItemRow>,
+    ) -> Result<(), Error>;
+
     /// Effieicntly check whether a user item exists:
     fn user_item_exists(&self, user: &UserID, signature: &Signature) -> Result<bool, Error>;
 
@@ -128,12 +211,16 @@ pub trait Backend
     /// * The user is followed by a "server user". (We want their content so we can create a feed.)
     fn user_known(&self, user_id: &UserID) -> Result<bool, Error>;
 
+    /// Is this user followed by any "server user"?
+    fn followed_by_server_user(&self, user_id: &UserID) -> Result<bool, Error>;
+
     /// Check whether a user has remaiing quota/permissions to upload a particular item.
     fn quota_check_item(&self, user_id: &UserID, bytes: &[u8], item: &Item) -> Result<Option<QuotaDenyReason>, Error>;
 
     /// Get a Stream of the bytes of the file attachment.
+    /// If a `range` is given, only those bytes are streamed. It must be within the file.
     // TODO: Take refs.
-    fn get_contents(&self, user_id: UserID, signature: Signature, file_name: &str) -> Result<Option<FileStream>, Error>;
+    fn get_contents(&self, user_id: UserID, signature: Signature, file_name: &str, range: Option<Range<u64>>) -> Result<Option<FileStream>, Error>;
 
     fn get_attachment_meta(&self, user_id: &UserID, signature: &Signature, file_name: &str) -> Result<Option<FileMeta>, Error>;
 
@@ -141,16 +228,136 @@ pub trait Backend
     /// This assumes you have already validated the content's size and hash match those returned by get_attachment_meta().
     fn save_attachment(&self, size: u64, hash: &SHA512, file: &mut dyn Read) -> Result<(), Error>;
 
+    /// Start streaming an attachment of `size` bytes directly into our content store,
+    /// with write_attachment(), so it needn't be buffered elsewhere first.
+    ///
+    /// Returns None if this backend can't do that. Callers should use save_attachment() instead.
+    fn start_attachment(&self, _size: u64) -> Result<Option<AttachmentUpload>, Error> {
+        Ok(None)
+    }
+
+    /// Write bytes of an attachment, starting at `offset`.
+    fn write_attachment(&self, _upload: &AttachmentUpload, _offset: u64, _bytes: &[u8]) -> Result<(), Error> {
+        bail!("This backend doesn't support streaming attachments")
+    }
+
+    /// Make a fully-written attachment available under its hash.
+    /// This assumes you have already validated the content's size and hash match those returned by get_attachment_meta().
+    fn finish_attachment(&self, _upload: AttachmentUpload, _hash: &SHA512) -> Result<(), Error> {
+        bail!("This backend doesn't support streaming attachments")
+    }
+
+    /// Discard an attachment that was rejected, or failed to upload.
+    fn abort_attachment(&self, _upload: AttachmentUpload) -> Result<(), Error> {
+        bail!("This backend doesn't support streaming attachments")
+    }
+
+    /// Delete attachments that were left unfinished, ex: by a crash mid-upload.
+    /// Only safe to call when no uploads are in progress, like at startup.
+    /// Returns how many were deleted.
+    fn delete_unfinished_attachments(&self) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    /// Start a resumable upload, which later requests may continue with its token.
+    fn start_partial_upload(&self, upload: &PartialUpload) -> Result<(), Error>;
+
+    /// How many bytes of a resumable upload we've received, or None if there's no such upload.
+    fn partial_upload_offset(&self, upload: &PartialUpload) -> Result<Option<u64>, Error>;
+
+    /// Append bytes to a resumable upload at `offset`.
+    /// Returns the new offset, or None if `offset` isn't where the upload left off.
+    fn write_partial_upload(&self, upload: &PartialUpload, meta: &FileMeta, offset: u64, bytes: &[u8]) -> Result<Option<u64>, Error>;
+
+    /// Check the hash of a completed resumable upload, and move it into our content store.
+    /// Returns false (and discards the upload) if the hash doesn't match.
+    fn finish_partial_upload(&self, upload: &PartialUpload, meta: &FileMeta) -> Result<bool, Error>;
+
+    /// Delete resumable uploads that haven't been written to since `before`.
+    /// Returns how many were deleted.
+    fn expire_partial_uploads(&self, before: Timestamp) -> Result<usize, Error>;
+
+    /// Get a cached thumbnail of an attachment, which fits within `size` x `size` pixels.
+    fn get_thumbnail(&self, hash: &SHA512, size: u32) -> Result<Option<Thumbnail>, Error>;
+
+    /// Cache a thumbnail for get_thumbnail(). Replaces any existing one.
+    fn save_thumbnail(&self, hash: &SHA512, size: u32, thumbnail: &Thumbnail) -> Result<(), Error>;
+
     /// Report on database size usage by user.
     /// Results sorted by total size desc. 
     fn usage_by_user(&self, callback: RowCallback<'_, UsageByUserRow>) -> Result<(), Error>;
 
     /// Remove unused data from the database.
     fn prune(&self, opts: PruneOpts) -> Result<PruneResult, Error>;
+
+    /// Get the PEM-encoded private key used to sign ActivityPub requests for a user, if one exists.
+    fn activitypub_key(&self, user_id: &UserID) -> Result<Option<String>, Error>;
+
+    /// Save an ActivityPub key for a user, unless they already have one.
+    /// Returns whichever key is stored afterward.
+    fn save_activitypub_key(&self, user_id: &UserID, private_key_pem: &str) -> Result<String, Error>;
+
+    /// Add (or update) a remote ActivityPub actor that follows one of our users.
+    fn add_activitypub_follower(&self, follower: &ActivityPubFollower) -> Result<(), Error>;
+
+    fn remove_activitypub_follower(&self, user_id: &UserID, actor: &str) -> Result<(), Error>;
+
+    /// List remote ActivityPub actors that follow a user.
+    fn activitypub_followers<'a>(&self, user_id: &UserID, callback: RowCallback<'a, ActivityPubFollower>) -> Result<(), Error>;
+
+    /// List configured webhooks.
+    fn webhooks<'a>(&self, callback: RowCallback<'a, Webhook>) -> Result<(), Error>;
+
+    fn add_webhook(&self, url: &str, secret: &str) -> Result<Webhook, Error>;
+
+    /// Remove a webhook, and any deliveries still queued for it.
+    /// Returns false if no such webhook exists.
+    fn remove_webhook(&self, webhook_id: i64) -> Result<bool, Error>;
+
+    /// Queue a payload for delivery to every webhook.
+    /// Returns the number of deliveries queued.
+    fn queue_webhook_deliveries(&self, event: &str, payload: &[u8]) -> Result<usize, Error>;
+
+    /// Queue an ActivityPub activity for delivery to a follower's inbox, signed by a server user.
+    fn queue_activitypub_delivery(&self, user_id: &UserID, inbox: &str, activity: &[u8]) -> Result<(), Error>;
+
+    /// List queued deliveries whose next attempt is due at `now`, oldest first.
+    fn due_webhook_deliveries<'a>(&self, now: Timestamp, callback: RowCallback<'a, WebhookDelivery>) -> Result<(), Error>;
+
+    /// Remove a delivery from the queue, because it succeeded or we've given up on it.
+    fn remove_webhook_delivery(&self, delivery_id: i64) -> Result<(), Error>;
+
+    /// Record a failed delivery attempt, and when to try again.
+    fn retry_webhook_delivery(&self, delivery_id: i64, next_attempt: Timestamp, error: &str) -> Result<(), Error>;
+}
+
+/// An attachment being streamed into the content store. See: [`Backend::start_attachment`]
+#[derive(Clone)]
+pub struct AttachmentUpload {
+    /// Identifies the unfinished attachment to the backend.
+    pub key: Vec<u8>,
+    pub size: u64,
+}
+
+/// A resumable upload of an attachment. See: [`Backend::start_partial_upload`]
+#[derive(Clone)]
+pub struct PartialUpload {
+    pub user_id: UserID,
+    pub signature: Signature,
+    pub file_name: String,
+    /// A secret, given only to the client that started the upload.
+    pub token: String,
+}
+
+/// A scaled-down copy of an image attachment.
+pub struct Thumbnail {
+    /// The MIME type of `bytes`. Not necessarily the same as the original file's.
+    pub content_type: String,
+    pub bytes: Vec<u8>,
 }
 
 pub struct FileStream {
-    /// file size in bytes
+    /// Number of bytes in the stream. (The file size, unless a range was requested.)
     pub size: u64,
 
     /// Stream of Bytes from the file:
@@ -158,6 +365,7 @@ pub struct FileStream {
 }
 
 /// Metadata about a file attachment.
+#[derive(Clone)]
 pub struct FileMeta {
     /// The hash of the file's contents.
     pub hash: SHA512,
@@ -340,6 +548,10 @@ pub struct ItemRow {
 
     /// Bytes which can be deserialized into an Item.
     pub item_bytes: Vec<u8>,
+
+    /// True if the Item's markdown contains raw HTML or links that can run script.
+    /// See: [`crate::markdown`]
+    pub unsafe_markdown: bool,
 }
 
 /// An [`ItemRow`] that has extra information (fetched via joins)
@@ -347,9 +559,8 @@ pub struct ItemDisplayRow {
     pub item: ItemRow,
 
     // TODO: Make an Arc<String> to avoid heap allocs?
-    // Or just make filling this in optional, since that's only used by the old HTML UI.
+    // Or just make filling this in optional, since that's only used by feeds.
     /// The display name for the author of the item, if available.
-    #[allow(dead_code)]
     pub display_name: Option<String>
 }
 
@@ -362,6 +573,64 @@ pub struct ServerUser {
     pub on_homepage: bool,
 }
 
+/// A URL that we POST item & attachment events to.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+
+    /// Shared secret used to sign payloads.
+    pub secret: String,
+}
+
+/// A queued event for a [`Webhook`], or an activity for an ActivityPub inbox.
+#[derive(Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub target: DeliveryTarget,
+    pub event: String,
+    pub payload: Vec<u8>,
+
+    /// How many times we've already failed to deliver this.
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum DeliveryTarget {
+    Webhook(Webhook),
+
+    /// A follower's inbox. Requests are signed with `user`'s ActivityPub key.
+    ActivityPub{ user: UserID, inbox: String },
+}
+
+impl DeliveryTarget {
+    pub fn url(&self) -> &str {
+        match self {
+            Self::Webhook(webhook) => &webhook.url,
+            Self::ActivityPub{inbox, ..} => inbox,
+        }
+    }
+}
+
+/// A remote ActivityPub actor that follows a server user.
+#[derive(Debug, Clone)]
+pub struct ActivityPubFollower {
+    /// The (local) user being followed.
+    pub user: UserID,
+
+    /// The ID (URL) of the remote actor.
+    pub actor: String,
+
+    /// Where we deliver activities for this follower.
+    pub inbox: String,
+
+    /// When we accepted the Follow.
+    pub timestamp: Timestamp,
+
+    /// The ID of the Follow activity, so that we can find it when it's undone.
+    pub follow_id: Option<String>,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Timestamp {
     /// UNIX time, at UTC, in milliseconds:
@@ -411,7 +680,7 @@ impl std::fmt::Display for QuotaDenyReason {
 
 /// A 64-byte SHA-512 hash.
 /// Used by nacl internally, but also used by us for hashing file attachments.
-#[derive(PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct SHA512 {
     hash: sodiumoxide::crypto::hash::sha512::Digest,
 }
@@ -432,7 +701,7 @@ impl SHA512 {
     }
 
     pub fn bytes(&self) -> &[u8] {
-        return &self.hash.0
+        &self.hash.0
     }
 
     pub fn from_file<F>(file: &mut F) -> Result<Self, std::io::Error> 
@@ -475,10 +744,7 @@ pub enum TimeSpan {
 
 impl TimeSpan {
     pub fn is_before(&self) -> bool {
-        match self {
-            Self::Before(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::Before(_))
     }
 }
 
@@ -495,6 +761,10 @@ pub struct PruneOpts {
 
     /// Delete items from users who are no longer followed?
     pub items: bool,
+
+    /// Delete all cached thumbnails? (They'll be re-generated as needed.)
+    /// Thumbnails of deleted attachments are always deleted along with them.
+    pub thumbnails: bool,
 }
 
 
@@ -508,6 +778,9 @@ pub struct PruneResult {
 
     pub items_count: u64,
     pub items_bytes: u64,
+
+    pub thumbnails_count: u64,
+    pub thumbnails_bytes: u64,
 }
 
 impl Display for PruneResult {
@@ -521,7 +794,7 @@ impl Display for PruneResult {
             col!(Row: .name).right(),
             col!(Row: .count).header("Count").right(),
             col!(Row: .size).header("Size").right(),
-        ]).title(&title).borders(true);
+        ]).title(title).borders(true);
 
         struct Row {
             name: &'static str,
@@ -541,7 +814,14 @@ impl Display for PruneResult {
             size: SizeDisplay::bytes(self.items_count)
         }).map_err(|_| std::fmt::Error)?;
 
-        let footer = format!("Total size: {}", SizeDisplay::bytes(self.items_bytes + self.attachments_bytes));
+        stream.row(Row{
+            name: "Thumbnails",
+            count: self.thumbnails_count,
+            size: SizeDisplay::bytes(self.thumbnails_bytes),
+        }).map_err(|_| std::fmt::Error)?;
+
+        let total = self.items_bytes + self.attachments_bytes + self.thumbnails_bytes;
+        let footer = format!("Total size: {}", SizeDisplay::bytes(total));
         stream.footer(&footer).map_err(|_| std::fmt::Error)?;
 
         write!(f, "{}", String::from_utf8_lossy(&out))
@@ -563,5 +843,8 @@ pub struct UsageByUserRow {
     pub items_count: u64,
     pub items_bytes: u64,
 
+    /// Cached thumbnails of this user's attachments.
+    pub thumbnails_bytes: u64,
+
     pub total_bytes: u64,
 }
@@ -9,15 +9,15 @@
 
 mod upgraders;
 
-use std::{io::{Read, Write}, path::Path, collections::HashMap};
+use std::{io::{Read, Write}, ops::Range, path::Path, collections::HashMap, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
 
-use crate::{backend::UsageByUserRow, protos::Item, util::AsHex};
+use crate::{backend::UsageByUserRow, protos::{Item, ItemType}, util::AsHex};
 use actix_web::web::Bytes;
-use backend::{FileMeta, RowCallback, SHA512};
+use backend::{AttachmentUpload, FileMeta, ItemEvents, NewItem, PartialUpload, PoolStats, RowCallback, SHA512, Thumbnail};
 use log::{debug, warn};
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{named_params, params_from_iter, DatabaseName, OpenFlags};
-use sodiumoxide::randombytes::randombytes;
+use sodiumoxide::{crypto::hash::sha512, randombytes::randombytes};
 use crate::backend::{self, UserID, Signature, ItemRow, ItemDisplayRow, Timestamp, ServerUser, QuotaDenyReason};
 
 use anyhow::{Error, bail, Context};
@@ -25,21 +25,32 @@ use rusqlite::{params, OptionalExtension, Row};
 
 use super::{FileStream, PruneResult, TimeSpan};
 
-const CURRENT_VERSION: u32 = 7;
+const CURRENT_VERSION: u32 = 15;
 
 type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 type PConn = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
 pub(crate) struct FactoryBuilder {
-    sqlite_file: String
+    sqlite_file: String,
+    pool_max_size: u32,
+    pool_min_idle: u32,
 }
 
 impl FactoryBuilder {
     pub fn new(sqlite_file: String) -> Self {
         Self {
-            sqlite_file
+            sqlite_file,
+            pool_max_size: 10,
+            pool_min_idle: 0,
         }
     }
+
+    /// Set the size of the connection pool used by the Factory.
+    pub fn pool_size(mut self, max_size: u32, min_idle: u32) -> Self {
+        self.pool_max_size = max_size;
+        self.pool_min_idle = min_idle;
+        self
+    }
 }
 
 impl backend::FactoryBuilder for FactoryBuilder {
@@ -101,6 +112,7 @@ impl backend::FactoryBuilder for FactoryBuilder {
         let conn = Connection{ 
             conn: pool.get()?,
             pool: pool.clone(),
+            events: ItemEvents::new(),
         };
         conn.initialize()?;
         println!("Database created.");
@@ -129,6 +141,7 @@ impl FactoryBuilder {
             Connection { 
                 conn: pool.get()?,
                 pool,
+                events: ItemEvents::new(),
             }
         )
     }
@@ -139,11 +152,16 @@ impl FactoryBuilder {
 
     fn pool_builder(&self) -> r2d2::Builder<SqliteConnectionManager> {
         r2d2::Pool::builder()
-        .min_idle(Some(0)) // defaults to max_size. (Which defaults to 10.)
+        .max_size(self.pool_max_size)
+        .min_idle(Some(self.pool_min_idle)) // defaults to max_size.
     }
 
     fn build_factory(&self) -> Result<Factory, Error> {
-        Ok(Factory{ pool: self.pool()? })
+        Ok(Factory{
+            pool: self.pool()?,
+            events: ItemEvents::new(),
+            waits: Default::default(),
+        })
     }
 
     fn connection_manager(&self) -> r2d2_sqlite::SqliteConnectionManager {
@@ -163,10 +181,10 @@ impl FactoryBuilder {
         let new_mode: String = conn.conn.pragma_update_and_check(
             None,
             "journal_mode",
-            &wal_mode,
+            wal_mode,
             |row| { row.get(0) },
         )?;
-        if wal_mode != &new_mode {
+        if wal_mode != new_mode {
             warn!("Could not set journal_mode to WAL mode. Using {}", new_mode);
         } else {
             debug!("WAL mode set.");
@@ -179,25 +197,57 @@ impl FactoryBuilder {
 pub(crate) struct Factory
 {
     pool: Pool,
+    events: ItemEvents,
+    waits: Arc<PoolWaits>,
+}
+
+/// Tracks time spent waiting for pooled connections.
+#[derive(Default)]
+struct PoolWaits {
+    gets: AtomicU64,
+    micros: AtomicU64,
 }
 
 impl backend::Factory for Factory
 {
     fn open(&self) -> Result<Box<dyn backend::Backend>, Error>
     {
+        let start = Instant::now();
+        let conn = self.pool.get()?;
+        self.waits.gets.fetch_add(1, Ordering::Relaxed);
+        self.waits.micros.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+
         let conn = Connection{
-            conn: self.pool.get()?,
+            conn,
             pool: self.pool.clone(),
+            events: self.events.clone(),
         };
         Ok(Box::new(conn))
     }
 
     fn dyn_clone(&self) -> Box<dyn backend::Factory> {
         let new_factory = Factory {
-            pool: self.pool.clone()
+            pool: self.pool.clone(),
+            events: self.events.clone(),
+            waits: self.waits.clone(),
         };
         Box::new(new_factory)
     }
+
+    fn item_events(&self) -> &ItemEvents {
+        &self.events
+    }
+
+    fn pool_stats(&self) -> PoolStats {
+        let state = self.pool.state();
+        PoolStats {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_size: self.pool.max_size(),
+            gets: self.waits.gets.load(Ordering::Relaxed),
+            wait: Duration::from_micros(self.waits.micros.load(Ordering::Relaxed)),
+        }
+    }
 }
 
 
@@ -208,6 +258,8 @@ pub(crate) struct Connection
 
     // But also let's get an Arc copy of the pool in case we need to open more connections.
     pool: Pool,
+
+    events: ItemEvents,
 }
 
 
@@ -349,7 +401,7 @@ impl Connection
             "
         )?.query_row(
             params![],
-            |row|  Ok(row.get(0)?)
+            |row|  row.get(0)
         )?;
 
         if table_count == 0 {
@@ -361,12 +413,12 @@ impl Connection
         )?; 
         let versions = stmt.query_map(
             params![],
-            |row| -> rusqlite::Result<u32> { Ok(row.get(0)?) }
+            |row| -> rusqlite::Result<u32> { row.get(0) }
         )?;
 
         let versions: Vec<u32> = versions.take(2).collect::<rusqlite::Result<Vec<u32>>>()?;
 
-        if versions.len() == 0 {
+        if versions.is_empty() {
             bail!("Found no version in the database. This may not be a valid diskuto database.");
         }
         if versions.len() > 1 {
@@ -399,8 +451,8 @@ impl Connection
                 ORDER BY user_id, signature
             ")?;
             rows = stmt.query(named_params! {
-                "uid": uid.bytes(),
-                "sig": sig.bytes(),
+                ":uid": uid.bytes(),
+                ":sig": sig.bytes(),
             })?;
         } else {
             // Start from the beginning:
@@ -430,6 +482,8 @@ impl Connection
                 timestamp: Timestamp {  unix_utc_ms: row.get(2)? },
                 received: Timestamp {  unix_utc_ms: row.get(3)? },
                 item_bytes: row.get(4)?,
+                // Upgraders use all_items(), and may run before this column exists:
+                unsafe_markdown: false,
             };
             fetch_more = callback(ir)?;
         }
@@ -467,15 +521,17 @@ fn update_profile(conn: &rusqlite::Savepoint, item_row: &ItemRow, item: &Item) -
 
     // Behavior is undefined if duplicate follows exist in a Profile. So we just replace:
     let mut add_follow = conn.prepare("
-        INSERT OR REPLACE INTO follow (source_user_id, followed_user_id, display_name)
-        VALUES (?, ?, ?)
+        INSERT OR REPLACE INTO follow (source_user_id, followed_user_id, display_name, follow_group)
+        VALUES (?, ?, ?, ?)
     ")?;
 
-    for follow in &item.profile().follows {
+    let profile = item.profile();
+    for follow in &profile.follows {
         add_follow.execute(params![
             item_row.user.bytes(),
             follow.user.bytes,
             follow.display_name,
+            profile.follow_group_name(follow),
         ])?;
     }
 
@@ -525,26 +581,91 @@ fn save_reply_rows(conn: &rusqlite::Connection, replies: &[ReplyRow]) -> Result<
     Ok(())
 }
 
+/// A row from the `mention` table.
+struct MentionRow {
+    from_user_id: UserID,
+    from_signature: Signature,
+    to_user_id: UserID,
+}
+
+/// Find users mentioned by ID in an Item's markdown. (ex: in a `/u/<userID>/` link.)
+fn get_mention_rows(row: &ItemRow, item: &Item) -> Vec<MentionRow> {
+    let text = if item.has_post() {
+        item.post().body.as_str()
+    } else if item.has_comment() {
+        item.comment().text.as_str()
+    } else {
+        return vec![];
+    };
+
+    find_user_ids(text).into_iter()
+        .filter(|uid| uid != &row.user)
+        .map(|to_user_id| MentionRow {
+            from_user_id: row.user.clone(),
+            from_signature: row.signature.clone(),
+            to_user_id,
+        })
+        .collect()
+}
+
+/// Find (unique) base58-encoded user IDs within some text.
+fn find_user_ids(text: &str) -> Vec<UserID> {
+    // A 32-byte ID is 32-44 characters of base58. Anything else can't be a UserID:
+    let is_base58 = |c: char| c.is_ascii_alphanumeric() && !"0OIl".contains(c);
+    let mut found: Vec<UserID> = vec![];
+    for word in text.split(|c: char| !is_base58(c)) {
+        if word.len() < 32 || word.len() > 44 {
+            continue;
+        }
+        if let Ok(uid) = UserID::from_base58(word) {
+            if !found.contains(&uid) {
+                found.push(uid);
+            }
+        }
+    }
+    found
+}
+
+fn save_mention_rows(conn: &rusqlite::Connection, mentions: &[MentionRow]) -> Result<(), Error> {
+    // Already-indexed mentions (ex: from an upgrader re-scanning items) are skipped:
+    let mut stmt = conn.prepare("
+        INSERT OR IGNORE INTO mention (from_user_id, from_signature, to_user_id)
+        VALUES (?,?,?)
+    ")?;
+    for mention in mentions {
+        stmt.execute(params![
+            mention.from_user_id.bytes(),
+            mention.from_signature.bytes(),
+            mention.to_user_id.bytes(),
+        ])?;
+    }
+
+    Ok(())
+}
+
 
 impl backend::Backend for Connection
 {
-    fn homepage_items<'a>(
+    fn homepage_items(
         &self,
         time_span: TimeSpan,
-        callback: &'a mut dyn FnMut(ItemDisplayRow) -> Result<bool,Error>
+        item_types: &[ItemType],
+        callback: &mut dyn FnMut(ItemDisplayRow) -> Result<bool,Error>
     ) -> Result<(), Error> {
 
         let mut params = vec![];
+        let type_filter = item_type_filter("i.item_type", item_types);
         let query = match time_span {
             TimeSpan::Before(before) => {
                 params.push(before.unix_utc_ms);
-                "
+                format!("
                     SELECT
                         user_id
                         , i.signature
                         , unix_utc_ms
                         , received_utc_ms
                         , bytes
+                        , unsafe_markdown
                         , p.display_name
                     FROM item AS i
                     LEFT OUTER JOIN profile AS p USING (user_id)
@@ -554,18 +675,20 @@ impl backend::Backend for Connection
                         FROM server_user
                         WHERE on_homepage = 1
                     )
+                    {type_filter}
                     ORDER BY unix_utc_ms DESC, i.signature DESC
-                "
+                ", type_filter=type_filter)
             },
             TimeSpan::After(after) => {
                 params.push(after.unix_utc_ms);
-                "
+                format!("
                     SELECT
                         user_id
                         , i.signature
                         , unix_utc_ms
                         , received_utc_ms
                         , bytes
+                        , unsafe_markdown
                         , p.display_name
                     FROM item AS i
                     LEFT OUTER JOIN profile AS p USING (user_id)
@@ -575,12 +698,13 @@ impl backend::Backend for Connection
                         FROM server_user
                         WHERE on_homepage = 1
                     )
+                    {type_filter}
                     ORDER BY unix_utc_ms ASC, i.signature DESC
-                "
+                ", type_filter=type_filter)
             },
         };
 
-        let mut stmt = self.conn.prepare(query)?;
+        let mut stmt = self.conn.prepare(&query)?;
         let mut rows = stmt.query(params_from_iter(params))?;
 
 
@@ -592,11 +716,12 @@ impl backend::Backend for Connection
                 timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
                 received: Timestamp{ unix_utc_ms: row.get(3)? },
                 item_bytes: row.get(4)?,
+                unsafe_markdown: row.get(5)?,
             };
 
             Ok(ItemDisplayRow{
                 item,
-                display_name: row.get(5)?
+                display_name: row.get(6)?
             })
         };
 
@@ -609,54 +734,60 @@ impl backend::Backend for Connection
         Ok( () )
     }
 
-    fn user_items<'a>(
+    fn user_items(
         &self,
         user: &UserID,
         time_span: TimeSpan,
-        callback: &'a mut dyn FnMut(ItemRow) -> Result<bool,Error>
+        item_types: &[ItemType],
+        callback: &mut dyn FnMut(ItemRow) -> Result<bool,Error>
     ) -> Result<(), Error> {
 
         let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![];
+        let type_filter = item_type_filter("i.item_type", item_types);
         let query = match time_span {
             TimeSpan::Before(before) => {
                 params.push(Box::new(before.unix_utc_ms));
                 params.push(Box::new(user.bytes().to_vec()));
-                "
+                format!("
                     SELECT
                         i.user_id
                         , i.signature
                         , unix_utc_ms
                         , received_utc_ms
                         , bytes
+                        , unsafe_markdown
                     FROM item AS i
                     WHERE
                         unix_utc_ms < ?
                         AND user_id = ?
                         AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+                        {type_filter}
                     ORDER BY unix_utc_ms DESC, i.signature DESC
-                "
+                ", type_filter=type_filter)
             },
             TimeSpan::After(after) => {
                 params.push(Box::new(after.unix_utc_ms));
                 params.push(Box::new(user.bytes().to_vec()));
-                "
+                format!("
                     SELECT
                         i.user_id
                         , i.signature
                         , unix_utc_ms
                         , received_utc_ms
                         , bytes
+                        , unsafe_markdown
                     FROM item AS i
                     WHERE
                         unix_utc_ms > ?
                         AND user_id = ?
                         AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+                        {type_filter}
                     ORDER BY unix_utc_ms ASC, i.signature ASC
-                "          
+                ", type_filter=type_filter)
             }
         };
         
-        let mut stmt = self.conn.prepare(query)?;
+        let mut stmt = self.conn.prepare(&query)?;
         let mut rows = stmt.query(params_from_iter(params))?;
 
         let convert = |row: &Row<'_>| -> Result<ItemRow, Error> {
@@ -666,6 +797,7 @@ impl backend::Backend for Connection
                 timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
                 received: Timestamp{ unix_utc_ms: row.get(3)? },
                 item_bytes: row.get(4)?,
+                unsafe_markdown: row.get(5)?,
             };
 
             Ok(item)
@@ -685,15 +817,17 @@ impl backend::Backend for Connection
         user: &UserID,
         signature: &Signature,
         before: Timestamp,
+        item_types: &[ItemType],
         callback: RowCallback<'a, ItemRow>,
     ) -> Result<(), Error> {
-        let mut stmt = self.conn.prepare("
+        let mut stmt = self.conn.prepare(&format!("
             SELECT
                 i.user_id
                 , i.signature
                 , unix_utc_ms
                 , received_utc_ms
                 , bytes
+                , unsafe_markdown
             FROM item AS i
             INNER JOIN reply AS r ON (
                 r.from_user_id = i.user_id
@@ -704,8 +838,9 @@ impl backend::Backend for Connection
                 AND r.to_user_id = ?
                 AND r.to_signature = ?
                 AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+                {type_filter}
             ORDER BY unix_utc_ms DESC, i.signature DESC
-        ")?;
+        ", type_filter=item_type_filter("i.item_type", item_types)))?;
 
         let mut rows = stmt.query(params![
             before.unix_utc_ms,
@@ -720,6 +855,91 @@ impl backend::Backend for Connection
                 timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
                 received: Timestamp{ unix_utc_ms: row.get(3)? },
                 item_bytes: row.get(4)?,
+                unsafe_markdown: row.get(5)?,
+            };
+
+            Ok(item)
+        };
+
+        while let Some(row) = rows.next()? {
+            let item = convert(row)?;
+            let result = callback(item)?;
+            if !result { break; }
+        }
+
+        Ok( () )
+    }
+
+    fn user_notification_items<'a>(
+        &self,
+        user_id: &UserID,
+        time_span: TimeSpan,
+        item_types: &[ItemType],
+        callback: RowCallback<'a, ItemRow>,
+    ) -> Result<(), Error> {
+        let timestamp;
+        let ts_order;
+        let filter_ts;
+        match time_span {
+            TimeSpan::Before(ts) => {
+                timestamp = ts;
+                filter_ts = "unix_utc_ms < :timestamp";
+                ts_order = "DESC";
+            },
+            TimeSpan::After(ts) => {
+                timestamp = ts;
+                filter_ts = "unix_utc_ms > :timestamp";
+                ts_order = "ASC";
+            }
+        };
+
+        // Start from the (indexed) reply & mention tables rather than scanning all items:
+        let query = format!(
+            "
+                WITH notification (user_id, signature) AS (
+                    SELECT from_user_id, from_signature
+                    FROM reply
+                    WHERE to_user_id = :user_id
+                    UNION
+                    SELECT from_user_id, from_signature
+                    FROM mention
+                    WHERE to_user_id = :user_id
+                )
+                SELECT
+                    i.user_id
+                    , i.signature
+                    , unix_utc_ms
+                    , received_utc_ms
+                    , bytes
+                    , unsafe_markdown
+                FROM notification AS n
+                INNER JOIN item AS i USING (user_id, signature)
+                WHERE
+                    {filter_ts}
+                    AND i.user_id != :user_id
+                    AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+                    {type_filter}
+                ORDER BY unix_utc_ms {ts_order}, i.signature {ts_order}
+            ",
+            filter_ts=filter_ts,
+            ts_order=ts_order,
+            type_filter=item_type_filter("i.item_type", item_types),
+        );
+
+        let mut stmt = self.conn.prepare(&query)?;
+        let mut rows = stmt.query(named_params! {
+            ":timestamp": timestamp.unix_utc_ms,
+            ":user_id": user_id.bytes(),
+        })?;
+
+        let convert = |row: &Row<'_>| -> Result<ItemRow, Error> {
+            let item = ItemRow{
+                user: UserID::from_vec(row.get(0)?)?,
+                signature: Signature::from_vec(row.get(1)?)?,
+                timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
+                received: Timestamp{ unix_utc_ms: row.get(3)? },
+                item_bytes: row.get(4)?,
+                unsafe_markdown: row.get(5)?,
             };
 
             Ok(item)
@@ -738,6 +958,8 @@ impl backend::Backend for Connection
         &self,
         user_id: &UserID,
         time_span: TimeSpan,
+        item_types: &[ItemType],
+        group: Option<&str>,
         callback: RowCallback<'a, ItemDisplayRow>,
     ) -> Result<(), Error> {
 
@@ -764,7 +986,7 @@ impl backend::Backend for Connection
         // against those indexes and merge them with a UNION ALL. This forces SQLite to walk & merge them like should
         // scale well, vs... whatever it was trying to do.
 
-        let follows = get_follows(&self, user_id)?;
+        let follows = get_follows(self, user_id, group)?;
         let subselects: Vec<String> = follows.keys().map(|uid| {
             format!(
                 "
@@ -788,13 +1010,16 @@ impl backend::Backend for Connection
                         , unix_utc_ms
                         , received_utc_ms
                         , bytes
+                        , unsafe_markdown
                     FROM item
                     WHERE {filter_ts}
+                    {type_filter}
                 )
                 {subselects}
                 ORDER BY unix_utc_ms {ts_order}, signature {ts_order}
             ", 
             filter_ts=filter_ts,
+            type_filter=item_type_filter("item_type", item_types),
             ts_order=ts_order,
             subselects=subselects.join("\n\nUNION ALL\n")
         );
@@ -813,10 +1038,11 @@ impl backend::Backend for Connection
                 timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
                 received: Timestamp{ unix_utc_ms: row.get(3)? },
                 item_bytes: row.get(4)?,
+                unsafe_markdown: row.get(5)?,
             };
 
             Ok(ItemDisplayRow{
-                display_name: follows.get(&item.user).map(|info| info.display_name.clone()).flatten(),
+                display_name: follows.get(&item.user).and_then(|info| info.display_name.clone()),
                 item,
             })
         };
@@ -901,7 +1127,7 @@ impl backend::Backend for Connection
                 user.bytes(),
                 signature.bytes(),
             ],
-            |row| { Ok(row.get(0)?) }
+            |row| { row.get(0) }
         )?;
 
         if count > 1 {
@@ -919,6 +1145,7 @@ impl backend::Backend for Connection
                 , unix_utc_ms
                 , received_utc_ms
                 , bytes
+                , unsafe_markdown
             FROM item AS i
             WHERE user_id = ?
             AND signature = ?
@@ -941,6 +1168,7 @@ impl backend::Backend for Connection
             timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
             received: Timestamp{ unix_utc_ms: row.get(3)? },
             item_bytes: row.get(4)?,
+            unsafe_markdown: row.get(5)?,
         };
 
         if rows.next()?.is_some() {
@@ -950,6 +1178,55 @@ impl backend::Backend for Connection
         Ok(Some(item))
     }
 
+    fn user_items_by_id<'a>(
+        &self,
+        ids: &[(UserID, Signature)],
+        callback: RowCallback<'a, ItemRow>,
+    ) -> Result<(), Error> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        // Each ID is 2 parameters. SQLite allows at most 32766, so callers should limit batch sizes.
+        let values = vec!["(?, ?)"; ids.len()].join(", ");
+        let query = format!("
+            WITH wanted(user_id, signature) AS (VALUES {})
+            SELECT
+                i.user_id
+                , i.signature
+                , unix_utc_ms
+                , received_utc_ms
+                , bytes
+                , unsafe_markdown
+            FROM wanted AS w
+            INNER JOIN item AS i ON (i.user_id = w.user_id AND i.signature = w.signature)
+            WHERE EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+        ", values);
+
+        let params = params_from_iter(
+            ids.iter().flat_map(|(user_id, signature)| [user_id.bytes(), signature.bytes()])
+        );
+
+        let mut stmt = self.conn.prepare(&query)?;
+        let mut rows = stmt.query(params)?;
+
+        while let Some(row) = rows.next()? {
+            let item = ItemRow{
+                user: UserID::from_vec(row.get(0)?)?,
+                signature: Signature::from_vec(row.get(1)?)?,
+                timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
+                received: Timestamp{ unix_utc_ms: row.get(3)? },
+                item_bytes: row.get(4)?,
+                unsafe_markdown: row.get(5)?,
+            };
+            if !callback(item)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
     fn save_user_item(&mut self, row: &ItemRow, item: &Item) -> Result<(), Error>
     {
         let tx = self.conn.savepoint().context("getting a transaction")?;
@@ -961,7 +1238,9 @@ impl backend::Backend for Connection
                 , unix_utc_ms
                 , received_utc_ms
                 , bytes
-            ) VALUES (?, ?, ?, ?, ?);
+                , unsafe_markdown
+                , item_type
+            ) VALUES (?, ?, ?, ?, ?, ?, ?);
        ";
 
         tx.execute(stmt, params![
@@ -970,6 +1249,8 @@ impl backend::Backend for Connection
             row.timestamp.unix_utc_ms,
             row.received.unix_utc_ms,
             row.item_bytes.as_slice(),
+            row.unsafe_markdown,
+            item.get_type() as i32,
         ])?;
 
         if item.has_profile() {
@@ -980,9 +1261,17 @@ impl backend::Backend for Connection
             save_comment_reply(&tx, row, item)?;
         }
 
+        save_mention_rows(&tx, &get_mention_rows(row, item))?;
+
         index_attachments(&tx, row, item)?;
 
         tx.commit().context("committing")?;
+
+        self.events.publish(NewItem{
+            user: row.user.clone(),
+            signature: row.signature.clone(),
+            timestamp: row.timestamp,
+        });
         Ok(())
     }
 
@@ -1053,6 +1342,18 @@ impl backend::Backend for Connection
         Ok(row.get(0)?)
     }
 
+    fn followed_by_server_user(&self, user_id: &UserID) -> Result<bool, Error> {
+        let followed = self.conn.query_row("
+            SELECT EXISTS(
+                SELECT followed_user_id
+                FROM follow AS f
+                INNER JOIN server_user AS su ON (f.source_user_id = su.user_id)
+                WHERE followed_user_id = ?
+            )
+        ", [user_id.bytes()], |row| row.get(0))?;
+        Ok(followed)
+    }
+
     fn quota_check_item(&self, user_id: &UserID, _bytes: &[u8], _item: &Item) -> Result<Option<QuotaDenyReason>, Error> {
         
         if self.server_user(user_id)?.is_some() {
@@ -1084,7 +1385,7 @@ impl backend::Backend for Connection
         Ok(Some(QuotaDenyReason::UnknownUser))
     }
    
-    fn get_contents(&self, user_id: UserID, signature: Signature, file_name: &str) 
+    fn get_contents(&self, user_id: UserID, signature: Signature, file_name: &str, range: Option<Range<u64>>) 
     -> Result< Option<FileStream> , Error> 
     {
         let mut stmt = self.conn.prepare("
@@ -1124,12 +1425,18 @@ impl backend::Backend for Connection
         drop(rows);
         drop(stmt);
 
+        let range = range.unwrap_or(0..size);
+        if range.start > range.end || range.end > size {
+            bail!("Range {:?} is not within the file's {} bytes", range, size);
+        }
 
         // Open a new pooled connection that will be owned just by our Iterator/Stream:
         // TODO: Maybe we should just re-open the connection every time if we have to for the BLOB too?
         let conn = self.pool.get()?;
-        let mut buf = [0 as u8; 32 * 1024];
-        let mut read_pos = 0;
+        let mut buf = [0_u8; 32 * 1024];
+        // Incremental BLOB I/O lets us start reading anywhere, without reading what came before:
+        let mut read_pos = range.start as usize;
+        let end = range.end as usize;
 
         let iter = std::iter::from_fn(move || -> Option<Result<Bytes,crate::server::SendError>> {
             // Have to re-open the BLOB every time because it's not Send (due to its lifetime on &Connection?).
@@ -1146,7 +1453,12 @@ impl backend::Backend for Connection
                 Err(err) => return Some(Err(err.into())),
             };
     
-            let bytes_read = match blob.read_at(&mut buf, read_pos) {
+            let want = buf.len().min(end - read_pos);
+            if want == 0 {
+                return None;
+            }
+
+            let bytes_read = match blob.read_at(&mut buf[..want], read_pos) {
                 Err(io_err) => return Some(Err(io_err.into())),
                 Ok(x) => x,
             };
@@ -1157,12 +1469,12 @@ impl backend::Backend for Connection
             }
 
             let bytes = Bytes::copy_from_slice(&buf[..bytes_read]);
-            return Some(Ok(bytes));
+            Some(Ok(bytes))
         });
 
         let stream = blocking::Unblock::with_capacity(2, iter);
         let stream = Box::new(stream);
-        Ok(Some(FileStream{stream, size}))
+        Ok(Some(FileStream{stream, size: range.end - range.start}))
     }
 
     fn get_attachment_meta(&self, user_id: &UserID, signature: &Signature, file_name: &str) -> Result<Option<backend::FileMeta>, Error> {
@@ -1269,6 +1581,199 @@ impl backend::Backend for Connection
         Ok(())
     }
 
+    fn start_attachment(&self, size: u64) -> Result<Option<AttachmentUpload>, Error> {
+        // As in save_attachment(), the zeroblob lives under a temporary hash until it's complete.
+        // We don't hold a transaction open while we wait for a (possibly slow) upload.
+        let temp_hash = randombytes(31);
+        self.conn.execute(
+            "INSERT INTO store (hash, contents) VALUES(?, zeroblob(?))",
+            params![&temp_hash, size as i64],
+        )?;
+
+        Ok(Some(AttachmentUpload{ key: temp_hash, size }))
+    }
+
+    fn write_attachment(&self, upload: &AttachmentUpload, offset: u64, bytes: &[u8]) -> Result<(), Error> {
+        let end = offset + bytes.len() as u64;
+        if end > upload.size {
+            bail!("Write of {} bytes would be larger than the file's {} bytes", end, upload.size);
+        }
+
+        let row_id: i64 = self.conn.query_row(
+            "SELECT rowid FROM store WHERE hash = ?",
+            params![&upload.key],
+            |row| row.get(0)
+        )?;
+        let mut blob = self.conn.blob_open(DatabaseName::Main, "store", "contents", row_id, false)?;
+        blob.write_all_at(bytes, offset as usize)?;
+
+        Ok(())
+    }
+
+    fn finish_attachment(&self, upload: AttachmentUpload, hash: &SHA512) -> Result<(), Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM store WHERE hash = ?)",
+            params![hash.bytes()],
+            |row| row.get(0),
+        )?;
+
+        let updated = if exists {
+            // Someone else uploaded the same file in the meantime:
+            tx.execute("DELETE FROM store WHERE hash = ?", params![&upload.key])?
+        } else {
+            tx.execute("UPDATE store SET hash = ? WHERE hash = ?", params![hash.bytes(), &upload.key])?
+        };
+        if updated != 1 {
+            bail!("Error updating content hash from {:?} to {}", upload.key, hash);
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn abort_attachment(&self, upload: AttachmentUpload) -> Result<(), Error> {
+        self.conn.execute("DELETE FROM store WHERE hash = ?", params![&upload.key])?;
+        Ok(())
+    }
+
+    fn delete_unfinished_attachments(&self) -> Result<usize, Error> {
+        // Only the temporary hashes used by save_attachment() and start_attachment() are 31 bytes:
+        let deleted = self.conn.execute("DELETE FROM store WHERE length(hash) = 31", params![])?;
+        Ok(deleted)
+    }
+
+    fn start_partial_upload(&self, upload: &PartialUpload) -> Result<(), Error> {
+        self.conn.execute(
+            "
+            INSERT INTO partial_upload(token, user_id, signature, name, received, updated_ms_utc)
+            VALUES (?, ?, ?, ?, 0, ?)
+            ",
+            params![upload.token, upload.user_id.bytes(), upload.signature.bytes(), upload.file_name, Timestamp::now().unix_utc_ms],
+        )?;
+        Ok(())
+    }
+
+    fn partial_upload_offset(&self, upload: &PartialUpload) -> Result<Option<u64>, Error> {
+        Ok(partial_upload_row(&self.conn, upload)?.map(|(_, received)| received))
+    }
+
+    fn write_partial_upload(&self, upload: &PartialUpload, meta: &FileMeta, offset: u64, bytes: &[u8])
+    -> Result<Option<u64>, Error>
+    {
+        let end = offset + bytes.len() as u64;
+        if end > meta.size {
+            bail!("Upload of {} bytes would be larger than the file's {} bytes", end, meta.size);
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        let upload_id = match partial_upload_row(&tx, upload)? {
+            Some((upload_id, received)) if received == offset => upload_id,
+            _ => return Ok(None),
+        };
+        if bytes.is_empty() {
+            return Ok(Some(offset));
+        }
+
+        // Storage grows as bytes arrive, so an abandoned upload only takes up what was sent:
+        tx.execute(
+            "INSERT INTO partial_upload_chunk(upload_id, start, contents) VALUES (?, ?, ?)",
+            params![upload_id, offset as i64, bytes],
+        )?;
+        tx.execute(
+            "UPDATE partial_upload SET received = ?, updated_ms_utc = ? WHERE upload_id = ?",
+            params![end as i64, Timestamp::now().unix_utc_ms, upload_id],
+        )?;
+        tx.commit()?;
+
+        Ok(Some(end))
+    }
+
+    fn finish_partial_upload(&self, upload: &PartialUpload, meta: &FileMeta) -> Result<bool, Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let (upload_id, received) = match partial_upload_row(&tx, upload)? {
+            Some(row) => row,
+            None => bail!("No such upload"),
+        };
+        if received != meta.size {
+            bail!("Upload is incomplete. Received {} of {} bytes", received, meta.size);
+        }
+
+        let chunks = "SELECT start, contents FROM partial_upload_chunk WHERE upload_id = ? ORDER BY start";
+        let mut hasher = sha512::State::new();
+        {
+            let mut stmt = tx.prepare(chunks)?;
+            let mut rows = stmt.query(params![upload_id])?;
+            while let Some(row) = rows.next()? {
+                hasher.update(row.get_ref(1)?.as_blob()?);
+            }
+        }
+        let hash = SHA512::from_digest(hasher.finalize());
+
+        let matches = hash == meta.hash;
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM store WHERE hash = ?)",
+            params![hash.bytes()],
+            |row| row.get(0),
+        )?;
+        // (Someone may have uploaded the same file in the meantime.)
+        if matches && !exists {
+            tx.execute(
+                "INSERT INTO store(hash, contents) VALUES (?, zeroblob(?))",
+                params![hash.bytes(), meta.size as i64],
+            )?;
+            let mut blob = tx.blob_open(DatabaseName::Main, "store", "contents", tx.last_insert_rowid(), false)?;
+            let mut stmt = tx.prepare(chunks)?;
+            let mut rows = stmt.query(params![upload_id])?;
+            while let Some(row) = rows.next()? {
+                let start: i64 = row.get(0)?;
+                blob.write_all_at(row.get_ref(1)?.as_blob()?, start as usize)?;
+            }
+        }
+        tx.execute("DELETE FROM partial_upload_chunk WHERE upload_id = ?", params![upload_id])?;
+        tx.execute("DELETE FROM partial_upload WHERE upload_id = ?", params![upload_id])?;
+        tx.commit()?;
+
+        Ok(matches)
+    }
+
+    fn expire_partial_uploads(&self, before: Timestamp) -> Result<usize, Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "
+            DELETE FROM partial_upload_chunk
+            WHERE upload_id IN (SELECT upload_id FROM partial_upload WHERE updated_ms_utc < ?)
+            ",
+            params![before.unix_utc_ms],
+        )?;
+        let deleted = tx.execute(
+            "DELETE FROM partial_upload WHERE updated_ms_utc < ?",
+            params![before.unix_utc_ms],
+        )?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn get_thumbnail(&self, hash: &SHA512, size: u32) -> Result<Option<Thumbnail>, Error> {
+        let thumbnail = self.conn.query_row(
+            "SELECT content_type, bytes FROM thumbnail WHERE hash = ? AND size = ?",
+            params![hash.bytes(), size],
+            |row| Ok(Thumbnail{
+                content_type: row.get(0)?,
+                bytes: row.get(1)?,
+            }),
+        ).optional()?;
+        Ok(thumbnail)
+    }
+
+    fn save_thumbnail(&self, hash: &SHA512, size: u32, thumbnail: &Thumbnail) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO thumbnail(hash, size, content_type, bytes) VALUES (?, ?, ?, ?)",
+            params![hash.bytes(), size, thumbnail.content_type, thumbnail.bytes],
+        )?;
+        Ok(())
+    }
+
     fn prune(&self, opts: backend::PruneOpts) -> Result<backend::PruneResult, Error> {
         
         let mut result = PruneResult{
@@ -1277,6 +1782,8 @@ impl backend::Backend for Connection
             attachments_count: 0,
             items_bytes: 0,
             items_count: 0,
+            thumbnails_bytes: 0,
+            thumbnails_count: 0,
         };
 
         if opts.items {
@@ -1341,6 +1848,42 @@ impl backend::Backend for Connection
             result.attachments_bytes = bytes;
         }
 
+        if opts.thumbnails || opts.attachments {
+            let query = if opts.thumbnails {
+                "SELECT COUNT(*), COALESCE(SUM(LENGTH(bytes)), 0) FROM thumbnail"
+            } else if opts.items {
+                "
+                SELECT COUNT(*), COALESCE(SUM(LENGTH(bytes)), 0)
+                FROM thumbnail AS t
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM item_attachment
+                    INNER JOIN item USING (user_id, signature)
+                    INNER JOIN known_users USING (user_id)
+                    WHERE hash = t.hash
+                )
+                "
+            } else {
+                "
+                SELECT COUNT(*), COALESCE(SUM(LENGTH(bytes)), 0)
+                FROM thumbnail AS t
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM item_attachment
+                    INNER JOIN item USING (user_id, signature)
+                    WHERE hash = t.hash
+                )
+                "
+            };
+            let (count, bytes) = self.conn.query_row(
+                query,
+                params![],
+                |row| Ok((row.get::<usize, i64>(0)? as u64, row.get::<usize,i64>(1)? as u64)),
+            )?;
+            result.thumbnails_count = count;
+            result.thumbnails_bytes = bytes;
+        }
+
         if opts.dry_run {
             return Ok(result)
         }
@@ -1385,6 +1928,22 @@ impl backend::Backend for Connection
             self.conn.execute(query, params![])?;
         }
 
+        if opts.thumbnails {
+            self.conn.execute("DELETE FROM thumbnail", params![])?;
+        } else if opts.attachments {
+            // Thumbnails of the attachments we just deleted:
+            let query = "
+                DELETE FROM thumbnail AS t
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM item_attachment
+                    INNER JOIN item USING (user_id, signature)
+                    WHERE hash = t.hash
+                )
+            ";
+            self.conn.execute(query, params![])?;
+        }
+
         self.conn.execute("VACUUM", params![])?;
 
         Ok(result)
@@ -1401,7 +1960,8 @@ impl backend::Backend for Connection
                 item_size,
                 IFNULL(attachment_size,0) AS attachment_size,
                 IFNULL(attachment_count,0) AS attachment_count,
-                item_size + COALESCE(attachment_size, 0) as total_size
+                IFNULL(thumbnail_size,0) AS thumbnail_size,
+                item_size + COALESCE(attachment_size, 0) + COALESCE(thumbnail_size, 0) as total_size
             FROM (
                 SELECT
                     user_id,
@@ -1425,10 +1985,22 @@ impl backend::Backend for Connection
                 INNER JOIN store USING (hash)
                 GROUP BY user_id
             ) AS s2 USING (user_id)
+            LEFT OUTER JOIN (
+                SELECT
+                    user_id,
+                    SUM(LENGTH(t.bytes)) as thumbnail_size
+                FROM (
+                    SELECT DISTINCT user_id, hash
+                    FROM item
+                    INNER JOIN item_attachment USING (user_id, signature)
+                ) AS user_hashes
+                INNER JOIN thumbnail AS t USING (hash)
+                GROUP BY user_id
+            ) AS s3 USING (user_id)
             ORDER BY total_size DESC
         ";
 
-        let mut stmt = self.conn.prepare(&query)?;
+        let mut stmt = self.conn.prepare(query)?;
         let mut rows = stmt.query(params![])?;
 
         let mut fetch_more = true;
@@ -1445,6 +2017,7 @@ impl backend::Backend for Connection
                 items_bytes: row.get::<&str, i64>("item_size")? as u64,
                 attachments_count: row.get::<&str, i64>("attachment_count")? as u64,
                 attachments_bytes: row.get::<&str, i64>("attachment_size")? as u64,
+                thumbnails_bytes: row.get::<&str, i64>("thumbnail_size")? as u64,
                 known_user: row.get("known_user")?,
                 server_user: row.get("server_user")?,
                 total_bytes: row.get::<&str, i64>("total_size")? as u64,
@@ -1455,6 +2028,191 @@ impl backend::Backend for Connection
 
         Ok(())
     }
+
+    fn activitypub_key(&self, user_id: &UserID) -> Result<Option<String>, Error> {
+        let key = self.conn.query_row(
+            "SELECT private_key_pem FROM activitypub_key WHERE user_id = ?",
+            params![user_id.bytes()],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(key)
+    }
+
+    fn save_activitypub_key(&self, user_id: &UserID, private_key_pem: &str) -> Result<String, Error> {
+        // Two requests may race to create a key. First one wins:
+        self.conn.execute(
+            "INSERT OR IGNORE INTO activitypub_key(user_id, private_key_pem) VALUES (?, ?)",
+            params![user_id.bytes(), private_key_pem],
+        )?;
+
+        match self.activitypub_key(user_id)? {
+            Some(key) => Ok(key),
+            None => bail!("Failed to save ActivityPub key for {}", user_id),
+        }
+    }
+
+    fn add_activitypub_follower(&self, follower: &backend::ActivityPubFollower) -> Result<(), Error> {
+        self.conn.execute("
+            INSERT OR REPLACE INTO activitypub_follower(user_id, actor, inbox, timestamp_ms_utc, follow_id)
+            VALUES (?, ?, ?, ?, ?)
+        ", params![
+            follower.user.bytes(),
+            follower.actor.as_str(),
+            follower.inbox.as_str(),
+            follower.timestamp.unix_utc_ms,
+            follower.follow_id,
+        ])?;
+        Ok(())
+    }
+
+    fn remove_activitypub_follower(&self, user_id: &UserID, actor: &str) -> Result<(), Error> {
+        self.conn.execute(
+            "DELETE FROM activitypub_follower WHERE user_id = ? AND actor = ?",
+            params![user_id.bytes(), actor],
+        )?;
+        Ok(())
+    }
+
+    fn activitypub_followers<'a>(&self, user_id: &UserID, callback: RowCallback<'a, backend::ActivityPubFollower>) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare("
+            SELECT actor, inbox, timestamp_ms_utc, follow_id
+            FROM activitypub_follower
+            WHERE user_id = ?
+            ORDER BY timestamp_ms_utc
+        ")?;
+        let mut rows = stmt.query(params![user_id.bytes()])?;
+
+        while let Some(row) = rows.next()? {
+            let follower = backend::ActivityPubFollower {
+                user: user_id.clone(),
+                actor: row.get(0)?,
+                inbox: row.get(1)?,
+                timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
+                follow_id: row.get(3)?,
+            };
+            if !callback(follower)? { break; }
+        }
+
+        Ok(())
+    }
+
+    fn webhooks<'a>(&self, callback: RowCallback<'a, backend::Webhook>) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare("
+            SELECT webhook_id, url, secret
+            FROM webhook
+            ORDER BY webhook_id
+        ")?;
+        let mut rows = stmt.query([])?;
+
+        while let Some(row) = rows.next()? {
+            let webhook = backend::Webhook {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                secret: row.get(2)?,
+            };
+            if !callback(webhook)? { break; }
+        }
+
+        Ok(())
+    }
+
+    fn add_webhook(&self, url: &str, secret: &str) -> Result<backend::Webhook, Error> {
+        self.conn.execute(
+            "INSERT INTO webhook(url, secret, created_ms_utc) VALUES (?, ?, ?)",
+            params![url, secret, Timestamp::now().unix_utc_ms],
+        )?;
+        Ok(backend::Webhook {
+            id: self.conn.last_insert_rowid(),
+            url: url.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+
+    fn remove_webhook(&self, webhook_id: i64) -> Result<bool, Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM webhook_delivery WHERE webhook_id = ?", [webhook_id])?;
+        let removed = tx.execute("DELETE FROM webhook WHERE webhook_id = ?", [webhook_id])?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+
+    fn queue_webhook_deliveries(&self, event: &str, payload: &[u8]) -> Result<usize, Error> {
+        let queued = self.conn.execute("
+            INSERT INTO webhook_delivery(webhook_id, event, payload, next_attempt_ms_utc)
+            SELECT webhook_id, ?, ?, ?
+            FROM webhook
+        ", params![event, payload, Timestamp::now().unix_utc_ms])?;
+        Ok(queued)
+    }
+
+    fn queue_activitypub_delivery(&self, user_id: &UserID, inbox: &str, activity: &[u8]) -> Result<(), Error> {
+        self.conn.execute("
+            INSERT INTO webhook_delivery(activitypub_user_id, activitypub_inbox, event, payload, next_attempt_ms_utc)
+            VALUES (?, ?, 'activitypub', ?, ?)
+        ", params![user_id.bytes(), inbox, activity, Timestamp::now().unix_utc_ms])?;
+        Ok(())
+    }
+
+    fn due_webhook_deliveries<'a>(&self, now: Timestamp, callback: RowCallback<'a, backend::WebhookDelivery>) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare("
+            SELECT
+                d.delivery_id
+                , d.event
+                , d.payload
+                , d.attempts
+                , w.webhook_id
+                , w.url
+                , w.secret
+                , d.activitypub_user_id
+                , d.activitypub_inbox
+            FROM webhook_delivery AS d
+            LEFT OUTER JOIN webhook AS w USING (webhook_id)
+            WHERE d.next_attempt_ms_utc <= ?
+            ORDER BY d.next_attempt_ms_utc, d.delivery_id
+        ")?;
+        let mut rows = stmt.query([now.unix_utc_ms])?;
+
+        while let Some(row) = rows.next()? {
+            let webhook_id: Option<i64> = row.get(4)?;
+            let target = match webhook_id {
+                Some(id) => backend::DeliveryTarget::Webhook(backend::Webhook {
+                    id,
+                    url: row.get(5)?,
+                    secret: row.get(6)?,
+                }),
+                None => backend::DeliveryTarget::ActivityPub {
+                    user: UserID::from_vec(row.get(7)?)?,
+                    inbox: row.get(8)?,
+                },
+            };
+            let delivery = backend::WebhookDelivery {
+                id: row.get(0)?,
+                event: row.get(1)?,
+                payload: row.get(2)?,
+                attempts: row.get(3)?,
+                target,
+            };
+            if !callback(delivery)? { break; }
+        }
+
+        Ok(())
+    }
+
+    fn remove_webhook_delivery(&self, delivery_id: i64) -> Result<(), Error> {
+        self.conn.execute("DELETE FROM webhook_delivery WHERE delivery_id = ?", [delivery_id])?;
+        Ok(())
+    }
+
+    fn retry_webhook_delivery(&self, delivery_id: i64, next_attempt: Timestamp, error: &str) -> Result<(), Error> {
+        self.conn.execute("
+            UPDATE webhook_delivery
+            SET attempts = attempts + 1
+                , next_attempt_ms_utc = ?
+                , last_error = ?
+            WHERE delivery_id = ?
+        ", params![next_attempt.unix_utc_ms, error, delivery_id])?;
+        Ok(())
+    }
 }
 
 struct ReplyRow {
@@ -1477,6 +2235,15 @@ struct AttachmentRow {
     hash: SHA512,
 }
 
+/// An `AND ... IN (...)` clause limiting `column` to `item_types`. Empty if there's no limit.
+fn item_type_filter(column: &str, item_types: &[ItemType]) -> String {
+    if item_types.is_empty() {
+        return String::new();
+    }
+    let types: Vec<String> = item_types.iter().map(|t| (*t as i32).to_string()).collect();
+    format!("AND {column} IN ({types})", column=column, types=types.join(", "))
+}
+
 fn index_attachments(conn: &rusqlite::Connection, row: &ItemRow, item: &Item) -> Result<(), Error> {
     save_attachment_rows(conn, get_attachment_rows(row, item)?)
 }
@@ -1505,7 +2272,21 @@ fn get_attachment_rows(row: &ItemRow, item: &Item) -> Result<Vec<AttachmentRow>,
 
         rows.push(row);
     }
-    return Ok(rows);
+    Ok(rows)
+}
+
+/// The ID and offset of a resumable upload, if `upload`'s token matches its file.
+fn partial_upload_row(conn: &rusqlite::Connection, upload: &PartialUpload) -> Result<Option<(i64, u64)>, Error> {
+    let row = conn.query_row(
+        "
+        SELECT upload_id, received
+        FROM partial_upload
+        WHERE token = ? AND user_id = ? AND signature = ? AND name = ?
+        ",
+        params![upload.token, upload.user_id.bytes(), upload.signature.bytes(), upload.file_name],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)? as u64)),
+    ).optional()?;
+    Ok(row)
 }
 
 fn save_attachment_rows(conn: &rusqlite::Connection, rows: Vec<AttachmentRow>) -> Result<(), Error> {
@@ -1524,17 +2305,17 @@ fn save_attachment_rows(conn: &rusqlite::Connection, rows: Vec<AttachmentRow>) -
             row.signature.bytes(),
             row.name,
             row.hash.bytes(),
-            row.size as i64,
+            { row.size },
         ])?;
     }
 
     Ok(())
 }
 
-/// Get all users that `user_id` follows (and themselves).
+/// Get all users that `user_id` follows (and themselves), or only those in `group`, if given.
 //
 // note: gets ALL follows, could be abused/DoS. (TODO: Protect against unreasonable amount of follows?)
-fn get_follows(conn: &Connection, user_id: &UserID) -> Result<HashMap<UserID, FollowInfo>, Error> {
+fn get_follows(conn: &Connection, user_id: &UserID, group: Option<&str>) -> Result<HashMap<UserID, FollowInfo>, Error> {
     let mut map = HashMap::new();
 
     let mut stmt = conn.conn.prepare("
@@ -1545,6 +2326,7 @@ fn get_follows(conn: &Connection, user_id: &UserID) -> Result<HashMap<UserID, Fo
         FROM follow AS f
         LEFT OUTER JOIN profile AS p ON (f.followed_user_id = p.user_id)
         WHERE f.source_user_id = :user_id
+        AND (:group IS NULL OR f.follow_group = :group)
 
         UNION ALL
         SELECT 
@@ -1553,23 +2335,25 @@ fn get_follows(conn: &Connection, user_id: &UserID) -> Result<HashMap<UserID, Fo
             , p.display_name 
         FROM profile AS p
         WHERE p.user_id = :user_id
+        AND :group IS NULL
     ")?;
 
-    let mut rows = stmt.query(&[
-        (":user_id", &user_id.bytes()),
-    ])?;
+    let mut rows = stmt.query(named_params!{
+        ":user_id": user_id.bytes(),
+        ":group": group,
+    })?;
 
     fn to_info(row: &Row<'_>) -> Result<FollowInfo, Error> {
         let display_name: Option<String> = row.get("display_name")?;
         let follow_display_name: Option<String> = row.get("follow_display_name")?;
-        fn not_empty(it: &String) -> bool { !it.trim().is_empty() }
+        fn not_empty(it: &str) -> bool { !it.trim().is_empty() }
 
         Ok(FollowInfo {
             user_id: UserID::from_vec(row.get("user_id")?)?,
 
             // Prefer displaying the name that this user has assigned to the follow.
             // TODO: This seems maybe business-logic-y? Should we move it out of Backend?
-            display_name: follow_display_name.filter(not_empty).or(display_name).filter(not_empty),
+            display_name: follow_display_name.filter(|it| not_empty(it)).or(display_name).filter(|it| not_empty(it)),
         })
     }
 
@@ -1585,4 +2369,170 @@ struct FollowInfo {
     user_id: UserID,
     // The display name specified by this user, or (fallback) the user they followed.
     display_name: Option<String>
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl FactoryBuilder {
+    /// How many attachments are still under a temporary hash. See: delete_unfinished_attachments()
+    pub(crate) fn unfinished_attachments(&self) -> usize {
+        let conn = self.connection().unwrap();
+        conn.conn.query_row("SELECT COUNT(*) FROM store WHERE length(hash) = 31", params![], |row| row.get(0)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backend::Backend;
+    use sodiumoxide::crypto::sign;
+
+    use crate::server::test_util::{TestDb, comment, post};
+    use crate::protos::{Follow, FollowGroup, Profile};
+    use super::*;
+
+    /// A profile following `follows`, each in the named group, if any.
+    fn profile(timestamp: i64, follows: &[(&UserID, Option<&str>)]) -> Item {
+        let mut profile = Profile::new();
+        for (user_id, group) in follows {
+            let mut follow = Follow::new();
+            follow.user.mut_or_insert_default().bytes = user_id.bytes().to_vec();
+            if let Some(name) = group {
+                follow.follow_group = Some(profile.follow_groups.len() as i32);
+                let mut group = FollowGroup::new();
+                group.name = name.to_string();
+                profile.follow_groups.push(group);
+            }
+            profile.follows.push(follow);
+        }
+        let mut item = Item::new();
+        item.timestamp_ms_utc = timestamp;
+        item.set_profile(profile);
+        item
+    }
+
+    /// Authors of the items in a user's feed, newest first.
+    fn feed(conn: &Connection, user_id: &UserID, group: Option<&str>) -> Vec<UserID> {
+        let mut found = vec![];
+        conn.user_feed_items(user_id, TimeSpan::Before(Timestamp::now()), &[ItemType::POST], group, &mut |row| {
+            found.push(row.item.user);
+            Ok(true)
+        }).unwrap();
+        found
+    }
+
+    fn notifications(conn: &Connection, user_id: &UserID) -> Vec<Signature> {
+        let mut found = vec![];
+        conn.user_notification_items(user_id, TimeSpan::Before(Timestamp::now()), &[], &mut |row| {
+            found.push(row.signature);
+            Ok(true)
+        }).unwrap();
+        found
+    }
+
+    #[test]
+    fn mentioned_user_ids() {
+        let a = UserID::from_vec(sign::gen_keypair().0.0.to_vec()).unwrap();
+        let b = UserID::from_vec(sign::gen_keypair().0.0.to_vec()).unwrap();
+        let text = format!("Hi {a}! See [this](/u/{b}/i/abc/), {a}. Too short: {short}", a=a, b=b, short="5HueCGU8rMjxEX");
+        assert_eq!(vec![a, b], super::find_user_ids(&text));
+        assert_eq!(Vec::<UserID>::new(), super::find_user_ids("0OIl0OIl0OIl0OIl0OIl0OIl0OIl0OIl0OIl"));
+    }
+
+    #[test]
+    fn user_notifications() {
+        let db = TestDb::new();
+        let conn = db.builder.connection().unwrap();
+        let alice = db.new_user();
+        let bob = db.new_user();
+        let carol = db.new_user();
+
+        let post_sig = db.save(&alice, &post(100, "Hello"));
+        let reply_sig = db.save(&bob, &comment(200, &alice.0, &post_sig, "Hi!"));
+        // The user's own replies & mentions don't notify them:
+        db.save(&alice, &comment(300, &alice.0, &post_sig, &format!("Thanks, me /u/{}/", alice.0)));
+        let mention = post(400, &format!("Go see /u/{}/", alice.0));
+        let mention_sig = db.save(&carol, &mention);
+        db.save(&bob, &post(500, "Unrelated"));
+
+        assert_eq!(vec![mention_sig.clone(), reply_sig], notifications(&conn, &alice.0));
+        assert_eq!(Vec::<Signature>::new(), notifications(&conn, &bob.0));
+
+        // Indexing the same mention again (ex: in an upgrader) doesn't duplicate it:
+        let row = conn.user_item(&carol.0, &mention_sig).unwrap().unwrap();
+        save_mention_rows(&conn.conn, &get_mention_rows(&row, &mention)).unwrap();
+        let count: u32 = conn.conn.query_row("SELECT COUNT(*) FROM mention", params![], |row| row.get(0)).unwrap();
+        assert_eq!(1, count);
+    }
+
+    #[test]
+    fn delivery_queue() {
+        let db = TestDb::new();
+        let conn = db.builder.connection().unwrap();
+        let user = db.new_user();
+
+        let webhook = conn.add_webhook("https://bot.example/hook", "secret").unwrap();
+        assert_eq!(1, conn.queue_webhook_deliveries("item", b"{}").unwrap());
+        conn.queue_activitypub_delivery(&user.0, "https://social.example/inbox", b"{}").unwrap();
+
+        let due = || {
+            let mut due = vec![];
+            conn.due_webhook_deliveries(Timestamp::now(), &mut |delivery| {
+                due.push(delivery);
+                Ok(true)
+            }).unwrap();
+            due
+        };
+        let queued = due();
+        assert_eq!(2, queued.len());
+        assert!(matches!(&queued[0].target, backend::DeliveryTarget::Webhook(it) if it.id == webhook.id));
+        assert!(matches!(&queued[1].target, backend::DeliveryTarget::ActivityPub{user: it, inbox} if *it == user.0 && inbox == "https://social.example/inbox"));
+
+        // Retries wait until they're due:
+        let later = Timestamp{ unix_utc_ms: Timestamp::now().unix_utc_ms + 60_000 };
+        conn.retry_webhook_delivery(queued[1].id, later, "timed out").unwrap();
+        conn.remove_webhook_delivery(queued[0].id).unwrap();
+        assert_eq!(0, due().len());
+    }
+
+    #[test]
+    fn unfinished_attachments() {
+        let db = TestDb::new();
+        let conn = db.builder.connection().unwrap();
+        db.save_file(b"finished");
+        let upload = conn.start_attachment(10).unwrap().unwrap();
+        conn.write_attachment(&upload, 0, b"01234").unwrap();
+        assert_eq!(1, db.builder.unfinished_attachments());
+
+        assert_eq!(1, conn.delete_unfinished_attachments().unwrap());
+        assert_eq!(0, db.builder.unfinished_attachments());
+        let count: u32 = conn.conn.query_row("SELECT COUNT(*) FROM store", params![], |row| row.get(0)).unwrap();
+        assert_eq!(1, count);
+    }
+
+    #[test]
+    fn feed_groups() {
+        let db = TestDb::new();
+        let conn = db.builder.connection().unwrap();
+        let alice = db.new_user();
+        let bob = db.new_user();
+        let carol = db.new_user();
+
+        db.save(&alice, &profile(100, &[(&bob.0, Some("News")), (&carol.0, None)]));
+        db.save(&alice, &post(200, "Alice"));
+        db.save(&bob, &post(300, "Bob"));
+        db.save(&carol, &post(400, "Carol"));
+
+        assert_eq!(vec![carol.0.clone(), bob.0.clone(), alice.0.clone()], feed(&conn, &alice.0, None));
+        // Only the group's follows, not the user themself:
+        assert_eq!(vec![bob.0.clone()], feed(&conn, &alice.0, Some("News")));
+        assert_eq!(Vec::<UserID>::new(), feed(&conn, &alice.0, Some("Nope")));
+
+        // Roll back to version 14, then check that upgrading fills in the groups again:
+        conn.run("ALTER TABLE activitypub_follower DROP COLUMN follow_id").unwrap();
+        conn.run("ALTER TABLE follow DROP COLUMN follow_group").unwrap();
+        conn.set_version(14).unwrap();
+        upgraders::Upgraders::new().upgrade(&conn).unwrap();
+        assert_eq!(vec![bob.0.clone()], feed(&conn, &alice.0, Some("News")));
+        let groups: u32 = conn.conn.query_row("SELECT COUNT(follow_group) FROM follow", params![], |row| row.get(0)).unwrap();
+        assert_eq!(1, groups);
+    }
+}
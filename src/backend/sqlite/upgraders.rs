@@ -4,9 +4,9 @@ use anyhow::{Error, bail};
 use protobuf::Message;
 use rusqlite::params;
 
-use crate::{backend::{ItemRow, RowCallback, Signature, UserID}, protos::Item};
+use crate::{backend::{ItemRow, RowCallback, Signature, UserID}, markdown, protos::{Item, ItemType}};
 
-use super::{AttachmentRow, CURRENT_VERSION, Connection, ReplyRow, get_attachment_rows, save_attachment_rows, save_reply_rows};
+use super::{AttachmentRow, CURRENT_VERSION, Connection, MentionRow, ReplyRow, get_attachment_rows, get_mention_rows, save_attachment_rows, save_mention_rows, save_reply_rows};
 
 pub(crate) struct Upgraders {
     upgraders: Vec<Box<dyn Upgrader>>
@@ -19,6 +19,14 @@ impl Upgraders {
             Box::new(From4To5),
             Box::new(From5To6),
             Box::new(From6To7),
+            Box::new(From7To8),
+            Box::new(From8To9),
+            Box::new(From9To10),
+            Box::new(From10To11),
+            Box::new(From11To12),
+            Box::new(From12To13),
+            Box::new(From13To14),
+            Box::new(From14To15),
         ]}
     }
 
@@ -51,6 +59,7 @@ impl Upgraders {
 
 
 trait Upgrader {
+    #[allow(clippy::wrong_self_convention)]
     fn from_version(&self) -> u32;
     fn to_version(&self) -> u32;
     fn upgrade(&self, conn: &Connection) -> Result<(), Error>;
@@ -86,7 +95,7 @@ impl Upgrader for From3To4 {
         let item_count: u32 = conn.conn.query_row(
             "SELECT COUNT(*) FROM item",
             params![],
-            |row| Ok(row.get(0)?)
+            |row| row.get(0)
         )?;
 
         if item_count > 1000 {
@@ -128,7 +137,7 @@ impl Upgrader for From3To4 {
             })?;
 
             // Write cached reply_tos to the database:
-            save_reply_rows(&*conn.conn, reply_tos.as_slice())?;
+            save_reply_rows(&conn.conn, reply_tos.as_slice())?;
             reply_tos.clear();
         }
 
@@ -170,7 +179,7 @@ impl ItemPager {
                 // If we tell the function we want more, but it never calls it again, then we're done iterating:
                 done = should_continue;
             }
-            return result;
+            result
         };
 
 
@@ -277,7 +286,7 @@ impl Upgrader for From5To6 {
         let item_count: u32 = conn.conn.query_row(
             "SELECT COUNT(*) FROM item",
             params![],
-            |row| Ok(row.get(0)?)
+            |row| row.get(0)
         )?;
 
         if item_count > 1000 {
@@ -344,4 +353,396 @@ impl Upgrader for From6To7 {
         conn.set_version(self.to_version())?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// New `mention` table to track items that mention other users.
+struct From7To8;
+impl Upgrader for From7To8 {
+    fn from_version(&self) -> u32 { 7 }
+    fn to_version(&self) -> u32 { 8 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            CREATE TABLE mention (
+                -- Tracks Items whose markdown mentions a user ID.
+
+                from_user_id BLOB,
+                from_signature BLOB,
+
+                to_user_id BLOB
+            )
+        ")?;
+
+        conn.run("
+            CREATE UNIQUE INDEX mention_primary_idx
+            ON mention(from_user_id, from_signature, to_user_id)
+        ")?;
+
+        conn.run("
+            CREATE INDEX mention_to_idx
+            ON mention(to_user_id)
+        ")?;
+
+        // TODO: Newer rusqlite supports u64 & usize:
+        let item_count: u32 = conn.conn.query_row(
+            "SELECT COUNT(*) FROM item",
+            params![],
+            |row| row.get(0)
+        )?;
+
+        if item_count > 1000 {
+            println!("Scanning {} items for mentions. This may take a some time.", item_count);
+        }
+
+        let mut pager = ItemPager::new();
+
+        // See notes about batching in From3To4:
+        let mut mentions = Vec::<MentionRow>::new();
+        let max_mentions = 1000;
+
+        while !pager.done {
+            pager.iterate(conn, &mut |row| {
+                let mut item = Item::new();
+                item.merge_from_bytes(row.item_bytes.as_slice())?;
+                mentions.extend(get_mention_rows(&row, &item));
+                Ok(mentions.len() < max_mentions)
+            })?;
+
+            save_mention_rows(&conn.conn, mentions.as_slice())?;
+            mentions.clear();
+        }
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+/// Flags items that contain unsafe markdown (raw HTML, script links).
+struct From8To9;
+impl Upgrader for From8To9 {
+    fn from_version(&self) -> u32 { 8 }
+    fn to_version(&self) -> u32 { 9 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            -- bool 0/1 -- Does this item's markdown contain raw HTML or script links?
+            -- See: markdown.rs
+            ALTER TABLE item
+            ADD COLUMN unsafe_markdown INTEGER NOT NULL DEFAULT 0
+        ")?;
+
+        // TODO: Newer rusqlite supports u64 & usize:
+        let item_count: u32 = conn.conn.query_row(
+            "SELECT COUNT(*) FROM item",
+            params![],
+            |row| row.get(0)
+        )?;
+
+        if item_count > 1000 {
+            println!("Scanning {} items for unsafe markdown. This may take a some time.", item_count);
+        }
+
+        let mut pager = ItemPager::new();
+
+        // See notes about batching in From3To4:
+        let mut flagged = Vec::<(UserID, Signature)>::new();
+        let max_flagged = 1000;
+
+        while !pager.done {
+            pager.iterate(conn, &mut |row| {
+                let mut item = Item::new();
+                item.merge_from_bytes(row.item_bytes.as_slice())?;
+                if markdown::find_unsafe_in_item(&item).is_some() {
+                    flagged.push((row.user, row.signature));
+                }
+                Ok(flagged.len() < max_flagged)
+            })?;
+
+            let mut stmt = conn.conn.prepare("
+                UPDATE item
+                SET unsafe_markdown = 1
+                WHERE user_id = ? AND signature = ?
+            ")?;
+            for (user_id, signature) in &flagged {
+                stmt.execute(params![user_id.bytes(), signature.bytes()])?;
+            }
+            flagged.clear();
+        }
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+/// New tables for the ActivityPub bridge. (See: server/activitypub.rs)
+struct From9To10;
+impl Upgrader for From9To10 {
+    fn from_version(&self) -> u32 { 9 }
+    fn to_version(&self) -> u32 { 10 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            CREATE TABLE activitypub_key(
+                -- RSA keys used to sign ActivityPub requests on behalf of a server user.
+                -- (ActivityPub servers don't support signing with our users' own ed25519 keys.)
+
+                user_id BLOB PRIMARY KEY,
+
+                -- PKCS#8, PEM-encoded.
+                private_key_pem TEXT NOT NULL
+            )
+        ")?;
+
+        conn.run("
+            CREATE TABLE activitypub_follower(
+                -- ActivityPub actors that follow a server user.
+
+                user_id BLOB NOT NULL,
+
+                -- The URL ID of the remote actor:
+                actor TEXT NOT NULL,
+
+                -- The URL of the inbox we deliver activities to:
+                inbox TEXT NOT NULL,
+
+                -- When the Follow was accepted, in ms since epoch (UTC).
+                timestamp_ms_utc INTEGER NOT NULL,
+
+                -- The ID (URL) of the Follow activity, so that an Undo can reference it by ID.
+                follow_id TEXT,
+
+                PRIMARY KEY (user_id, actor)
+            )
+        ")?;
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+/// Outgoing webhooks, and a queue of deliveries to them. (See: server/webhooks.rs)
+struct From10To11;
+impl Upgrader for From10To11 {
+    fn from_version(&self) -> u32 { 10 }
+    fn to_version(&self) -> u32 { 11 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            CREATE TABLE webhook(
+                webhook_id INTEGER PRIMARY KEY,
+
+                -- We POST events to this URL:
+                url TEXT NOT NULL,
+
+                -- Shared secret used to HMAC-sign each payload.
+                secret TEXT NOT NULL,
+
+                created_ms_utc INTEGER NOT NULL
+            )
+        ")?;
+
+        conn.run("
+            CREATE TABLE webhook_delivery(
+                -- Payloads waiting to be (re)delivered to a webhook, or to an ActivityPub inbox.
+
+                delivery_id INTEGER PRIMARY KEY,
+
+                -- NULL for ActivityPub deliveries.
+                webhook_id INTEGER,
+
+                -- For ActivityPub deliveries: the server user who signs the
+                -- request, and the follower's inbox URL.
+                activitypub_user_id BLOB,
+                activitypub_inbox TEXT,
+
+                -- ex: 'item', 'file', 'activitypub'
+                event TEXT NOT NULL,
+                payload BLOB NOT NULL,
+
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_ms_utc INTEGER NOT NULL,
+                last_error TEXT
+            )
+        ")?;
+
+        conn.run("
+            CREATE INDEX webhook_delivery_next_attempt_idx
+            ON webhook_delivery(next_attempt_ms_utc)
+        ")?;
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+struct From11To12;
+impl Upgrader for From11To12 {
+    fn from_version(&self) -> u32 { 11 }
+    fn to_version(&self) -> u32 { 12 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            CREATE TABLE thumbnail(
+                -- Cached thumbnails of image attachments.
+                -- These can always be re-generated from `store`, so are safe to delete.
+
+                -- The sha-512 hash of the original file.
+                hash BLOB NOT NULL,
+
+                -- The thumbnail fits within size x size pixels.
+                size INTEGER NOT NULL,
+
+                content_type TEXT NOT NULL,
+                bytes BLOB NOT NULL,
+
+                PRIMARY KEY (hash, size)
+            )
+        ")?;
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+struct From12To13;
+impl Upgrader for From12To13 {
+    fn from_version(&self) -> u32 { 12 }
+    fn to_version(&self) -> u32 { 13 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            CREATE TABLE partial_upload(
+                -- Resumable attachment uploads that haven't finished yet.
+                upload_id INTEGER PRIMARY KEY,
+
+                -- A secret, given only to the client that started the upload.
+                token TEXT NOT NULL UNIQUE,
+
+                user_id BLOB NOT NULL,
+                signature BLOB NOT NULL,
+                name TEXT NOT NULL,
+
+                -- How many bytes (from the start) we've received.
+                received INTEGER NOT NULL,
+
+                -- Abandoned uploads expire:
+                updated_ms_utc INTEGER NOT NULL
+            )
+        ")?;
+
+        conn.run("
+            CREATE INDEX partial_upload_updated_idx
+            ON partial_upload(updated_ms_utc)
+        ")?;
+
+        conn.run("
+            CREATE TABLE partial_upload_chunk(
+                -- The bytes of a partial_upload, saved as they arrive.
+                upload_id INTEGER NOT NULL,
+                -- Where in the file these bytes go.
+                start INTEGER NOT NULL,
+                contents BLOB NOT NULL,
+
+                PRIMARY KEY (upload_id, start)
+            )
+        ")?;
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+/// Store each Item's type in `item.item_type`, so lists can filter by type in SQL.
+struct From13To14;
+impl Upgrader for From13To14 {
+    fn from_version(&self) -> u32 { 13 }
+    fn to_version(&self) -> u32 { 14 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            -- An ItemType enum value. (See: diskuto.proto)
+            -- 0 = UNKNOWN, until the backfill below fills it in.
+            ALTER TABLE item
+            ADD COLUMN item_type INTEGER NOT NULL DEFAULT 0
+        ")?;
+
+        let item_count: u32 = conn.conn.query_row(
+            "SELECT COUNT(*) FROM item",
+            params![],
+            |row| row.get(0)
+        )?;
+
+        if item_count > 1000 {
+            println!("Recording the type of {} items. This may take a some time.", item_count);
+        }
+
+        let mut pager = ItemPager::new();
+
+        // See notes about batching in From3To4:
+        let mut types = Vec::<(UserID, Signature, ItemType)>::new();
+        let max_types = 1000;
+
+        while !pager.done {
+            pager.iterate(conn, &mut |row| {
+                let mut item = Item::new();
+                item.merge_from_bytes(row.item_bytes.as_slice())?;
+                types.push((row.user, row.signature, item.get_type()));
+                Ok(types.len() < max_types)
+            })?;
+
+            let mut stmt = conn.conn.prepare("
+                UPDATE item
+                SET item_type = ?
+                WHERE user_id = ? AND signature = ?
+            ")?;
+            for (user_id, signature, item_type) in &types {
+                stmt.execute(params![*item_type as i32, user_id.bytes(), signature.bytes()])?;
+            }
+            types.clear();
+        }
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+/// Store the name of the FollowGroup that each follow is in, so feeds can be filtered by group.
+struct From14To15;
+impl Upgrader for From14To15 {
+    fn from_version(&self) -> u32 { 14 }
+    fn to_version(&self) -> u32 { 15 }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            -- The name of the FollowGroup this follow is in. (See: Profile.follow_group_name())
+            -- NULL if it's not in a group.
+            ALTER TABLE follow
+            ADD COLUMN follow_group TEXT
+        ")?;
+
+        // The follow table only holds follows from each user's latest profile, so that's all we need to read:
+        let mut grouped = Vec::<(Vec<u8>, Vec<u8>, String)>::new();
+        let mut stmt = conn.conn.prepare("
+            SELECT p.user_id, i.bytes
+            FROM profile AS p
+            INNER JOIN item AS i USING (user_id, signature)
+        ")?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let user_id: Vec<u8> = row.get(0)?;
+            let bytes: Vec<u8> = row.get(1)?;
+            let mut item = Item::new();
+            item.merge_from_bytes(&bytes)?;
+            let profile = item.profile();
+            for follow in &profile.follows {
+                if let Some(name) = profile.follow_group_name(follow) {
+                    grouped.push((user_id.clone(), follow.user.bytes.clone(), name.to_string()));
+                }
+            }
+        }
+
+        let mut stmt = conn.conn.prepare("
+            UPDATE follow
+            SET follow_group = ?
+            WHERE source_user_id = ? AND followed_user_id = ?
+        ")?;
+        for (source_user_id, followed_user_id, name) in &grouped {
+            stmt.execute(params![name, source_user_id, followed_user_id])?;
+        }
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
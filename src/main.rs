@@ -4,13 +4,14 @@
 #[cfg(test)]
 mod tests;
 
-use crate::{backend::{PruneOpts, ServerUser, UserID, sqlite}, util::AsHex};
+use crate::{backend::{PruneOpts, ServerUser, UserID, sqlite}, server::config::DatabaseConfig, util::AsHex};
 use anyhow::{Error, bail};
 use clap::{Args, Parser};
 use sizedisplay::SizeDisplay;
 use tablestream::{Stream, Column, col};
 
 mod backend;
+mod markdown;
 mod protos;
 mod server;
 mod util;
@@ -24,6 +25,7 @@ fn main() -> Result<(), Error> {
         Serve(command) => server::serve(command)?,
         User(command) => command.main()?,
         Db(command) => command.main()?,
+        Webhook(command) => command.main()?,
     };
 
     Ok(())
@@ -46,6 +48,10 @@ enum Command
     /// Database administration commands
     #[clap(subcommand)]
     Db(DbCommand),
+
+    /// Manage webhooks, which are notified of new items and attachments.
+    #[clap(subcommand)]
+    Webhook(WebhookCommand),
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -57,10 +63,46 @@ struct ServeCommand {
     #[arg(long)]
     open: bool,
 
+    /// Read settings from this TOML file. See: docs/config.md
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+
     /// Bind to this local address.
-    /// If unspecified, will try to bind to some port on localhost.
+    /// If neither this nor --tls-bind is specified, uses the config file, or 127.0.0.1:8080.
     #[arg(long="bind")]
-    binds: Vec<String>
+    binds: Vec<String>,
+
+    /// Serve HTTPS (and HTTP/2) on this local address. Requires --tls-cert and --tls-key.
+    #[arg(long="tls-bind")]
+    tls_binds: Vec<String>,
+
+    /// A PEM file with the TLS certificate (chain) for --tls-bind addresses.
+    /// Reloaded on SIGHUP.
+    #[arg(long)]
+    tls_cert: Option<std::path::PathBuf>,
+
+    /// A PEM file with the private key for --tls-cert.
+    #[arg(long)]
+    tls_key: Option<std::path::PathBuf>,
+
+    /// What to do with uploaded Items whose markdown contains raw HTML or script links.
+    #[arg(long, value_enum, default_value = "flag")]
+    unsafe_markdown: server::UnsafeMarkdownPolicy,
+
+    /// Enable the ActivityPub bridge, so that server users can be followed from
+    /// Mastodon & co. The public URL of this server (ex: https://example.com/)
+    /// is used in actor and object IDs, so should not change.
+    #[arg(long)]
+    activitypub_url: Option<url::Url>,
+
+    /// Serve Prometheus metrics at /metrics.
+    #[arg(long)]
+    metrics: bool,
+
+    /// Serve /metrics on this address, instead of alongside the API.
+    /// (ex: a private admin port.) Implies --metrics.
+    #[arg(long)]
+    metrics_bind: Option<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -73,10 +115,16 @@ pub(crate) struct BackendOptions
 // Implements some functionality which may be different depending on the DB backend.
 impl BackendOptions {
     fn factory_builder(&self) -> Result<Box<dyn backend::FactoryBuilder>, Error> {
+        self.factory_builder_for(&DatabaseConfig::default())
+    }
+
+    /// A FactoryBuilder with settings from `diskuto serve --config`.
+    fn factory_builder_for(&self, database: &DatabaseConfig) -> Result<Box<dyn backend::FactoryBuilder>, Error> {
         // When we support more than one kind of DB, we can switch on that here:
         Ok(
             Box::new(
                 sqlite::FactoryBuilder::new(self.sqlite_file.clone())
+                .pool_size(database.pool_max_size, database.pool_min_idle)
             )
         )
     }
@@ -180,6 +228,139 @@ impl UserRemoveCommand {
 }
 
 
+#[derive(Parser, Debug, Clone)]
+pub(crate) enum WebhookCommand {
+    /// List webhooks. (Their secrets are only shown by `add`.)
+    List(WebhookListCommand),
+
+    /// Add a webhook.
+    Add(WebhookAddCommand),
+
+    /// Remove a webhook, and any deliveries still queued for it.
+    Remove(WebhookRemoveCommand),
+
+    /// Send a test event to a webhook.
+    Test(WebhookTestCommand),
+}
+
+impl WebhookCommand {
+    fn main(&self) -> Result<(), Error> {
+        use WebhookCommand::*;
+        match self {
+            List(command) => command.main(),
+            Add(command) => command.main(),
+            Remove(command) => command.main(),
+            Test(command) => command.main(),
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+struct WebhookListCommand {
+    #[clap(flatten)]
+    backend_options: BackendOptions,
+}
+
+impl WebhookListCommand {
+    fn main(&self) -> Result<(), Error> {
+        let factory = self.backend_options.factory_builder()?.factory()?;
+        let conn = factory.open()?;
+
+        conn.webhooks(&mut |webhook| {
+            println!("{} {}", webhook.id, webhook.url);
+            Ok(true)
+        })?;
+
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+struct WebhookAddCommand {
+    #[clap(flatten)]
+    backend_options: BackendOptions,
+
+    /// We POST events to this URL.
+    url: url::Url,
+
+    /// The secret used to sign payloads. If unspecified, one will be generated.
+    #[arg(long)]
+    secret: Option<String>,
+}
+
+impl WebhookAddCommand {
+    fn main(&self) -> Result<(), Error> {
+        if self.url.scheme() != "https" && self.url.scheme() != "http" {
+            bail!("Webhooks must be http(s) URLs: {}", self.url);
+        }
+
+        let secret = match &self.secret {
+            Some(secret) => secret.clone(),
+            None => {
+                sodiumoxide::init().expect("sodiumoxide::init()");
+                sodiumoxide::randombytes::randombytes(32).as_slice().as_hex().to_string()
+            },
+        };
+
+        let factory = self.backend_options.factory_builder()?.factory()?;
+        let webhook = factory.open()?.add_webhook(self.url.as_str(), &secret)?;
+
+        println!("Added webhook {}", webhook.id);
+        println!("Secret: {}", webhook.secret);
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+struct WebhookRemoveCommand {
+    #[clap(flatten)]
+    backend_options: BackendOptions,
+
+    webhook_id: i64,
+}
+
+impl WebhookRemoveCommand {
+    fn main(&self) -> Result<(), Error> {
+        let factory = self.backend_options.factory_builder()?.factory()?;
+        if !factory.open()?.remove_webhook(self.webhook_id)? {
+            bail!("No such webhook: {}", self.webhook_id);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+struct WebhookTestCommand {
+    #[clap(flatten)]
+    backend_options: BackendOptions,
+
+    webhook_id: i64,
+}
+
+impl WebhookTestCommand {
+    fn main(&self) -> Result<(), Error> {
+        let factory = self.backend_options.factory_builder()?.factory()?;
+
+        let mut found = None;
+        factory.open()?.webhooks(&mut |webhook| {
+            if webhook.id == self.webhook_id {
+                found = Some(webhook);
+                return Ok(false);
+            }
+            Ok(true)
+        })?;
+
+        let webhook = match found {
+            Some(webhook) => webhook,
+            None => bail!("No such webhook: {}", self.webhook_id),
+        };
+
+        server::webhooks::send_test(&webhook)?;
+        println!("OK");
+        Ok(())
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 pub(crate) enum DbCommand {
     /// Initialize a new database
@@ -276,6 +457,10 @@ struct DbPruneCommand {
     #[arg(long)]
     skip_unfollowed_items: bool,
 
+    /// Also delete all cached thumbnails. (They'll be re-generated as needed.)
+    #[arg(long)]
+    thumbnails: bool,
+
 }
 
 impl DbPruneCommand {
@@ -291,6 +476,7 @@ impl DbPruneCommand {
             dry_run: self.dry_run,
             attachments: !self.skip_unused_attachments,
             items: !self.skip_unfollowed_items,
+            thumbnails: self.thumbnails,
         })?;
 
         println!("{}", result);
@@ -337,6 +523,7 @@ impl DbUsageCommand {
             col!(Row: .name).header("Display Name"),
             col!(Row: .item_bytes).header("Items").right(),
             col!(Row: .attachment_bytes).header("Attachments").right(),
+            col!(Row: .thumbnail_bytes).header("Thumbnails").right(),
             col!(Row: .total_bytes).header("Total").right(),
         ]);
 
@@ -345,6 +532,7 @@ impl DbUsageCommand {
             name: String,
             item_bytes: SizeDisplay,
             attachment_bytes: SizeDisplay,
+            thumbnail_bytes: SizeDisplay,
             total_bytes: SizeDisplay,
         }
 
@@ -356,6 +544,7 @@ impl DbUsageCommand {
                 name: row.display_name.unwrap_or_else(String::new),
                 item_bytes: SizeDisplay::bytes(row.items_bytes).short(),
                 attachment_bytes: SizeDisplay::bytes(row.attachments_bytes).short(),
+                thumbnail_bytes: SizeDisplay::bytes(row.thumbnails_bytes).short(),
                 total_bytes: SizeDisplay::bytes(row.total_bytes).short(),
             })?;
             count += 1;
@@ -0,0 +1,273 @@
+//! Checks for the CommonMark markdown within Items.
+//!
+//! diskuto.proto says that "Servers should suppress unsafe raw HTML blocks" in
+//! markdown. For Items we serve as protobufs, we do that by finding them at
+//! upload time, and either rejecting the Item or flagging it for clients.
+//! See: [`crate::server::UnsafeMarkdownPolicy`].
+//!
+//! When we render markdown ourselves (ex: for RSS), we just omit those parts.
+
+use std::fmt::{self, Display};
+
+use pulldown_cmark::{CowStr, Event, Parser, Tag, TagEnd};
+use url::Url;
+
+use crate::protos::Item;
+
+/// Something in markdown that shouldn't be rendered as-is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum UnsafeKind {
+    /// A raw HTML block, or inline HTML.
+    RawHtml,
+
+    /// A link or image whose URL can run script. (ex: `javascript:`)
+    ScriptLink,
+}
+
+impl Display for UnsafeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RawHtml => write!(f, "raw HTML is not allowed"),
+            Self::ScriptLink => write!(f, "links to script URLs are not allowed"),
+        }
+    }
+}
+
+/// The first unsafe markdown found within an Item.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UnsafeMarkdown {
+    /// The proto field containing the markdown. ex: "Post.body"
+    pub field: &'static str,
+    pub kind: UnsafeKind,
+}
+
+impl Display for UnsafeMarkdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsafe markdown in {}: {}", self.field, self.kind)
+    }
+}
+
+/// Check all of the markdown fields within an Item.
+pub(crate) fn find_unsafe_in_item(item: &Item) -> Option<UnsafeMarkdown> {
+    let (field, markdown) = if item.has_post() {
+        ("Post.body", item.post().body.as_str())
+    } else if item.has_profile() {
+        ("Profile.about", item.profile().about.as_str())
+    } else if item.has_comment() {
+        ("Comment.text", item.comment().text.as_str())
+    } else {
+        return None;
+    };
+
+    find_unsafe(markdown).map(|kind| UnsafeMarkdown{ field, kind })
+}
+
+/// Find the first unsafe thing in some markdown, if any.
+pub(crate) fn find_unsafe(markdown: &str) -> Option<UnsafeKind> {
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Html(_) | Event::InlineHtml(_) => return Some(UnsafeKind::RawHtml),
+            Event::Start(Tag::Link{dest_url, ..}) | Event::Start(Tag::Image{dest_url, ..}) if is_script_url(&dest_url) => {
+                return Some(UnsafeKind::ScriptLink);
+            },
+            _ => {},
+        }
+    }
+
+    None
+}
+
+/// Render markdown as HTML.
+///
+/// Raw HTML and script links are omitted. If `base` is given, relative links
+/// are resolved against it, so that the HTML can be displayed elsewhere.
+/// (ex: `files/cat.png` in a feed reader.)
+pub(crate) fn to_html(markdown: &str, base: Option<&Url>) -> String {
+    let resolve = |url: CowStr<'_>| -> String {
+        if is_script_url(&url) {
+            return String::new();
+        }
+        match base.map(|base| base.join(&url)) {
+            Some(Ok(resolved)) => resolved.into(),
+            _ => url.into_string(),
+        }
+    };
+
+    let events = Parser::new(markdown).filter_map(|event| match event {
+        Event::Html(_) | Event::InlineHtml(_) => None,
+        Event::Start(Tag::Link{link_type, dest_url, title, id}) => Some(Event::Start(Tag::Link{
+            link_type, title, id,
+            dest_url: resolve(dest_url).into(),
+        })),
+        Event::Start(Tag::Image{link_type, dest_url, title, id}) => Some(Event::Start(Tag::Image{
+            link_type, title, id,
+            dest_url: resolve(dest_url).into(),
+        })),
+        event => Some(event),
+    });
+
+    let mut html = String::with_capacity(markdown.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut html, events);
+    html
+}
+
+/// A plain text summary of markdown, at most `max_chars` long. (ex: for link previews.)
+pub(crate) fn to_text(markdown: &str, max_chars: usize) -> String {
+    let mut text = String::new();
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Text(it) | Event::Code(it) => text.push_str(&it),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            // Don't run words together across blocks:
+            Event::End(
+                TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item | TagEnd::CodeBlock
+                | TagEnd::BlockQuote(_) | TagEnd::TableCell
+            ) => text.push(' '),
+            _ => {},
+        }
+    }
+
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let text = words.join(" ");
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+
+    let mut summary: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    summary.push('…');
+    summary
+}
+
+/// True if following/loading this URL could run script.
+fn is_script_url(url: &str) -> bool {
+    // Browsers ignore whitespace and control characters in URL schemes. ("java\tscript:")
+    // Note: pulldown-cmark has already decoded entities like "&#x61;" for us.
+    let url: String = url.chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    let (scheme, rest) = match url.split_once(':') {
+        Some(parts) => parts,
+        None => return false, // relative URL
+    };
+
+    // ex: "./foo:bar" is a relative path, not a scheme:
+    if scheme.contains(['/', '?', '#']) {
+        return false;
+    }
+
+    match scheme {
+        "javascript" | "vbscript" | "livescript" => true,
+        // data: URLs can be HTML documents. But inline images are common & harmless:
+        "data" => !SAFE_DATA_TYPES.iter().any(|t| rest.starts_with(t)),
+        _ => false,
+    }
+}
+
+const SAFE_DATA_TYPES: &[&str] = &[
+    "image/gif;",
+    "image/jpeg;",
+    "image/png;",
+    "image/webp;",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{find_unsafe, to_html, to_text, UnsafeKind::*};
+
+    #[test]
+    fn safe_markdown() {
+        let corpus = [
+            "Just some *plain* markdown.",
+            "a < b > c",
+            "Escaped: \\<script>alert(1)\\</script>",
+            "Inline code: `<script>alert(1)</script>`",
+            "```\n<script>alert(1)</script>\n```",
+            "    <script>alert(1)</script>",
+            "[link](https://example.com/)",
+            "[relative](/u/A719rvsCkuN2SC5W2vz5hypDE2SpevNTUsEXrVFe9XQ7/)",
+            "![attachment](files/cat.png)",
+            "[not a scheme](./javascript:alert(1))",
+            "[query](?q=javascript:alert(1))",
+            "<https://example.com/>",
+            "<someone@example.com>",
+            "javascript:alert(1) is just text here.",
+            "![inline image](data:image/png;base64,iVBORw0KGgo=)",
+        ];
+
+        for markdown in corpus {
+            assert_eq!(None, find_unsafe(markdown), "{:?}", markdown);
+        }
+    }
+
+    #[test]
+    fn raw_html() {
+        let corpus = [
+            "<script>alert(1)</script>",
+            "<div>\n\n*block*\n\n</div>",
+            "Inline <img src=x onerror=alert(1)> HTML",
+            "<svg onload=alert(1)>",
+            "<iframe src=\"https://example.com/\"></iframe>",
+            "<a href=\"javascript:alert(1)\">link</a>",
+            "Click <a href=\"https://example.com/\">me</a>",
+            "<!-- hidden comment -->",
+            "<style>body { display: none }</style>",
+            "* list item <b onmouseover=alert(1)>x</b>",
+            "> quoted <script>alert(1)</script>",
+        ];
+
+        for markdown in corpus {
+            assert_eq!(Some(RawHtml), find_unsafe(markdown), "{:?}", markdown);
+        }
+    }
+
+    #[test]
+    fn script_links() {
+        let corpus = [
+            "[x](javascript:alert(1))",
+            "[x](JaVaScRiPt:alert(1))",
+            "[x](<java\tscript:alert(1)>)",
+            "[x](< javascript:alert(1)>)",
+            "[x](jav&#x61;script:alert(1))",
+            "[x](&#106;avascript:alert(1))",
+            "[x](javascript&colon;alert(1))",
+            "[x](vbscript:msgbox(1))",
+            "![x](javascript:alert(1))",
+            "<javascript:alert(1)>",
+            "[x][ref]\n\n[ref]: javascript:alert(1)",
+            "[x](data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==)",
+            "![x](data:image/svg+xml;base64,PHN2Zz48L3N2Zz4=)",
+        ];
+
+        for markdown in corpus {
+            assert_eq!(Some(ScriptLink), find_unsafe(markdown), "{:?}", markdown);
+        }
+    }
+
+    #[test]
+    fn html_omits_unsafe_parts() {
+        let html = to_html("Hi <script>alert(1)</script>\n\n<div>\nblock\n</div>\n\n[x](javascript:alert(1))", None);
+        assert_eq!("<p>Hi alert(1)</p>\n<p><a href=\"\">x</a></p>\n", html);
+    }
+
+    #[test]
+    fn html_resolves_links() {
+        let base = url::Url::parse("https://example.com/u/abc/i/def/").unwrap();
+        let html = to_html("![cat](files/cat.png) [home](/) [away](https://example.org/)", Some(&base));
+        assert_eq!(
+            "<p><img src=\"https://example.com/u/abc/i/def/files/cat.png\" alt=\"cat\" /> \
+            <a href=\"https://example.com/\">home</a> \
+            <a href=\"https://example.org/\">away</a></p>\n",
+            html
+        );
+    }
+
+    #[test]
+    fn text_summary() {
+        let text = to_text("# Title\n\nSome *emphasis*, `code` and <b>html</b>.\n\n* one\n* two", 100);
+        assert_eq!("Title Some emphasis, code and html. one two", text);
+
+        assert_eq!("Title Some…", to_text("# Title\n\nSome *emphasis*", 11));
+    }
+}
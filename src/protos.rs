@@ -1,4 +1,5 @@
 use std::borrow::Cow; 
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 
@@ -33,9 +34,67 @@ impl ProtoValid for Item {
             );
         }
 
-        // TODO: Validations for specific item types.
-        if self.has_profile() {
-            let err = self.profile().get_error();
+        if self.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES {
+            return Some(
+                format!("utc_offset_minutes must be within +/- {} minutes", MAX_UTC_OFFSET_MINUTES).into()
+            );
+        }
+
+        use item::Item_type::*;
+        match &self.item_type {
+            None => Some("Item type is required".into()),
+            Some(Post(post)) => post.get_error(),
+            Some(Profile(profile)) => profile.get_error(),
+            Some(Comment(comment)) => comment.get_error(),
+        }
+    }
+}
+
+impl Item {
+    /// The ItemType that matches this Item's item_type.
+    pub(crate) fn get_type(&self) -> ItemType {
+        use item::Item_type::*;
+        match self.item_type {
+            Some(Post(_)) => ItemType::POST,
+            Some(Profile(_)) => ItemType::PROFILE,
+            Some(Comment(_)) => ItemType::COMMENT,
+            None => ItemType::UNKNOWN,
+        }
+    }
+}
+
+impl Profile {
+    /// The name of the FollowGroup that `follow` is grouped under, if any.
+    ///
+    /// For now that's `Follow.follow_group`, a (deprecated) index into `follow_groups`.
+    /// See: https://github.com/diskuto/diskuto-api/issues/131
+    /// The server stores and filters follows by group *name*, so if the proto settles on another
+    /// way to group follows, this should be the only thing that needs to change.
+    pub(crate) fn follow_group_name(&self, follow: &Follow) -> Option<&str> {
+        let index = usize::try_from(follow.follow_group?).ok()?;
+        let name = self.follow_groups.get(index)?.name.as_str();
+        Some(name).filter(|name| !name.trim().is_empty())
+    }
+}
+
+/// Servers should reject offsets of more than +/- 24 hours.
+const MAX_UTC_OFFSET_MINUTES: i32 = 24 * 60;
+
+/// Titles should be <= 256 bytes.
+const MAX_TITLE_BYTES: usize = 256;
+
+const USER_ID_BYTES: usize = 32;
+const SIGNATURE_BYTES: usize = 64;
+const SHA512_BYTES: usize = 64;
+
+impl ProtoValid for Post {
+    fn get_error(&self) -> Option<Cow<'static, str>> {
+        if self.title.len() > MAX_TITLE_BYTES {
+            return Some(format!("Post.title must be <= {} bytes", MAX_TITLE_BYTES).into())
+        }
+
+        for file in &self.attachments.file {
+            let err = file.get_error();
             if err.is_some() {
                 return err;
             }
@@ -45,15 +104,92 @@ impl ProtoValid for Item {
     }
 }
 
+impl ProtoValid for File {
+    fn get_error(&self) -> Option<Cow<'static, str>> {
+        if self.name.is_empty() {
+            return Some("File.name is required".into())
+        }
+        if self.name.contains('/') || self.name.contains('\\') {
+            return Some("File.name may not contain path separators".into())
+        }
+        if self.size == 0 {
+            return Some("File.size must be > 0".into())
+        }
+        if self.hash.len() != SHA512_BYTES {
+            return Some(format!("File.hash must be {} bytes", SHA512_BYTES).into())
+        }
+
+        None
+    }
+}
+
 impl ProtoValid for Profile {
     fn get_error(&self) -> Option<Cow<'static, str>> {
 
         for follow in &self.follows {
-            if follow.user.bytes.len() != 32 {
+            if follow.user.bytes.len() != USER_ID_BYTES {
                 return Some("UserID.bytes must be 32 bytes".into())
             }
         }
 
+        for server in &self.servers {
+            let err = server.get_error();
+            if err.is_some() {
+                return err;
+            }
+        }
+
+        None
+    }
+}
+
+impl ProtoValid for Server {
+    fn get_error(&self) -> Option<Cow<'static, str>> {
+        let rest = self.url.strip_prefix("https://")
+            .or_else(|| self.url.strip_prefix("http://"));
+        let rest = match rest {
+            Some(rest) => rest,
+            None => return Some("Server.url must be an http(s) URL".into()),
+        };
+
+        // "host[:port]", optionally followed by a single "/":
+        let host = rest.strip_suffix('/').unwrap_or(rest);
+        if host.is_empty() {
+            return Some("Server.url must include a host".into())
+        }
+        if host.contains(['/', '?', '#']) {
+            return Some("Server.url may not include a subpath".into())
+        }
+
+        None
+    }
+}
+
+impl ProtoValid for Comment {
+    fn get_error(&self) -> Option<Cow<'static, str>> {
+        if self.reply_to.is_none() {
+            return Some("Comment.reply_to is required".into())
+        }
+
+        self.reply_to.get_error()
+    }
+}
+
+impl ProtoValid for ReplyRef {
+    fn get_error(&self) -> Option<Cow<'static, str>> {
+        if self.user_id.is_none() {
+            return Some("ReplyRef.user_id is required".into())
+        }
+        if self.user_id.bytes.len() != USER_ID_BYTES {
+            return Some(format!("ReplyRef.user_id must be {} bytes", USER_ID_BYTES).into())
+        }
+        if self.signature.is_none() {
+            return Some("ReplyRef.signature is required".into())
+        }
+        if self.signature.bytes.len() != SIGNATURE_BYTES {
+            return Some(format!("ReplyRef.signature must be {} bytes", SIGNATURE_BYTES).into())
+        }
+
         None
     }
 }
@@ -69,4 +205,185 @@ impl Display for ValidationError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> { 
         write!(f, "Protobuf validation error: {}", self.message)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::MessageField;
+
+    use super::*;
+
+    fn post() -> Item {
+        let mut item = Item::new();
+        item.timestamp_ms_utc = 1;
+        item.set_post(Post::new());
+        item
+    }
+
+    fn file() -> File {
+        let mut file = File::new();
+        file.name = "example.txt".into();
+        file.size = 42;
+        file.hash = vec![0; 64];
+        file
+    }
+
+    fn with_file(file: File) -> Item {
+        let mut item = post();
+        item.mut_post().attachments.mut_or_insert_default().file.push(file);
+        item
+    }
+
+    fn comment(user_id: Option<Vec<u8>>, signature: Option<Vec<u8>>) -> Item {
+        let mut reply_to = ReplyRef::new();
+        if let Some(bytes) = user_id {
+            reply_to.user_id.mut_or_insert_default().bytes = bytes;
+        }
+        if let Some(bytes) = signature {
+            reply_to.signature.mut_or_insert_default().bytes = bytes;
+        }
+        let mut comment = Comment::new();
+        comment.reply_to = MessageField::some(reply_to);
+
+        let mut item = Item::new();
+        item.timestamp_ms_utc = 1;
+        item.set_comment(comment);
+        item
+    }
+
+    fn server(url: &str) -> Item {
+        let mut server = Server::new();
+        server.url = url.into();
+        let mut profile = Profile::new();
+        profile.servers.push(server);
+
+        let mut item = Item::new();
+        item.timestamp_ms_utc = 1;
+        item.set_profile(profile);
+        item
+    }
+
+    fn error(item: &Item) -> String {
+        item.get_error().expect("expected an error").into_owned()
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(None, post().get_error());
+        assert_eq!(None, with_file(file()).get_error());
+        assert_eq!(None, comment(Some(vec![0; 32]), Some(vec![0; 64])).get_error());
+        assert_eq!(None, server("https://feo.example.com:8080/").get_error());
+    }
+
+    #[test]
+    fn timestamp_required() {
+        let mut item = post();
+        item.timestamp_ms_utc = 0;
+        assert_eq!("Timestamp is required", error(&item));
+    }
+
+    #[test]
+    fn utc_offset() {
+        let mut item = post();
+        item.utc_offset_minutes = -24 * 60;
+        assert_eq!(None, item.get_error());
+        item.utc_offset_minutes = 24 * 60 + 1;
+        assert_eq!("utc_offset_minutes must be within +/- 1440 minutes", error(&item));
+    }
+
+    #[test]
+    fn item_type_required() {
+        let mut item = Item::new();
+        item.timestamp_ms_utc = 1;
+        assert_eq!("Item type is required", error(&item));
+    }
+
+    #[test]
+    fn title_length() {
+        let mut item = post();
+        item.mut_post().title = "x".repeat(256);
+        assert_eq!(None, item.get_error());
+        item.mut_post().title = "x".repeat(257);
+        assert_eq!("Post.title must be <= 256 bytes", error(&item));
+    }
+
+    #[test]
+    fn file_name() {
+        let mut f = file();
+        f.name = "".into();
+        assert_eq!("File.name is required", error(&with_file(f)));
+
+        for name in ["../x.txt", "a\\b.txt"] {
+            let mut f = file();
+            f.name = name.into();
+            assert_eq!("File.name may not contain path separators", error(&with_file(f)));
+        }
+    }
+
+    #[test]
+    fn file_size() {
+        let mut f = file();
+        f.size = 0;
+        assert_eq!("File.size must be > 0", error(&with_file(f)));
+    }
+
+    #[test]
+    fn file_hash() {
+        let mut f = file();
+        f.hash = vec![0; 32];
+        assert_eq!("File.hash must be 64 bytes", error(&with_file(f)));
+    }
+
+    #[test]
+    fn reply_ref() {
+        let mut item = comment(None, None);
+        item.mut_comment().reply_to = MessageField::none();
+        assert_eq!("Comment.reply_to is required", error(&item));
+
+        assert_eq!("ReplyRef.user_id is required", error(&comment(None, Some(vec![0; 64]))));
+        assert_eq!("ReplyRef.user_id must be 32 bytes", error(&comment(Some(vec![0; 31]), Some(vec![0; 64]))));
+        assert_eq!("ReplyRef.signature is required", error(&comment(Some(vec![0; 32]), None)));
+        assert_eq!("ReplyRef.signature must be 64 bytes", error(&comment(Some(vec![0; 32]), Some(vec![0; 32]))));
+    }
+
+    #[test]
+    fn follow_user_id() {
+        let mut item = server("https://feo.example.com");
+        item.mut_profile().follows.push(Follow::new());
+        assert_eq!("UserID.bytes must be 32 bytes", error(&item));
+    }
+
+    #[test]
+    fn server_url() {
+        for url in ["http://feo.example.com", "https://feo.example.com/", "https://feo.example.com:8080"] {
+            assert_eq!(None, server(url).get_error(), "{}", url);
+        }
+
+        assert_eq!("Server.url must be an http(s) URL", error(&server("ftp://feo.example.com")));
+        assert_eq!("Server.url must be an http(s) URL", error(&server("feo.example.com")));
+        assert_eq!("Server.url must include a host", error(&server("https://")));
+        assert_eq!("Server.url may not include a subpath", error(&server("https://feo.example.com/some/subpath/")));
+        assert_eq!("Server.url may not include a subpath", error(&server("https://feo.example.com/?q=1")));
+    }
+
+    #[test]
+    fn follow_group_name() {
+        let mut profile = Profile::new();
+        for name in ["News", " "] {
+            let mut group = FollowGroup::new();
+            group.name = name.into();
+            profile.follow_groups.push(group);
+        }
+        let follow = |group| {
+            let mut follow = Follow::new();
+            follow.follow_group = group;
+            follow
+        };
+
+        assert_eq!(Some("News"), profile.follow_group_name(&follow(Some(0))));
+        assert_eq!(None, profile.follow_group_name(&follow(None)));
+        assert_eq!(None, profile.follow_group_name(&follow(Some(1))), "blank names aren't groups");
+        assert_eq!(None, profile.follow_group_name(&follow(Some(2))));
+        assert_eq!(None, profile.follow_group_name(&follow(Some(-1))));
+    }
+}
@@ -1,15 +1,16 @@
-use std::{fmt, net::TcpListener};
+use std::{fmt, net::TcpListener, sync::Arc};
 
 use actix_web::http::header::HeaderValue;
 use backend::FactoryBox;
 use futures::Future;
 
-use actix_web::{middleware::DefaultHeaders, HttpResponse, body};
-use actix_web::http::{Method, header};
+use actix_web::{middleware::DefaultHeaders, HttpRequest, HttpResponse, body};
+use actix_web::http::{Method, StatusCode, header::{self, EntityTag, Header}};
 
 use actix_web::web::{
     self,
     get,
+    post,
     put,
     route,
     Data,
@@ -17,17 +18,31 @@ use actix_web::web::{
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
 
 use actix_web::{App, HttpServer};
-use anyhow::Context;
+use anyhow::{Context, bail};
 
 
 use crate::ServeCommand;
-use crate::backend;
+use crate::backend::{self, Signature, UserID};
 
+mod activitypub;
 mod attachments;
+mod compression;
+pub(crate) mod config;
+mod feeds;
 mod html;
+mod json;
+mod metrics;
 mod pagination;
+mod rate_limit;
 mod rest;
+mod server_info;
 mod non_standard;
+mod stream;
+mod thumbnails;
+mod tls;
+pub(crate) mod webhooks;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 
 pub(crate) fn serve(command: ServeCommand) -> Result<(), anyhow::Error> {
@@ -38,35 +53,92 @@ pub(crate) fn serve(command: ServeCommand) -> Result<(), anyhow::Error> {
     
     sodiumoxide::init().expect("sodiumoxide::init()");
 
-    let ServeCommand{open, backend_options, mut binds} = command;
+    let ServeCommand{
+        open, backend_options, config, binds, tls_binds, tls_cert, tls_key,
+        unsafe_markdown, activitypub_url, metrics: serve_metrics, metrics_bind,
+    } = command;
+
+    let mut config = config::Config::load(config.as_deref())?;
+    if !binds.is_empty() || !tls_binds.is_empty() {
+        config.server.binds = binds;
+        config.server.tls_binds = tls_binds;
+    }
+    if tls_cert.is_some() {
+        config.server.tls_cert = tls_cert;
+    }
+    if tls_key.is_some() {
+        config.server.tls_key = tls_key;
+    }
+    config.validate().context("Invalid configuration")?;
+    let config = Arc::new(config);
+    let binds = config.server.binds.clone();
+    let tls_binds = config.server.tls_binds.clone();
+
+    let cert_resolver = match (&config.server.tls_cert, &config.server.tls_key) {
+        (Some(cert), Some(key)) if !tls_binds.is_empty() => Some(Arc::new(tls::CertResolver::new(cert, key)?)),
+        _ => None,
+    };
+
+    if let Some(url) = &activitypub_url {
+        if url.scheme() != "https" && url.scheme() != "http" {
+            bail!("--activitypub-url must be an http(s) URL: {}", url);
+        }
+    }
 
     let factory_box = FactoryBox{
-        factory: backend_options.factory_builder()?.factory()?
+        factory: backend_options.factory_builder_for(&config.database)?.factory()?
     };
+    // We haven't started accepting uploads yet, so any unfinished ones were abandoned. (ex: by a crash.)
+    let deleted = factory_box.factory.open()?.delete_unfinished_attachments()?;
+    if deleted > 0 {
+        println!("Deleted {} unfinished attachment uploads.", deleted);
+    }
+    let webhooks = webhooks::start(factory_box.factory.dyn_clone())?;
+    let expiring_factory = factory_box.factory.dyn_clone();
+    let partial_upload_expiry_hours = config.attachments.partial_upload_expiry_hours;
+    let metrics = Arc::new(metrics::Metrics::new()?);
+    let rate_limits = Arc::new(rate_limit::RateLimits::new(&config.rate_limits));
+
+    let app_data = move || Data::new(
+        AppData{
+            backend_factory: factory_box.factory.dyn_clone(),
+            unsafe_markdown,
+            activitypub_url: activitypub_url.clone(),
+            webhooks: webhooks.clone(),
+            metrics: metrics.clone(),
+            config: config.clone(),
+            rate_limits: rate_limits.clone(),
+        }
+    );
+
+    // If there's a separate admin listener, /metrics is served only there:
+    let admin_data = app_data.clone();
+    let serve_metrics = serve_metrics && metrics_bind.is_none();
 
     let app_factory = move || {
-        let data = Data::new(
-            AppData{
-                backend_factory: factory_box.factory.dyn_clone(),
-            }
-        );
+        let data = app_data();
+        let activitypub = data.activitypub_url.is_some();
         let mut app = App::new()
+            .wrap_fn(compression::compress)
+            .wrap_fn(metrics::record)
             .wrap(actix_web::middleware::Logger::default())
             .app_data(data)
             ;
         app = app.configure(api_routes);
+        if activitypub {
+            app = app.configure(activitypub::routes);
+        }
+        if serve_metrics {
+            app = app.configure(metrics::routes);
+        }
         
         // Soon to be deprecated.  (First: upgrade mastodon & RSS scripts)
         app = app.configure(deprecated_api_routes);
         app = app.configure(html::routes);
 
-        return app;
+        app
     };
 
-    if binds.is_empty() {
-        binds.push("127.0.0.1:8080".into());
-    }
-
     let mut server = HttpServer::new(app_factory); 
     
     for bind in &binds {
@@ -76,22 +148,62 @@ pub(crate) fn serve(command: ServeCommand) -> Result<(), anyhow::Error> {
         server = server.listen(socket)?;
     }
 
+    if let Some(resolver) = &cert_resolver {
+        let tls_config = resolver.server_config()?;
+        for bind in &tls_binds {
+            let socket = open_socket(bind).with_context(|| {
+                format!("Error binding to address/port: {}", bind)
+            })?;
+            server = server.listen_rustls_0_23(socket, tls_config.clone())?;
+        }
+    }
+
+    let urls: Vec<String> = binds.iter().map(|bind| format!("http://{}/", bind))
+        .chain(tls_binds.iter().map(|bind| format!("https://{}/", bind)))
+        .collect();
+
     if open {
         // TODO: This opens up a (AFAICT) blocking CLI browser on Linux. Boo. Don't do that.
         // TODO: Handle wildcard addresses (0.0.0.0, ::0) and --open them via localhost.
-        let url = format!("http://{}/", binds[0]);
-        let opened = webbrowser::open(&url);
-        if !opened.is_ok() {
+        let opened = webbrowser::open(&urls[0]);
+        if opened.is_err() {
             println!("Warning: Couldn't open browser.");
         }
     }
 
-    for bind in &binds {
-        println!("Started at: http://{}/", bind);
+    for url in &urls {
+        println!("Started at: {}", url);
     }
+
+    let admin_server = match &metrics_bind {
+        None => None,
+        Some(bind) => {
+            let socket = open_socket(bind).with_context(|| {
+                format!("Error binding to address/port: {}", bind)
+            })?;
+            let admin_server = HttpServer::new(move || {
+                App::new()
+                    .app_data(admin_data())
+                    .configure(metrics::routes)
+            })
+            .workers(1)
+            .listen(socket)?;
+            println!("Metrics at: http://{}/metrics", bind);
+            Some(admin_server)
+        }
+    };
  
     let system = actix_web::rt::System::new();
-    system.block_on(server.run())?;
+    system.block_on(async move {
+        attachments::expire_partial_uploads(expiring_factory, partial_upload_expiry_hours);
+        if let Some(resolver) = cert_resolver {
+            tls::reload_on_sighup(resolver).map_err(std::io::Error::other)?;
+        }
+        match admin_server {
+            None => server.run().await,
+            Some(admin_server) => futures::future::try_join(server.run(), admin_server.run()).await.map(|_| ()),
+        }
+    })?;
    
     Ok(())
 }
@@ -124,13 +236,62 @@ fn open_socket(bind: &str) -> Result<TcpListener, anyhow::Error> {
 // yourself.
 pub(crate) struct AppData {
     backend_factory: Box<dyn backend::Factory>,
+    unsafe_markdown: UnsafeMarkdownPolicy,
+
+    /// Public URL of this server, if the ActivityPub bridge is enabled.
+    activitypub_url: Option<url::Url>,
+
+    webhooks: webhooks::Notifier,
+    metrics: Arc<metrics::Metrics>,
+    config: Arc<config::Config>,
+    rate_limits: Arc<rate_limit::RateLimits>,
+}
+
+/// How to handle uploaded Items whose markdown contains raw HTML or script links.
+/// See: [`crate::markdown`]
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnsafeMarkdownPolicy {
+    /// Refuse the Item with a 400 Bad Request.
+    Reject,
+
+    /// Accept the Item, but set `unsafe_markdown` on its ItemListEntry.
+    Flag,
 }
 
 fn api_routes(cfg: &mut web::ServiceConfig) {
     cfg
+        .service(
+            web::resource("/diskuto/server-info")
+            .route(get().to(server_info::server_info))
+            .wrap(cors_ok_headers())
+        )
+
         .service(
             web::resource("/diskuto/homepage")
             .route(get().to(rest::homepage_item_list))
+            .wrap_fn(rate_limit::list_gets)
+            .wrap(cors_ok_headers())
+        )
+
+        .service(
+            web::resource("/diskuto/homepage/stream")
+            .route(get().to(stream::homepage_stream))
+            .wrap_fn(rate_limit::list_gets)
+            .wrap(cors_ok_headers())
+        )
+
+        .service(
+            web::resource("/diskuto/homepage.atom")
+            .route(get().to(feeds::homepage_atom))
+            .wrap_fn(rate_limit::list_gets)
+            .wrap(cors_ok_headers())
+        )
+
+        .service(
+            web::resource("/diskuto/items/batch")
+            .route(post().to(rest::get_item_batch))
+            .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap_fn(rate_limit::list_gets)
             .wrap(cors_ok_headers())
         )
 
@@ -142,11 +303,37 @@ fn api_routes(cfg: &mut web::ServiceConfig) {
         .service(
             web::resource("/diskuto/users/{user_id}/items")
             .route(get().to(rest::user_item_list))
+            .wrap_fn(rate_limit::list_gets)
             .wrap(cors_ok_headers())
         )
         .service(
             web::resource("/diskuto/users/{user_id}/feed")
             .route(get().to(rest::feed_item_list))
+            .wrap_fn(rate_limit::list_gets)
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/feed/stream")
+            .route(get().to(stream::feed_stream))
+            .wrap_fn(rate_limit::list_gets)
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/feed.atom")
+            .route(get().to(feeds::user_atom))
+            .wrap_fn(rate_limit::list_gets)
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/feed.rss")
+            .route(get().to(feeds::user_rss))
+            .wrap_fn(rate_limit::list_gets)
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/notifications")
+            .route(get().to(rest::notification_item_list))
+            .wrap_fn(rate_limit::list_gets)
             .wrap(cors_ok_headers())
         )
 
@@ -154,28 +341,37 @@ fn api_routes(cfg: &mut web::ServiceConfig) {
         .service(
             web::resource("/diskuto/users/{user_id}/icon.png")
             .route(get().to(non_standard::identicon_get))
-            .wrap_fn(immutable_etag)
+            .wrap_fn(immutable_icon)
+        )
+        // Must come before the {signature} route, which would also match:
+        .service(
+            web::resource("/diskuto/users/{user_id}/items/{signature}.html")
+            .route(get().to(html::item_preview))
         )
         .service(
             web::resource("/diskuto/users/{userID}/items/{signature}")
             .route(get().to(rest::get_item))
             .route(put().to(rest::put_item))
             .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap_fn(rate_limit::item_puts)
             .wrap(cors_ok_headers())
-            .wrap_fn(immutable_etag)
+            .wrap_fn(immutable_item)
         )
         .service(
             web::resource("/diskuto/users/{user_id}/items/{signature}/replies")
             .route(get().to(rest::item_reply_list))
+            .wrap_fn(rate_limit::list_gets)
             .wrap(cors_ok_headers())
         ).service(
             web::resource("/diskuto/users/{user_id}/items/{signature}/files/{file_name}")
             .route(get().to(attachments::get_file))
             .route(put().to(attachments::put_file))
+            .route(route().method(Method::PATCH).to(attachments::patch_file))
             .route(route().method(Method::HEAD).to(attachments::head_file))
             .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap_fn(rate_limit::attachment_puts)
             .wrap(cors_ok_headers())
-            .wrap_fn(immutable_etag)
+            .wrap_fn(immutable_file)
         )
     ;
 }
@@ -187,29 +383,33 @@ fn deprecated_api_routes(cfg: &mut web::ServiceConfig) {
         .service(
             web::resource("/homepage/proto3")
             .route(get().to(rest::homepage_item_list))
+            .wrap_fn(rate_limit::list_gets)
             .wrap(cors_ok_headers())
         )
         .service(
             web::resource("/u/{user_id}/proto3")
             .route(get().to(rest::user_item_list))
+            .wrap_fn(rate_limit::list_gets)
             .wrap(cors_ok_headers())
         )
         .service(
             web::resource("/u/{user_id}/icon.png")
             .route(get().to(non_standard::identicon_get))
-            .wrap_fn(immutable_etag)
+            .wrap_fn(immutable_icon)
         )
         .service(
             web::resource("/u/{userID}/i/{signature}/proto3")
             .route(get().to(rest::get_item))
             .route(put().to(rest::put_item))
             .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap_fn(rate_limit::item_puts)
             .wrap(cors_ok_headers())
-            .wrap_fn(immutable_etag)
+            .wrap_fn(immutable_item)
         )
         .service(
             web::resource("/u/{user_id}/i/{signature}/replies/proto3")
             .route(get().to(rest::item_reply_list))
+            .wrap_fn(rate_limit::list_gets)
             .wrap(cors_ok_headers())
         ).service(
             web::resource("/u/{user_id}/i/{signature}/files/{file_name}")
@@ -217,15 +417,20 @@ fn deprecated_api_routes(cfg: &mut web::ServiceConfig) {
             .route(put().to(attachments::put_file))
             .route(route().method(Method::HEAD).to(attachments::head_file))
             .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap_fn(rate_limit::attachment_puts)
             .wrap(cors_ok_headers())
-            .wrap_fn(immutable_etag)
+            .wrap_fn(immutable_file)
         )
         .service(
             web::resource("/u/{user_id}/profile/proto3")
             .route(get().to(rest::get_profile_item))
             .wrap(cors_ok_headers())
         )
-        .route("/u/{user_id}/feed/proto3", get().to(rest::feed_item_list))
+        .service(
+            web::resource("/u/{user_id}/feed/proto3")
+            .route(get().to(rest::feed_item_list))
+            .wrap_fn(rate_limit::list_gets)
+        )
     ;
 }
 
@@ -244,44 +449,110 @@ fn http_not_modified() -> HttpResponse {
     HttpResponse::NotModified().body(body::None::new())
 }
 
+/// Whether the client's If-None-Match already includes `etag`.
+fn etag_matches(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match header::IfNoneMatch::parse(req) {
+        Ok(header::IfNoneMatch::Any) => true,
+        Ok(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+/// Items are addressed by their signature, so it makes a fine ETag.
+/// (JSON is another representation of the same Item, so gets its own.)
+fn item_etag(signature: &Signature, json: bool) -> EntityTag {
+    let suffix = if json { ".json" } else { "" };
+    EntityTag::new_strong(format!("{}{}", signature.to_base58(), suffix))
+}
+
+/// Identicons are generated from the user ID.
+fn icon_etag(user_id: &UserID) -> EntityTag {
+    EntityTag::new_strong(format!("icon-{}", user_id.to_base58()))
+}
+
+fn immutable_item<S>(req: ServiceRequest, service: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    let etag = req.match_info().get("signature")
+        .and_then(|sig| Signature::from_base58(sig).ok())
+        .map(|sig| item_etag(&sig, json::wants_json(req.request())));
+    immutable(req, service, etag)
+}
+
+fn immutable_icon<S>(req: ServiceRequest, service: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    let etag = req.match_info().get("user_id")
+        .and_then(|user| UserID::from_base58(user).ok())
+        .map(|user| icon_etag(&user));
+    immutable(req, service, etag)
+}
+
+/// Attachments' ETags come from their hash, which requires a DB lookup, so attachments::get_file() sets them.
+fn immutable_file<S>(req: ServiceRequest, service: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    immutable(req, service, None)
+}
+
 /// Browsers like to re-validate things even when they don't need to. (Say, when the user hits reload.)
-/// For our content-addressable URLs, make a shortcut etag to spare us some bandwidth & DB hits:
-fn immutable_etag<'a, S>(req: ServiceRequest, service: &'a S) 
+/// Our URLs are content-addressable, so a matching ETag means the client already has the right content.
+///
+/// If we know the `etag` from the request alone, we can skip dispatching (and DB hits) entirely.
+/// Otherwise, we compare against the ETag that the handler set.
+fn immutable<S>(req: ServiceRequest, service: &S, etag: Option<EntityTag>)
 -> impl Future<Output = Result<ServiceResponse, S::Error>>
 where S: Service<ServiceRequest, Response=ServiceResponse>
 {
     use actix_web::Either;
 
-    let is_get = req.method() == &Method::GET;
-    // If the client sends us an if-none-match, they're just sending back our "immutable" ETag.
-    // This means they already have our data and are just trying to re-load it unnecessarily.
-    let cache_validation_request = req.headers().get("if-none-match").is_some();
+    let cacheable = req.method() == Method::GET || req.method() == Method::HEAD;
 
+    // Note: An If-None-Match of "*" must wait until we know that the resource exists.
+    let known_match = match (&etag, header::IfNoneMatch::parse(req.request())) {
+        (Some(etag), Ok(header::IfNoneMatch::Items(tags))) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        _ => false,
+    };
 
-    let fut = if !cache_validation_request {
-        Either::Left(service.call(req))
-    } else {
+    let fut = if cacheable && known_match {
         // Skip dispatching to the underlying service, and pass along the req:
         Either::Right(req)
+    } else {
+        Either::Left(service.call(req))
     };
     async move {
-        let res = match fut {
-            Either::Left(fut) => fut.await,
-            Either::Right(req) => {
-                let res = req.into_response(http_not_modified());
-                return Ok(res);
-            }
+        let mut res = match fut {
+            Either::Left(fut) => fut.await?,
+            Either::Right(req) => req.into_response(http_not_modified()),
         };
 
-        let mut res = match res {
-            // If result was an error, no caching:
-            Err(r) => { return Err(r); }
-            Ok(r) => r,
-        };
+        if !cacheable {
+            return Ok(res);
+        }
+
+        // Handlers may set their own:
+        let etag = etag.or_else(|| {
+            res.headers().get(header::ETAG)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<EntityTag>().ok())
+        });
+
+        if let Some(etag) = &etag {
+            if res.status().is_success() && etag_matches(res.request(), etag) {
+                let (req, _) = res.into_parts();
+                res = ServiceResponse::new(req, http_not_modified());
+            }
+        }
 
-        if is_get && res.response().status().is_success() {
+        // A 304 should have the same caching headers that a 200 would have:
+        if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED {
             let headers = res.headers_mut();
-            headers.insert(header::ETAG, HeaderValue::from_static("\"immutable\""));
+            if let Some(etag) = &etag {
+                headers.insert(header::ETAG, HeaderValue::from_str(&etag.to_string()).expect("valid ETag"));
+            }
                     
             // "aggressive caching" according to https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Cache-Control
             // 31536000 = 365 days, as seconds
@@ -302,25 +573,22 @@ fn cors_ok_headers() -> DefaultHeaders {
     DefaultHeaders::new()
     .add(("Access-Control-Allow-Origin", "*"))
     .add(("Access-Control-Expose-Headers", "*"))
-
-    // Number of seconds a browser can cache the cors allows.
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Access-Control-Max-Age
-    // FF caps this at 24 hours, and is the most permissive there, so that's what we'll use.
-    // Does this mean that my Cache-Control max-age is truncated to this value? That would be sad.
-    .add(("Access-Control-Max-Age", "86400"))
 }
 
 // Before browsers will post data to a server, they make a CORS OPTIONS request to see if that's OK.
 // This responds to that request to let the client know this request is allowed.
-async fn cors_preflight_allow() -> HttpResponse {
+async fn cors_preflight_allow(data: Data<AppData>) -> HttpResponse {
     HttpResponse::NoContent()
-        .append_header(("Access-Control-Allow-Methods", "OPTIONS, GET, PUT, HEAD"))
+        .append_header(("Access-Control-Allow-Methods", "OPTIONS, GET, POST, PUT, PATCH, HEAD"))
+        // For resumable uploads. See: attachments::patch_file()
+        .append_header(("Access-Control-Allow-Headers", "Content-Type, Upload-Offset, Upload-Token"))
+        // Number of seconds a browser can cache the cors allows. See: config::LimitsConfig
+        .append_header(("Access-Control-Max-Age", data.config.limits.cors_max_age_secs.to_string()))
         .body("")
 }
 
 
-const MAX_ITEM_SIZE: usize = 1024 * 32; 
-const PLAINTEXT: &'static str = "text/plain; charset=utf-8";
+const PLAINTEXT: &str = "text/plain; charset=utf-8";
 
 
 
@@ -355,9 +623,9 @@ pub struct SendError {
     inner: Box<dyn std::error::Error + Send + 'static>
 }
 
-impl Into<Box<dyn std::error::Error>> for SendError {
-    fn into(self) -> Box<dyn std::error::Error> {
-        self.inner
+impl From<SendError> for Box<dyn std::error::Error> {
+    fn from(val: SendError) -> Self {
+        val.inner
     }
 }
 
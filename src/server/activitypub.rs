@@ -0,0 +1,1245 @@
+//! A read-only ActivityPub bridge for server users.
+//!
+//! Each server user gets an ActivityPub actor, discoverable via WebFinger, whose
+//! outbox contains their Posts. (As `Note`s, or `Article`s if they have a title.)
+//! Remote actors (ex: Mastodon users) can Follow them, and we deliver a `Create`
+//! to followers' inboxes when a new Post is uploaded.
+//!
+//! ActivityPub servers don't know about our users' ed25519 keys, so we sign
+//! requests (with HTTP Signatures) using an RSA key that we generate and store
+//! for each user. Note that signatures cover the `Host` header, so a reverse
+//! proxy must pass it through unchanged.
+//!
+//! Only enabled with `diskuto serve --activitypub-url`.
+
+use std::{collections::HashMap, convert::TryFrom, io::{self, Read}, net::{IpAddr, SocketAddr, ToSocketAddrs}, str::FromStr, sync::{Mutex, OnceLock, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant, SystemTime}};
+
+use actix_web::{HttpRequest, HttpResponse, http::header::{self, HttpDate}, web::{self, Bytes, Data, Path, Query, ServiceConfig, get, post}};
+use anyhow::{Context, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use log::{info, warn};
+use protobuf::Message;
+use rsa::{RsaPrivateKey, RsaPublicKey, pkcs1::DecodeRsaPublicKey, pkcs1v15::{SigningKey, VerifyingKey}, pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding}, signature::{SignatureEncoding, Signer, Verifier}};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{backend::{ActivityPubFollower, Backend, Factory, ItemRow, Signature, TimeSpan, Timestamp, UserID}, markdown, protos::{Item, ItemType}, util::AsHex};
+
+use super::{AppData, Error, PLAINTEXT, config::{AttachmentsConfig, Config}, cors_ok_headers, feeds::rfc3339, html::not_found, rate_limit, webhooks::Notifier};
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// Posts per page of an outbox.
+const PAGE_SIZE: usize = 20;
+
+/// How far the `Date` of a signed request may be from our own clock.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Only deliver Posts this recent to followers.
+/// Older ones are probably being copied from another server, and would flood timelines.
+const MAX_DELIVERY_AGE_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Don't read more than this from remote servers.
+const MAX_FETCH_BYTES: u64 = 1024 * 1024;
+
+/// How long we reuse a remote actor (and its key) before fetching it again.
+const ACTOR_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_CACHED_ACTORS: usize = 1000;
+
+/// Tests run stub ActivityPub servers on localhost, over plain HTTP.
+const ALLOW_LOCAL_FETCHES: bool = cfg!(test);
+
+const RSA_BITS: usize = 2048;
+
+/// Inbox requests that may be fetching remote keys at once. Any more get a 503.
+const MAX_PENDING_RECEIVES: usize = 16;
+static PENDING_RECEIVES: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn routes(cfg: &mut ServiceConfig) {
+    cfg
+        .service(
+            web::resource("/.well-known/webfinger")
+            .route(get().to(webfinger))
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/activitypub")
+            .route(get().to(actor))
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/activitypub/outbox")
+            .route(get().to(outbox))
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/activitypub/followers")
+            .route(get().to(followers))
+            .wrap(cors_ok_headers())
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/activitypub/inbox")
+            .route(post().to(inbox))
+            .wrap_fn(rate_limit::inbox_posts)
+        )
+        .service(
+            web::resource("/diskuto/users/{user_id}/items/{signature}/activitypub")
+            .route(get().to(note))
+            .wrap(cors_ok_headers())
+        )
+    ;
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebFingerQuery {
+    resource: String,
+}
+
+/// `/.well-known/webfinger?resource=acct:{userID}@{host}`
+pub(crate) async fn webfinger(
+    data: Data<AppData>,
+    Query(query): Query<WebFingerQuery>,
+) -> Result<HttpResponse, Error> {
+    let base = base_url(&data)?;
+    let user_id = match webfinger_user(base, &query.resource) {
+        Some(user_id) => user_id,
+        None => return not_found().await,
+    };
+
+    let backend = data.backend_factory.open()?;
+    if backend.server_user(&user_id)?.is_none() {
+        return not_found().await;
+    }
+
+    let urls = Urls::new(base, &user_id);
+    let body = json!({
+        "subject": format!("acct:{}@{}", user_id, host(base)),
+        "aliases": [urls.actor(), urls.profile_page()],
+        "links": [
+            {
+                "rel": "self",
+                "type": ACTIVITY_JSON,
+                "href": urls.actor(),
+            },
+            {
+                "rel": "http://webfinger.net/rel/profile-page",
+                "type": "text/html",
+                "href": urls.profile_page(),
+            },
+        ],
+    });
+
+    Ok(
+        HttpResponse::Ok()
+        .content_type("application/jrd+json")
+        .body(body.to_string())
+    )
+}
+
+/// Find the user in a WebFinger `resource`. It may be `acct:{userID}@{host}` or the actor's URL.
+fn webfinger_user(base: &Url, resource: &str) -> Option<UserID> {
+    if let Some(account) = resource.strip_prefix("acct:") {
+        let (user, account_host) = account.rsplit_once('@')?;
+        if !account_host.eq_ignore_ascii_case(&host(base)) {
+            return None;
+        }
+        return UserID::from_base58(user).ok();
+    }
+
+    let prefix = format!("{}/diskuto/users/", base.as_str().trim_end_matches('/'));
+    let user = resource.strip_prefix(&prefix)?.strip_suffix("/activitypub")?;
+    UserID::from_base58(user).ok()
+}
+
+/// `/diskuto/users/{userID}/activitypub`
+pub(crate) async fn actor(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    let base = base_url(&data)?.clone();
+    let factory = data.backend_factory.dyn_clone();
+
+    // Generating a key is slow, so get off of the main thread:
+    let actor = blocking::unblock(move || -> Result<Option<Value>, anyhow::Error> {
+        let backend = factory.open()?;
+        if backend.server_user(&user_id)?.is_none() {
+            return Ok(None);
+        }
+
+        let key = user_key(backend.as_ref(), &user_id)?;
+        let public_key_pem = RsaPublicKey::from(&key).to_public_key_pem(LineEnding::LF)?;
+
+        let mut display_name = String::new();
+        let mut about = String::new();
+        if let Some(row) = backend.user_profile(&user_id)? {
+            let mut item = Item::new();
+            item.merge_from_bytes(&row.item_bytes)?;
+            display_name = item.profile().display_name.trim().to_string();
+            about = item.profile().about.clone();
+        }
+        if display_name.is_empty() {
+            display_name = user_id.to_base58();
+        }
+
+        let urls = Urls::new(&base, &user_id);
+        let profile_page = Url::parse(&urls.profile_page())?;
+        Ok(Some(with_context(json!({
+            "id": urls.actor(),
+            "type": "Person",
+            "preferredUsername": user_id.to_base58(),
+            "name": display_name,
+            "summary": markdown::to_html(&about, Some(&profile_page)),
+            "url": urls.profile_page(),
+            "icon": {
+                "type": "Image",
+                "mediaType": "image/png",
+                "url": urls.icon(),
+            },
+            "inbox": urls.inbox(),
+            "outbox": urls.outbox(),
+            "followers": urls.followers(),
+            "manuallyApprovesFollowers": false,
+            "publicKey": {
+                "id": urls.key_id(),
+                "owner": urls.actor(),
+                "publicKeyPem": public_key_pem,
+            },
+        }))))
+    }).await?;
+
+    match actor {
+        Some(actor) => Ok(activity_ok(&actor)),
+        None => not_found().await,
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct OutboxQuery {
+    #[serde(default)]
+    page: bool,
+
+    /// Timestamp (ms UTC) to page backward from.
+    before: Option<i64>,
+}
+
+/// `/diskuto/users/{userID}/activitypub/outbox`
+pub(crate) async fn outbox(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+    Query(query): Query<OutboxQuery>,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    let base = base_url(&data)?;
+    let backend = data.backend_factory.open()?;
+    if backend.server_user(&user_id)?.is_none() {
+        return not_found().await;
+    }
+
+    let urls = Urls::new(base, &user_id);
+    if !query.page && query.before.is_none() {
+        return Ok(activity_ok(&with_context(json!({
+            "id": urls.outbox(),
+            "type": "OrderedCollection",
+            "first": format!("{}?page=true", urls.outbox()),
+        }))));
+    }
+
+    let before = match query.before {
+        Some(unix_utc_ms) => Timestamp{ unix_utc_ms },
+        None => Timestamp::now(),
+    };
+    let mut activities = vec![];
+    let mut oldest = None;
+    backend.user_items(&user_id, TimeSpan::Before(before), &[ItemType::POST], &mut |row| {
+        let mut item = Item::new();
+        item.merge_from_bytes(&row.item_bytes)?;
+        if item.has_post() {
+            activities.push(create_activity(&urls, &data.config.attachments, &row, &item)?);
+            oldest = Some(row.timestamp);
+        }
+        Ok(activities.len() < PAGE_SIZE)
+    })?;
+
+    let id = match query.before {
+        Some(before) => format!("{}?page=true&before={}", urls.outbox(), before),
+        None => format!("{}?page=true", urls.outbox()),
+    };
+    let mut page = with_context(json!({
+        "id": id,
+        "type": "OrderedCollectionPage",
+        "partOf": urls.outbox(),
+    }));
+    if let (true, Some(oldest)) = (activities.len() == PAGE_SIZE, oldest) {
+        page["next"] = json!(format!("{}?page=true&before={}", urls.outbox(), oldest.unix_utc_ms));
+    }
+    page["orderedItems"] = Value::Array(activities);
+
+    Ok(activity_ok(&page))
+}
+
+/// `/diskuto/users/{userID}/activitypub/followers`
+///
+/// Only reports a count. The followers themselves are nobody else's business.
+pub(crate) async fn followers(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    let base = base_url(&data)?;
+    let backend = data.backend_factory.open()?;
+    if backend.server_user(&user_id)?.is_none() {
+        return not_found().await;
+    }
+
+    let mut count = 0;
+    backend.activitypub_followers(&user_id, &mut |_| {
+        count += 1;
+        Ok(true)
+    })?;
+
+    let urls = Urls::new(base, &user_id);
+    Ok(activity_ok(&with_context(json!({
+        "id": urls.followers(),
+        "type": "OrderedCollection",
+        "totalItems": count,
+    }))))
+}
+
+/// `/diskuto/users/{userID}/items/{signature}/activitypub`
+pub(crate) async fn note(
+    data: Data<AppData>,
+    path: Path<(UserID, Signature)>,
+) -> Result<HttpResponse, Error> {
+    let (user_id, signature) = path.into_inner();
+    let base = base_url(&data)?;
+    let backend = data.backend_factory.open()?;
+    if backend.server_user(&user_id)?.is_none() {
+        return not_found().await;
+    }
+
+    let row = match backend.user_item(&user_id, &signature)? {
+        Some(row) => row,
+        None => return not_found().await,
+    };
+    let mut item = Item::new();
+    item.merge_from_bytes(&row.item_bytes)?;
+    if !item.has_post() {
+        return not_found().await;
+    }
+
+    let urls = Urls::new(base, &user_id);
+    Ok(activity_ok(&with_context(note_object(&urls, &data.config.attachments, &row, &item)?)))
+}
+
+/// `/diskuto/users/{userID}/activitypub/inbox`
+///
+/// We only care about (un)follows. Everything else is accepted and ignored.
+pub(crate) async fn inbox(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+    req: HttpRequest,
+    body: Bytes,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    let base = base_url(&data)?.clone();
+
+    let signed = match SignedRequest::from_request(&req, &body) {
+        Ok(signed) => signed,
+        Err(err) => {
+            return Ok(
+                HttpResponse::Unauthorized()
+                .content_type(PLAINTEXT)
+                .body(err.to_string())
+            );
+        }
+    };
+
+    let activity: Value = match serde_json::from_slice(&body) {
+        Ok(activity) => activity,
+        Err(err) => {
+            return Ok(
+                HttpResponse::BadRequest()
+                .content_type(PLAINTEXT)
+                .body(format!("Invalid JSON: {}", err))
+            );
+        }
+    };
+
+    let slot = match ReceiveSlot::take() {
+        Some(slot) => slot,
+        None => {
+            return Ok(
+                HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, "10"))
+                .content_type(PLAINTEXT)
+                .body("Too many pending inbox requests")
+            );
+        }
+    };
+
+    // Fetching the sender's key is blocking I/O:
+    let factory = data.backend_factory.dyn_clone();
+    let notifier = data.webhooks.clone();
+    let received = blocking::unblock(move || {
+        let _slot = slot;
+        receive(factory.as_ref(), &notifier, &base, &user_id, &signed, &activity)
+    }).await?;
+
+    match received {
+        Received::Accepted => Ok(HttpResponse::Accepted().finish()),
+        Received::NotFound => not_found().await,
+        Received::Unauthorized(message) => Ok(
+            HttpResponse::Unauthorized()
+            .content_type(PLAINTEXT)
+            .body(message)
+        ),
+        Received::BadRequest(message) => Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(message)
+        ),
+    }
+}
+
+/// One of [`MAX_PENDING_RECEIVES`]. Freed when dropped.
+struct ReceiveSlot;
+
+impl ReceiveSlot {
+    fn take() -> Option<Self> {
+        if PENDING_RECEIVES.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_RECEIVES {
+            PENDING_RECEIVES.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self)
+    }
+}
+
+impl Drop for ReceiveSlot {
+    fn drop(&mut self) {
+        PENDING_RECEIVES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+enum Received {
+    Accepted,
+    NotFound,
+    Unauthorized(String),
+    BadRequest(String),
+}
+
+fn receive(
+    factory: &dyn Factory,
+    notifier: &Notifier,
+    base: &Url,
+    user_id: &UserID,
+    signed: &SignedRequest,
+    activity: &Value,
+) -> Result<Received, anyhow::Error> {
+    let backend = factory.open()?;
+    if backend.server_user(user_id)?.is_none() {
+        return Ok(Received::NotFound);
+    }
+
+    let urls = Urls::new(base, user_id);
+    let client = Client::new(user_key(backend.as_ref(), user_id)?, urls.key_id());
+
+    let fetch = |refresh| match client.actor(&signed.key_id, refresh) {
+        Ok(sender) => Ok(sender),
+        Err(err) => Err(Received::Unauthorized(format!("Couldn't fetch key {}: {}", signed.key_id, err))),
+    };
+    let (mut sender, cached) = match fetch(false) {
+        Ok(sender) => sender,
+        Err(received) => return Ok(received),
+    };
+    if signed.verify(&sender.public_key).is_err() && cached {
+        // They may have changed keys since we cached this one:
+        sender = match fetch(true) {
+            Ok((sender, _)) => sender,
+            Err(received) => return Ok(received),
+        };
+    }
+    if signed.verify(&sender.public_key).is_err() {
+        return Ok(Received::Unauthorized("Invalid signature".into()));
+    }
+    if activity["actor"].as_str() != Some(sender.id.as_str()) {
+        return Ok(Received::Unauthorized("Activity was not signed by its actor".into()));
+    }
+
+    match activity["type"].as_str() {
+        Some("Follow") => {
+            if object_id(&activity["object"]) != Some(urls.actor().as_str()) {
+                return Ok(Received::BadRequest(format!("Expected a Follow of {}", urls.actor())));
+            }
+
+            backend.add_activitypub_follower(&ActivityPubFollower {
+                user: user_id.clone(),
+                actor: sender.id.clone(),
+                inbox: sender.inbox.clone(),
+                timestamp: Timestamp::now(),
+                follow_id: activity["id"].as_str().map(str::to_string),
+            })?;
+            info!("{} followed {}", sender.id, user_id);
+
+            let accept = with_context(json!({
+                "id": format!("{}#accepts/{}", urls.actor(), sodiumoxide::randombytes::randombytes(16).as_slice().as_hex()),
+                "type": "Accept",
+                "actor": urls.actor(),
+                "object": activity,
+            }));
+            // Queued, so that it's retried if the sender is down, and sent after they finish processing their Follow:
+            backend.queue_activitypub_delivery(user_id, &sender.inbox, accept.to_string().as_bytes())?;
+            notifier.notify();
+        },
+        Some("Undo") => {
+            // The Follow may be embedded, or just referenced by its ID:
+            let object = &activity["object"];
+            let unfollow = match object.as_str() {
+                Some(follow_id) => {
+                    let mut found = false;
+                    backend.activitypub_followers(user_id, &mut |follower| {
+                        found = follower.actor == sender.id && follower.follow_id.as_deref() == Some(follow_id);
+                        Ok(!found)
+                    })?;
+                    found
+                },
+                None => object["type"].as_str() == Some("Follow"),
+            };
+            if unfollow {
+                backend.remove_activitypub_follower(user_id, &sender.id)?;
+                info!("{} unfollowed {}", sender.id, user_id);
+            }
+        },
+        _ => {
+            // This is a read-only bridge. We don't accept replies, likes, etc.
+        },
+    }
+
+    Ok(Received::Accepted)
+}
+
+/// Queue a newly-uploaded Post for delivery to a server user's ActivityPub followers.
+///
+/// Deliveries share the webhook queue, so failures are retried with backoff.
+pub(crate) fn deliver_post(data: &AppData, row: ItemRow, item: Item) {
+    let base = match &data.activitypub_url {
+        Some(base) => base.clone(),
+        None => return,
+    };
+    if !item.has_post() {
+        return;
+    }
+    if Timestamp::now().unix_utc_ms - row.timestamp.unix_utc_ms > MAX_DELIVERY_AGE_MS {
+        return;
+    }
+
+    let factory = data.backend_factory.dyn_clone();
+    let config = data.config.clone();
+    let notifier = data.webhooks.clone();
+    blocking::unblock(move || {
+        match queue_post(factory.as_ref(), &config, &base, &row, &item) {
+            Ok(0) => {},
+            Ok(_) => notifier.notify(),
+            Err(err) => warn!("Couldn't queue ActivityPub delivery of {}: {:?}", row.signature.to_base58(), err),
+        }
+    }).detach();
+}
+
+/// Returns the number of deliveries queued.
+fn queue_post(factory: &dyn Factory, config: &Config, base: &Url, row: &ItemRow, item: &Item) -> Result<usize, anyhow::Error> {
+    let backend = factory.open()?;
+    if backend.server_user(&row.user)?.is_none() {
+        return Ok(0);
+    }
+
+    let mut inboxes: Vec<String> = vec![];
+    backend.activitypub_followers(&row.user, &mut |follower| {
+        if !inboxes.contains(&follower.inbox) {
+            inboxes.push(follower.inbox);
+        }
+        Ok(true)
+    })?;
+    if inboxes.is_empty() {
+        return Ok(0);
+    }
+
+    let urls = Urls::new(base, &row.user);
+    let activity = with_context(create_activity(&urls, &config.attachments, row, item)?).to_string();
+    for inbox in &inboxes {
+        backend.queue_activitypub_delivery(&row.user, inbox, activity.as_bytes())?;
+    }
+
+    Ok(inboxes.len())
+}
+
+/// Deliver a queued activity to an inbox, signed by the server user who sent it.
+pub(crate) fn deliver(factory: &dyn Factory, user_id: &UserID, inbox: &str, activity: &[u8]) -> Result<(), anyhow::Error> {
+    let activity: Value = serde_json::from_slice(activity)?;
+    let actor = activity["actor"].as_str().context("Activity has no actor")?;
+    // Don't hold a pooled connection while we wait on the network:
+    let key = user_key(factory.open()?.as_ref(), user_id)?;
+    Client::new(key, format!("{}#main-key", actor)).post(inbox, &activity)
+}
+
+// --------------------------------------
+
+fn base_url(data: &AppData) -> Result<&Url, Error> {
+    // Routes are only registered if this is set:
+    data.activitypub_url.as_ref().ok_or_else(|| "ActivityPub is not enabled".into())
+}
+
+/// A host, with port if it's not the default. (Like the `Host` header.)
+fn host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+fn activity_ok(body: &Value) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ACTIVITY_JSON)
+        .body(body.to_string())
+}
+
+/// Add the JSON-LD `@context` to a top-level object.
+fn with_context(mut object: Value) -> Value {
+    object["@context"] = json!([
+        "https://www.w3.org/ns/activitystreams",
+        "https://w3id.org/security/v1",
+    ]);
+    object
+}
+
+/// An object may be referenced by its ID, or embedded.
+fn object_id(object: &Value) -> Option<&str> {
+    match object {
+        Value::String(id) => Some(id),
+        _ => object["id"].as_str(),
+    }
+}
+
+/// Load (or create) the RSA key we use to sign requests for a user.
+fn user_key(backend: &dyn Backend, user_id: &UserID) -> Result<RsaPrivateKey, anyhow::Error> {
+    let pem = match backend.activitypub_key(user_id)? {
+        Some(pem) => pem,
+        None => {
+            let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, RSA_BITS)?;
+            let pem = key.to_pkcs8_pem(LineEnding::LF)?;
+            backend.save_activitypub_key(user_id, &pem)?
+        }
+    };
+    Ok(RsaPrivateKey::from_pkcs8_pem(&pem)?)
+}
+
+/// Note that the Post's ed25519 signature doubles as a unique ID.
+fn note_object(urls: &Urls, attachments: &AttachmentsConfig, row: &ItemRow, item: &Item) -> Result<Value, anyhow::Error> {
+    let post = item.post();
+    let page = urls.item_page(&row.signature);
+
+    let mut files = vec![];
+    for file in &post.attachments.file {
+        files.push(json!({
+            "type": "Document",
+            "mediaType": attachments.mime_type(&file.name).to_string(),
+            "url": urls.file(&row.signature, &file.name)?,
+            "name": file.name,
+        }));
+    }
+
+    let mut note = json!({
+        "id": urls.note(&row.signature),
+        "type": "Note",
+        "attributedTo": urls.actor(),
+        "published": rfc3339(row.timestamp),
+        "url": page,
+        "to": [PUBLIC],
+        "cc": [urls.followers()],
+        "content": markdown::to_html(&post.body, Some(&Url::parse(&page)?)),
+        "attachment": files,
+    });
+    if !post.title.is_empty() {
+        note["type"] = json!("Article");
+        note["name"] = json!(post.title);
+    }
+
+    Ok(note)
+}
+
+fn create_activity(urls: &Urls, attachments: &AttachmentsConfig, row: &ItemRow, item: &Item) -> Result<Value, anyhow::Error> {
+    Ok(json!({
+        "id": format!("{}#create", urls.note(&row.signature)),
+        "type": "Create",
+        "actor": urls.actor(),
+        "published": rfc3339(row.timestamp),
+        "to": [PUBLIC],
+        "cc": [urls.followers()],
+        "object": note_object(urls, attachments, row, item)?,
+    }))
+}
+
+/// URLs for a user's ActivityPub objects, and related web pages.
+struct Urls {
+    /// Base URL, without a trailing slash.
+    base: String,
+    user: String,
+}
+
+impl Urls {
+    fn new(base: &Url, user_id: &UserID) -> Self {
+        Self {
+            base: base.as_str().trim_end_matches('/').to_string(),
+            user: user_id.to_base58(),
+        }
+    }
+
+    fn actor(&self) -> String { format!("{}/diskuto/users/{}/activitypub", self.base, self.user) }
+    fn key_id(&self) -> String { format!("{}#main-key", self.actor()) }
+    fn inbox(&self) -> String { format!("{}/inbox", self.actor()) }
+    fn outbox(&self) -> String { format!("{}/outbox", self.actor()) }
+    fn followers(&self) -> String { format!("{}/followers", self.actor()) }
+    fn icon(&self) -> String { format!("{}/diskuto/users/{}/icon.png", self.base, self.user) }
+
+    fn note(&self, signature: &Signature) -> String {
+        format!("{}/diskuto/users/{}/items/{}/activitypub", self.base, self.user, signature.to_base58())
+    }
+
+    fn file(&self, signature: &Signature, file_name: &str) -> Result<String, anyhow::Error> {
+        let mut url = Url::parse(&format!(
+            "{}/diskuto/users/{}/items/{}/files",
+            self.base,
+            self.user,
+            signature.to_base58(),
+        ))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::format_err!("Invalid base URL: {}", self.base))?
+            .push(file_name);
+        Ok(url.into())
+    }
+
+    /// Web client pages, using the standard URL layout. (See: docs/url_layout.md)
+    fn profile_page(&self) -> String { format!("{}/u/{}/", self.base, self.user) }
+    fn item_page(&self, signature: &Signature) -> String {
+        format!("{}/u/{}/i/{}/", self.base, self.user, signature.to_base58())
+    }
+}
+
+/// Makes HTTP requests signed on behalf of one of our users.
+struct Client {
+    key: RsaPrivateKey,
+    key_id: String,
+    agent: ureq::Agent,
+}
+
+/// A remote actor, as much as we care about it.
+#[derive(Clone)]
+struct RemoteActor {
+    id: String,
+    inbox: String,
+    public_key: RsaPublicKey,
+}
+
+impl Client {
+    fn new(key: RsaPrivateKey, key_id: String) -> Self {
+        let mut agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .user_agent(concat!("diskuto/", env!("CARGO_PKG_VERSION")));
+        if !ALLOW_LOCAL_FETCHES {
+            agent = agent.https_only(true).resolver(public_addresses);
+        }
+        Self { key, key_id, agent: agent.build() }
+    }
+
+    fn get(&self, url: &Url) -> Result<Value, anyhow::Error> {
+        let (date, signature) = sign_request(&self.key, &self.key_id, "get", url, None)?;
+        let response = self.agent.get(url.as_str())
+            .set("Accept", ACTIVITY_JSON)
+            .set("Date", &date)
+            .set("Signature", &signature)
+            .call()?;
+        Ok(serde_json::from_reader(response.into_reader().take(MAX_FETCH_BYTES))?)
+    }
+
+    fn post(&self, url: &str, body: &Value) -> Result<(), anyhow::Error> {
+        let url = Url::parse(url)?;
+        let body = body.to_string();
+        let digest = digest(body.as_bytes());
+        let (date, signature) = sign_request(&self.key, &self.key_id, "post", &url, Some(&digest))?;
+        self.agent.post(url.as_str())
+            .set("Content-Type", ACTIVITY_JSON)
+            .set("Date", &date)
+            .set("Digest", &digest)
+            .set("Signature", &signature)
+            .send_string(&body)?;
+        Ok(())
+    }
+
+    /// The actor that owns a key, from our cache if we fetched it recently (and `refresh` is false).
+    /// Returns whether it was cached.
+    fn actor(&self, key_id: &str, refresh: bool) -> Result<(RemoteActor, bool), anyhow::Error> {
+        static CACHE: OnceLock<Mutex<HashMap<String, (Instant, RemoteActor)>>> = OnceLock::new();
+        let cache = CACHE.get_or_init(Default::default);
+
+        if !refresh {
+            let cache = cache.lock().expect("actor cache");
+            if let Some((fetched, actor)) = cache.get(key_id) {
+                if fetched.elapsed() < ACTOR_CACHE_TTL {
+                    return Ok((actor.clone(), true));
+                }
+            }
+        }
+
+        let actor = self.fetch_actor(key_id)?;
+        let mut cache = cache.lock().expect("actor cache");
+        if cache.len() >= MAX_CACHED_ACTORS {
+            cache.retain(|_, (fetched, _)| fetched.elapsed() < ACTOR_CACHE_TTL);
+            if cache.len() >= MAX_CACHED_ACTORS {
+                cache.clear();
+            }
+        }
+        cache.insert(key_id.to_string(), (Instant::now(), actor.clone()));
+        Ok((actor, false))
+    }
+
+    /// Fetch the actor that owns a key.
+    fn fetch_actor(&self, key_id: &str) -> Result<RemoteActor, anyhow::Error> {
+        let mut url = Url::parse(key_id)?;
+        url.set_fragment(None);
+        let actor = self.get(&url)?;
+
+        let id = actor["id"].as_str().context("Actor has no id")?;
+        if Url::parse(id)?.host_str() != url.host_str() {
+            bail!("Actor {} was served from a different host", id);
+        }
+
+        let key = &actor["publicKey"];
+        if key["id"].as_str() != Some(key_id) || key["owner"].as_str() != Some(id) {
+            bail!("Actor {} does not own key {}", id, key_id);
+        }
+        let pem = key["publicKeyPem"].as_str().context("Key has no publicKeyPem")?;
+        let public_key = RsaPublicKey::from_public_key_pem(pem)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))?;
+
+        Ok(RemoteActor {
+            id: id.to_string(),
+            inbox: actor["inbox"].as_str().context("Actor has no inbox")?.to_string(),
+            public_key,
+        })
+    }
+}
+
+/// A ureq Resolver that only resolves hosts to public addresses.
+///
+/// Anyone can make us fetch a URL, by sending a signed request with it as the keyId. Without this, they could
+/// make us request services on our own network, or a cloud provider's metadata endpoint. (SSRF)
+/// Checking here, as we connect, also covers redirects and DNS names that resolve to internal addresses.
+fn public_addresses(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.filter(|addr| is_public(addr.ip())).collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} does not resolve to a public address", netloc),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Whether an address is (probably) on the public internet.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(
+                ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0 // "this network"
+                || (a == 100 && (64..128).contains(&b)) // Carrier-grade NAT
+                || (a == 198 && (18..20).contains(&b)) // Benchmarking
+                || a >= 240 // Reserved, and broadcast
+            )
+        },
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(
+                ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // Unique local
+                || (first & 0xffc0) == 0xfe80 // Link-local
+            )
+        },
+    }
+}
+
+/// The value of a `Digest` header for a body.
+fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", BASE64.encode(Sha256::digest(body)))
+}
+
+/// Returns `Date` and `Signature` headers for a request.
+fn sign_request(
+    key: &RsaPrivateKey,
+    key_id: &str,
+    method: &str,
+    url: &Url,
+    digest: Option<&str>,
+) -> Result<(String, String), anyhow::Error> {
+    let date = HttpDate::from(SystemTime::now()).to_string();
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let mut headers = vec!["(request-target)", "host", "date"];
+    let mut lines = vec![
+        format!("(request-target): {} {}", method, target),
+        format!("host: {}", host(url)),
+        format!("date: {}", date),
+    ];
+    if let Some(digest) = digest {
+        headers.push("digest");
+        lines.push(format!("digest: {}", digest));
+    }
+
+    let signer = SigningKey::<Sha256>::new(key.clone());
+    let signature = signer.try_sign(lines.join("\n").as_bytes())?;
+    let header = format!(
+        r#"keyId="{}",algorithm="rsa-sha256",headers="{}",signature="{}""#,
+        key_id,
+        headers.join(" "),
+        BASE64.encode(signature.to_bytes()),
+    );
+    Ok((date, header))
+}
+
+/// The parts of an incoming request that its HTTP Signature covers.
+struct SignedRequest {
+    key_id: String,
+    signing_string: String,
+    signature: Vec<u8>,
+}
+
+impl SignedRequest {
+    /// Checks everything but the signature itself, which requires fetching the sender's key.
+    fn from_request(req: &HttpRequest, body: &[u8]) -> Result<Self, anyhow::Error> {
+        let params = parse_signature_header(header_str(req, "signature")?);
+        let param = |name: &str| params.get(name).context(format!("Signature is missing {}", name));
+
+        if let Some(algorithm) = params.get("algorithm") {
+            if algorithm != "rsa-sha256" && algorithm != "hs2019" {
+                bail!("Unsupported signature algorithm: {}", algorithm);
+            }
+        }
+
+        let headers: Vec<&str> = params.get("headers")
+            .map(|headers| headers.as_str())
+            .unwrap_or("date")
+            .split_whitespace()
+            .collect();
+        for required in ["(request-target)", "host", "date", "digest"] {
+            if !headers.contains(&required) {
+                bail!("Signature must cover {}", required);
+            }
+        }
+
+        let date: SystemTime = HttpDate::from_str(header_str(req, "date")?)?.into();
+        let skew = match SystemTime::now().duration_since(date) {
+            Ok(skew) => skew,
+            Err(err) => err.duration(),
+        };
+        if skew > MAX_CLOCK_SKEW {
+            bail!("Date is too far from the current time");
+        }
+
+        let expected = digest(body);
+        let digest_ok = header_str(req, "digest")?
+            .split(',')
+            .any(|value| value.trim() == expected);
+        if !digest_ok {
+            bail!("Digest does not match the request body");
+        }
+
+        let mut lines = vec![];
+        for name in headers {
+            if name == "(request-target)" {
+                let target = req.uri().path_and_query().map(|it| it.as_str()).unwrap_or(req.path());
+                lines.push(format!("(request-target): {} {}", req.method().as_str().to_lowercase(), target));
+            } else {
+                lines.push(format!("{}: {}", name, header_str(req, name)?));
+            }
+        }
+
+        Ok(Self {
+            key_id: param("keyId")?.clone(),
+            signing_string: lines.join("\n"),
+            signature: BASE64.decode(param("signature")?)?,
+        })
+    }
+
+    fn verify(&self, key: &RsaPublicKey) -> Result<(), anyhow::Error> {
+        let verifier = VerifyingKey::<Sha256>::new(key.clone());
+        let signature = rsa::pkcs1v15::Signature::try_from(self.signature.as_slice())?;
+        verifier.verify(self.signing_string.as_bytes(), &signature)?;
+        Ok(())
+    }
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Result<&'a str, anyhow::Error> {
+    let value = req.headers().get(name).context(format!("Missing {} header", name))?;
+    Ok(value.to_str()?)
+}
+
+/// Parse `key="value",...` pairs from a `Signature` header.
+fn parse_signature_header(header: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    for part in header.split(',') {
+        if let Some((key, value)) = part.trim().split_once('=') {
+            params.insert(key.trim().to_string(), value.trim().trim_matches('"').to_string());
+        }
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{App, HttpServer, test};
+
+    use crate::server::{config::RateLimitsConfig, rate_limit::RateLimits, test_util::{self, TestDb, sign_item}};
+    use super::*;
+
+    const BASE: &str = "https://diskuto.example/";
+
+    type Inbox = Arc<Mutex<Vec<(SignedRequest, Value)>>>;
+
+    /// Follow a server user from a stub ActivityPub server, and check that new Posts get delivered to it.
+    #[actix_web::test]
+    async fn follow_and_deliver() {
+        let db = TestDb::new();
+        let user = db.new_user();
+        let user_id = user.0.clone();
+
+        // A stub remote server with one actor:
+        let stub_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, RSA_BITS).unwrap();
+        let received: Inbox = Default::default();
+        let stub_inbox = received.clone();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stub = format!("http://{}", listener.local_addr().unwrap());
+        let stub_actor = with_context(json!({
+            "id": format!("{}/actor", stub),
+            "type": "Person",
+            "inbox": format!("{}/inbox", stub),
+            "publicKey": {
+                "id": format!("{}/actor#main-key", stub),
+                "owner": format!("{}/actor", stub),
+                "publicKeyPem": RsaPublicKey::from(&stub_key).to_public_key_pem(LineEnding::LF).unwrap(),
+            },
+        }));
+        let actor_json = stub_actor.clone();
+        let server = HttpServer::new(move || {
+            let actor_json = actor_json.clone();
+            let stub_inbox = stub_inbox.clone();
+            App::new()
+                .route("/actor", get().to(move || {
+                    let actor_json = actor_json.clone();
+                    async move { activity_ok(&actor_json) }
+                }))
+                .route("/inbox", post().to(move |req: HttpRequest, body: Bytes| {
+                    let stub_inbox = stub_inbox.clone();
+                    async move {
+                        let signed = SignedRequest::from_request(&req, &body).unwrap();
+                        let activity = serde_json::from_slice(&body).unwrap();
+                        stub_inbox.lock().unwrap().push((signed, activity));
+                        HttpResponse::Accepted().finish()
+                    }
+                }))
+        }).workers(1).listen(listener).unwrap().run();
+        actix_web::rt::spawn(server);
+
+        let app = test::init_service(
+            App::new()
+            .app_data(Data::new(AppData{
+                activitypub_url: Some(Url::parse(BASE).unwrap()),
+                ..db.app_data()
+            }))
+            .configure(super::super::api_routes)
+            .configure(routes)
+        ).await;
+        let urls = Urls::new(&Url::parse(BASE).unwrap(), &user_id);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/.well-known/webfinger?resource=acct:{}@diskuto.example", user_id))
+            .to_request();
+        let jrd: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(jrd["links"][0]["href"], json!(urls.actor()));
+
+        let req = test::TestRequest::get().uri(&format!("/diskuto/users/{}/activitypub", user_id)).to_request();
+        let actor: Value = test::call_and_read_body_json(&app, req).await;
+        let our_key = RsaPublicKey::from_public_key_pem(actor["publicKey"]["publicKeyPem"].as_str().unwrap()).unwrap();
+
+        // Follow, signed by the stub actor:
+        let follow = json!({
+            "id": format!("{}/follows/1", stub),
+            "type": "Follow",
+            "actor": stub_actor["id"],
+            "object": urls.actor(),
+        });
+        let body = follow.to_string();
+        let digest = digest(body.as_bytes());
+        let (date, signature) = sign_request(
+            &stub_key,
+            stub_actor["publicKey"]["id"].as_str().unwrap(),
+            "post",
+            &Url::parse(&urls.inbox()).unwrap(),
+            Some(&digest),
+        ).unwrap();
+        let follow_request = || test::TestRequest::post()
+            .uri(&format!("/diskuto/users/{}/activitypub/inbox", user_id))
+            .insert_header(("host", "diskuto.example"))
+            .insert_header(("date", date.clone()))
+            .insert_header(("digest", digest.clone()))
+            .insert_header(("signature", signature.clone()))
+            .set_payload(body.clone());
+
+        // Tampering with the body invalidates the signature:
+        let req = follow_request().set_payload(body.replace("Follow", "Fallow")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+        let req = follow_request().to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 202);
+
+        let (signed, accept) = next_activity(&received).await;
+        signed.verify(&our_key).unwrap();
+        assert_eq!(accept["type"], json!("Accept"));
+        assert_eq!(accept["object"]["id"], follow["id"]);
+
+        // New Posts are delivered:
+        let (row, item_signature) = sign_item(&user, &test_util::post(Timestamp::now().unix_utc_ms, "Hello, *fediverse*!"));
+        let bytes = row.item_bytes;
+        let req = test::TestRequest::put()
+            .uri(&format!("/diskuto/users/{}/items/{}", user_id, item_signature.to_base58()))
+            .insert_header(("content-length", bytes.len()))
+            .set_payload(bytes)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+        let (signed, create) = next_activity(&received).await;
+        signed.verify(&our_key).unwrap();
+        assert_eq!(create["type"], json!("Create"));
+        assert_eq!(create["object"]["id"], json!(urls.note(&item_signature)));
+        assert!(create["object"]["content"].as_str().unwrap().contains("<em>fediverse</em>"));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/diskuto/users/{}/activitypub/outbox?page=true", user_id))
+            .to_request();
+        let page: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(page["orderedItems"][0]["object"], create["object"]);
+
+        // Undoing something else (ex: a Like) doesn't unfollow:
+        let key_id = stub_actor["publicKey"]["id"].as_str().unwrap();
+        let followers = || {
+            let mut actors = vec![];
+            db.factory.open().unwrap().activitypub_followers(&user_id, &mut |follower| {
+                actors.push(follower.actor);
+                Ok(true)
+            }).unwrap();
+            actors
+        };
+        let undo = |object: Value| json!({
+            "id": format!("{}/undos/1", stub),
+            "type": "Undo",
+            "actor": stub_actor["id"],
+            "object": object,
+        });
+        let req = inbox_request(&user_id, &stub_key, key_id, &undo(json!(format!("{}/likes/1", stub)))).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 202);
+        assert_eq!(vec![stub_actor["id"].as_str().unwrap().to_string()], followers());
+
+        // Many servers Undo a Follow by its ID, rather than embedding it:
+        let req = inbox_request(&user_id, &stub_key, key_id, &undo(follow["id"].clone())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 202);
+        assert_eq!(Vec::<String>::new(), followers());
+    }
+
+    /// A POST of an activity to a user's inbox, signed with `key`.
+    fn inbox_request(user_id: &UserID, key: &RsaPrivateKey, key_id: &str, activity: &Value) -> test::TestRequest {
+        let urls = Urls::new(&Url::parse(BASE).unwrap(), user_id);
+        let body = activity.to_string();
+        let digest = digest(body.as_bytes());
+        let (date, signature) = sign_request(key, key_id, "post", &Url::parse(&urls.inbox()).unwrap(), Some(&digest)).unwrap();
+        test::TestRequest::post()
+            .uri(&format!("/diskuto/users/{}/activitypub/inbox", user_id))
+            .insert_header(("host", "diskuto.example"))
+            .insert_header(("date", date))
+            .insert_header(("digest", digest))
+            .insert_header(("signature", signature))
+            .set_payload(body)
+    }
+
+    #[actix_web::test]
+    async fn inbox_rate_limit() {
+        let db = TestDb::new();
+        let (user_id, _) = db.new_user();
+        let limits = RateLimitsConfig{ inbox_posts_per_minute: 2, ..Default::default() };
+        let app = test::init_service(
+            App::new()
+            .app_data(Data::new(AppData{
+                activitypub_url: Some(Url::parse(BASE).unwrap()),
+                rate_limits: Arc::new(RateLimits::new(&limits)),
+                ..db.app_data()
+            }))
+            .configure(routes)
+        ).await;
+
+        // Even unsigned requests count, since we have to look at them:
+        let post = |ip: &str| test::TestRequest::post()
+            .uri(&format!("/diskuto/users/{}/activitypub/inbox", user_id))
+            .peer_addr(format!("{}:1234", ip).parse().unwrap())
+            .set_payload("{}")
+            .to_request();
+        assert_eq!(test::call_service(&app, post("10.0.0.1")).await.status(), 401);
+        assert_eq!(test::call_service(&app, post("10.0.0.1")).await.status(), 401);
+        assert_eq!(test::call_service(&app, post("10.0.0.1")).await.status(), 429);
+        assert_eq!(test::call_service(&app, post("10.0.0.2")).await.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn public_addresses_only() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+
+        assert!(public_addresses("127.0.0.1:443").is_err());
+        assert!(public_addresses("localhost:443").is_err());
+        assert!(public_addresses("[::1]:443").is_err());
+        assert_eq!(public_addresses("1.1.1.1:443").unwrap(), vec!["1.1.1.1:443".parse().unwrap()]);
+    }
+
+    /// Wait for the next activity delivered to the stub inbox.
+    async fn next_activity(inbox: &Inbox) -> (SignedRequest, Value) {
+        for _ in 0..100 {
+            {
+                let mut received = inbox.lock().unwrap();
+                if !received.is_empty() {
+                    return received.remove(0);
+                }
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Timed out waiting for an activity");
+    }
+}
@@ -4,47 +4,92 @@
 //! And, I suppose they could also be considered part of the REST API.
 
 
-use std::io::{Seek, SeekFrom};
+use std::{io::{Seek, SeekFrom}, time::Duration};
 
-use actix_web::{HttpRequest, HttpResponse, web::{Data, Path, Payload}};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, http::header::{self, ByteRangeSpec, Charset, ContentDisposition, DispositionParam, DispositionType, EntityTag, ExtendedValue, Header, IfRange, Range}, web::{Bytes, Data, Path, Payload, Query}};
 use anyhow::Context;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use futures::{AsyncWriteExt, StreamExt};
-use mime_guess::mime;
-use sodiumoxide::crypto::hash::sha512;
+use protobuf::Message;
+use sodiumoxide::{crypto::hash::sha512, randombytes::randombytes};
 use tempfile::tempfile;
-use log::debug;
+use log::{debug, warn};
+use mime_guess::{Mime, mime};
+use serde::Deserialize;
 
-use crate::{backend::{SHA512, Signature, UserID}, server::html::not_found};
+use crate::{backend::{AttachmentUpload, Factory, FileMeta, PartialUpload, SHA512, Signature, Timestamp, UserID}, protos::Item, server::html::not_found};
 
-use super::{AppData, Error, PLAINTEXT};
+use super::{AppData, Error, PLAINTEXT, etag_matches, http_not_modified, thumbnails, webhooks};
+
+#[derive(Deserialize)]
+pub(crate) struct FileQuery {
+    /// Get a thumbnail, at most this many pixels wide/tall.
+    thumb: Option<u32>,
+}
 
 pub(crate) async fn get_file(
     data: Data<AppData>,
     path: Path<(UserID, Signature, String)>,
+    Query(query): Query<FileQuery>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature, file_name) = path.into_inner();
     let backend = data.backend_factory.open()?;
 
-    let contents = backend.get_contents(user_id, signature, file_name.as_str())?;
+    let meta = match backend.get_attachment_meta(&user_id, &signature, &file_name)? {
+        Some(meta) if meta.exists => meta,
+        _ => return not_found().await,
+    };
+
+    if let Some(size) = query.thumb {
+        return thumbnails::get_thumbnail(data, &req, user_id, signature, &file_name, meta, size).await;
+    }
+
+    let etag = file_etag(&meta.hash);
+
+    // immutable_file() would also catch this, but this way we skip opening the file:
+    if etag_matches(&req, &etag) {
+        let mut response = http_not_modified();
+        response.headers_mut().insert(header::ETAG, etag.to_string().parse().expect("valid ETag"));
+        return Ok(response);
+    }
+
+    // The requested range, and the full file size:
+    let mut partial = None;
+    if let Some(spec) = requested_range(&req, &etag) {
+        match spec.to_satisfiable_range(meta.size) {
+            Some((first, last)) => partial = Some((first..last + 1, meta.size)),
+            None => return Ok(
+                HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", meta.size)))
+                .content_type(PLAINTEXT)
+                .body(format!("File is only {} bytes", meta.size))
+            ),
+        }
+    }
+
+    let range = partial.as_ref().map(|(range, _)| range.clone());
+    let contents = backend.get_contents(user_id, signature, file_name.as_str(), range)?;
     let contents = match contents {
         None => return not_found().await,
         Some(c) => c,
     };
 
-    let mut mime_type = mime_guess::from_path(&file_name).first_or_octet_stream();
+    data.metrics.attachment_bytes_out(contents.size);
 
-    // Diskuto is not meant to be a general web server.
-    // Plus, since the client also runs in the browser, any mime type that can run JavaScript
-    // could exfiltrate private keys.
-    // Javascript, obviously. But HTML and SVG(!!!) can embed JavaScript.
-    if !safe_type(&mime_type) {
-        mime_type = mime::APPLICATION_OCTET_STREAM;
-    }
-
-    let mime_string = mime_type.to_string();
-    let response = HttpResponse::Ok()
-        .content_type(mime_string)
+    let mut response = match &partial {
+        None => HttpResponse::Ok(),
+        Some((range, file_size)) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end - 1, file_size),
+            ));
+            response
+        },
+    };
 
+    let response = file_headers(&mut response, &data, &file_name, &meta)
         // no_chunking() sets the content-length, so this is redundant:
         // .set_header(CONTENT_LENGTH, contents.size)
         .no_chunking(contents.size)
@@ -56,28 +101,123 @@ pub(crate) async fn get_file(
     Ok(response)
 }
 
-// An allow-list for types we know can't embed JavaScript:
-fn safe_type(mime_type: &mime_guess::Mime) -> bool {
-    return match (mime_type.type_().as_str(), mime_type.subtype().as_str()) {
-        ("text", "plain") => true,
-        ("image", "gif") => true,
-        ("image", "jpeg") => true,
-        ("image", "png") => true,
-        ("audio", "mpeg") => true,
-        ("audio", "ogg") => true,
-        
-        // NO: javascript, HTML, SVG, others.
-        // TODO: Consider a content-security-policy here to allow SVG?
-        _ => false,
+/// Attachments are content-addressed by their hash, so it makes a fine ETag.
+fn file_etag(hash: &SHA512) -> EntityTag {
+    EntityTag::new_strong(bs58::encode(hash.bytes()).into_string())
+}
+
+/// Headers that describe the whole file, for both GET and HEAD.
+fn file_headers<'a>(
+    response: &'a mut HttpResponseBuilder,
+    data: &AppData,
+    file_name: &str,
+    meta: &FileMeta,
+) -> &'a mut HttpResponseBuilder {
+    let mime_type = data.config.attachments.mime_type(file_name);
+    security_headers(response, &mime_type, file_name)
+        .content_type(mime_type.to_string())
+        .insert_header(header::ETag(file_etag(&meta.hash)))
+        // See: RFC 9530. Lets clients verify downloads, or skip files they already have.
+        .insert_header(("Repr-Digest", format!("sha-512=:{}:", BASE64.encode(meta.hash.bytes()))))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+}
+
+/// How browsers may show an attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handling {
+    /// Media that browsers just display. (ex: images, audio, video)
+    Inline,
+
+    /// Displayed, but in a sandbox with no scripts and no network access. (ex: SVG)
+    Sandboxed,
+
+    /// Only ever downloaded, and sandboxed in case it's opened anyway. (ex: PDF)
+    Download,
+}
+
+impl Handling {
+    fn of(mime_type: &Mime) -> Self {
+        match (mime_type.type_(), mime_type.subtype()) {
+            (mime::IMAGE, mime::SVG) => Self::Sandboxed,
+            (mime::IMAGE, _) | (mime::AUDIO, _) | (mime::VIDEO, _) => Self::Inline,
+            (mime::TEXT, mime::PLAIN) => Self::Inline,
+            // PDF viewers can run scripts, and don't all work in a sandbox, so:
+            _ => Self::Download,
+        }
     }
+}
+
+/// Attachments share an origin with the web client, which holds users' private keys.
+/// So anything that might run scripts gets a CSP sandbox, which gives it a unique origin.
+const SANDBOX_CSP: &str = "sandbox; default-src 'none'; style-src 'unsafe-inline'";
 
+/// Keep browsers from treating attachments as anything other than `mime_type`.
+fn security_headers<'a>(
+    response: &'a mut HttpResponseBuilder,
+    mime_type: &Mime,
+    file_name: &str,
+) -> &'a mut HttpResponseBuilder {
+    // Never guess at types. (ex: an "image" that's really HTML)
+    response.insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+
+    match Handling::of(mime_type) {
+        Handling::Inline => {},
+        Handling::Sandboxed => {
+            response.insert_header((header::CONTENT_SECURITY_POLICY, SANDBOX_CSP));
+        },
+        Handling::Download => {
+            response.insert_header((header::CONTENT_SECURITY_POLICY, SANDBOX_CSP));
+            response.insert_header(download_disposition(file_name));
+        },
+    }
+    response
+}
+
+fn download_disposition(file_name: &str) -> ContentDisposition {
+    let name = if file_name.is_ascii() {
+        DispositionParam::Filename(file_name.into())
+    } else {
+        DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".into()),
+            language_tag: None,
+            value: file_name.as_bytes().to_vec(),
+        })
+    };
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![name],
+    }
+}
+
+/// The byte range that the client asked for, if any.
+///
+/// Multiple ranges aren't supported, so clients asking for those get the whole file,
+/// as do clients whose If-Range doesn't match.
+fn requested_range(req: &HttpRequest, etag: &EntityTag) -> Option<ByteRangeSpec> {
+    let mut ranges = match Range::parse(req) {
+        Ok(Range::Bytes(ranges)) if ranges.len() == 1 => ranges,
+        _ => return None,
+    };
+
+    if req.headers().contains_key(header::IF_RANGE) {
+        let matches = match IfRange::parse(req) {
+            Ok(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+            // We don't send Last-Modified, so can't compare dates.
+            Ok(IfRange::Date(_)) | Err(_) => false,
+        };
+        if !matches {
+            return None;
+        }
+    }
+
+    ranges.pop()
 }
 
 pub(crate) async fn put_file(
     data: Data<AppData>,
     path: Path<(UserID, Signature, String)>,
     req: HttpRequest,
-    mut body: Payload,
+    body: Payload,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature, file_name) = path.into_inner();
     let backend = data.backend_factory.open()?;
@@ -143,9 +283,133 @@ pub(crate) async fn put_file(
         ); 
     }
 
-    // Collect the file bytes into a temp file so that we're not using the backend while we wait for the upload:
+    let backend = data.backend_factory.open()?;
+    let upload = backend.start_attachment(size)?;
+    // (Back to the pool. We take connections only as needed while receiving.)
+    drop(backend);
+
+    debug!("Receiving and hashing file: {}", &file_name);
+    let rejected = match upload {
+        Some(upload) => receive_to_store(&data, upload, &metadata, body).await?,
+        None => receive_to_temp_file(&data, &metadata, body).await?,
+    };
+    if let Some(response) = rejected {
+        return Ok(response);
+    }
+
+    blocking::unblock(move || {
+        notify_file_saved(&data, &user_id, &signature, &file_name, &metadata)
+    }).await?;
+
+    Ok(
+        HttpResponse::Created()
+        .body("")
+    )
+}
+
+/// Stream an upload straight into the backend's content store, hashing as we go.
+///
+/// Returns a response if the upload was rejected, in which case it is discarded.
+async fn receive_to_store(data: &AppData, upload: AttachmentUpload, metadata: &FileMeta, body: Payload) -> Result<Option<HttpResponse>, Error> {
+    let upload = UploadGuard{ factory: data.backend_factory.dyn_clone(), upload: Some(upload) };
+    let (written, hash) = write_to_store(&upload, body).await?;
+
+    data.metrics.attachment_bytes_in(written);
+
+    if let Some(response) = check_upload(metadata, written, &hash) {
+        upload.abort().await?;
+        return Ok(Some(response));
+    }
+    debug!("Received correct hash: {}", &hash);
+
+    upload.finish(hash).await?;
+    Ok(None)
+}
+
+/// Returns the number of bytes received, and their hash.
+///
+/// Stops writing (but keeps counting) if we receive more than `upload.size` bytes.
+async fn write_to_store(upload: &UploadGuard, mut body: Payload) -> Result<(u64, SHA512), Error> {
+    let size = upload.size();
+    let mut buf = Vec::with_capacity(DB_WRITE_BYTES);
+    let mut written: u64 = 0;
+    let mut hasher = sha512::State::new();
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Error parsing chunk")?;
+
+        written += chunk.len() as u64;
+        hasher.update(&chunk);
+        if written > size { break; }
+
+        buf.extend_from_slice(&chunk);
+        if buf.len() >= DB_WRITE_BYTES {
+            let bytes = std::mem::replace(&mut buf, Vec::with_capacity(DB_WRITE_BYTES));
+            upload.write(written - bytes.len() as u64, bytes).await?;
+        }
+    }
+
+    if !buf.is_empty() && written <= size {
+        upload.write(written - buf.len() as u64, buf).await?;
+    }
+
+    Ok((written, SHA512::from_digest(hasher.finalize())))
+}
+
+/// An upload being streamed into the content store, which is aborted if it's dropped unfinished.
+/// (ex: When a client disconnects, actix drops put_file() mid-upload.)
+///
+/// Backend calls run via `blocking`, so that SQLite I/O doesn't tie up actix's worker thread.
+struct UploadGuard {
+    factory: Box<dyn Factory>,
+    upload: Option<AttachmentUpload>,
+}
+
+impl UploadGuard {
+    fn size(&self) -> u64 {
+        self.upload.as_ref().map(|upload| upload.size).unwrap_or(0)
+    }
+
+    async fn write(&self, offset: u64, bytes: Vec<u8>) -> Result<(), Error> {
+        let factory = self.factory.dyn_clone();
+        let upload = self.upload.clone().context("Upload already finished")?;
+        blocking::unblock(move || factory.open()?.write_attachment(&upload, offset, &bytes)).await?;
+        Ok(())
+    }
+
+    async fn finish(mut self, hash: SHA512) -> Result<(), Error> {
+        let factory = self.factory.dyn_clone();
+        let upload = self.upload.take().context("Upload already finished")?;
+        blocking::unblock(move || factory.open()?.finish_attachment(upload, &hash)).await?;
+        Ok(())
+    }
+
+    async fn abort(mut self) -> Result<(), Error> {
+        let factory = self.factory.dyn_clone();
+        let upload = self.upload.take().context("Upload already finished")?;
+        blocking::unblock(move || factory.open()?.abort_attachment(upload)).await?;
+        Ok(())
+    }
+}
 
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            let factory = self.factory.dyn_clone();
+            blocking::unblock(move || {
+                if let Err(err) = factory.open().and_then(|backend| backend.abort_attachment(upload)) {
+                    warn!("Error discarding unfinished upload: {:?}", err);
+                }
+            }).detach();
+        }
+    }
+}
 
+/// Collect the file bytes into a temp file so that we're not using the backend while we wait for the upload,
+/// then copy it into the backend with save_attachment().
+///
+/// For backends that can't start_attachment(). Returns a response if the upload was rejected.
+async fn receive_to_temp_file(data: &Data<AppData>, metadata: &FileMeta, mut body: Payload) -> Result<Option<HttpResponse>, Error> {
     let file = tempfile().context("Error opening temp file")?;
 
     // Unblock's default buffer for I/O is *8MiB*!?  32k at a time seems fine.
@@ -154,31 +418,58 @@ pub(crate) async fn put_file(
     let mut written: u64 = 0;
     let mut hasher = sha512::State::new();
 
-    debug!("Receiving and hashing file: {}", &file_name);
-    
     while let Some(chunk) = body.next().await {
         let chunk = chunk.context("Error parsing chunk")?;
 
         file.write_all(&chunk).await?;
         written += chunk.len() as u64;
         hasher.update(&chunk);
-        if written > size { break; }
+        if written > metadata.size { break; }
     }
 
-    if written != size {
-        return Ok(
+    data.metrics.attachment_bytes_in(written);
+
+    let hash = SHA512::from_digest(hasher.finalize());
+    if let Some(response) = check_upload(metadata, written, &hash) {
+        return Ok(Some(response));
+    }
+    debug!("Received correct hash: {}", &hash);
+
+    // Got here, file upload is good.
+    // Copy the file into the appropriate BLOB.
+    // Need to start from the beginning:
+    file.flush().await?;
+
+    // Just grab the inner file to simplify types for the Backend:
+    let mut file = file.into_inner().await;
+
+    let data = data.clone();
+    let (size, hash) = (metadata.size, metadata.hash.clone());
+    blocking::unblock(move || -> Result<(), anyhow::Error> {
+        file.seek(SeekFrom::Start(0))?;
+        let backend = data.backend_factory.open()?;
+        backend.save_attachment(size, &hash, &mut file)?;
+        Ok(())
+    }).await?;
+
+    Ok(None)
+}
+
+/// A response rejecting an upload, if its size or hash doesn't match the attachment's.
+fn check_upload(metadata: &FileMeta, written: u64, hash: &SHA512) -> Option<HttpResponse> {
+    if written != metadata.size {
+        return Some(
             HttpResponse::BadRequest()
             .body(format!(
                 "Expected {} bytes but received {}",
-                size,
+                metadata.size,
                 written
             ))
         );
     }
 
-    let hash = SHA512::from_digest(hasher.finalize());
-    if hash != metadata.hash {
-        return Ok(
+    if hash != &metadata.hash {
+        return Some(
             HttpResponse::BadRequest()
             .content_type(PLAINTEXT)
             .body(format!(
@@ -186,29 +477,248 @@ pub(crate) async fn put_file(
             ))
         );
     }
-    debug!("Received correct hash: {}", &hash);
 
-    // Got here, file upload is good.
-    // Copy the file into the appropriate BLOB.
-    // Need to start from the beginning:
-    file.flush().await?;
+    None
+}
 
-    // Just grab the inner file to simplify types for the Backend:
-    let mut file = file.into_inner().await;
+/// Tell webhooks about a newly-saved file.
+fn notify_file_saved(data: &AppData, user_id: &UserID, signature: &Signature, file_name: &str, metadata: &FileMeta) -> Result<(), anyhow::Error> {
+    let backend = data.backend_factory.open()?;
+    if let Some(row) = backend.user_item(user_id, signature)? {
+        drop(backend);
+        let mut item = Item::new();
+        item.merge_from_bytes(&row.item_bytes)?;
+        webhooks::file_saved(data, &row, &item, file_name, metadata);
+    }
+    Ok(())
+}
 
-    blocking::unblock(move || -> Result<(), anyhow::Error> {
-        file.seek(SeekFrom::Start(0))?;
+/// Chunks of a resumable upload must have this Content-Type. (As in tus.)
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+/// Identifies a resumable upload to the client that started it.
+const UPLOAD_TOKEN: &str = "Upload-Token";
+
+/// Write uploads to the database this many bytes at a time.
+/// For resumable uploads, a dropped connection loses at most this much.
+const DB_WRITE_BYTES: usize = 1024 * 1024;
+
+/// Upload part of a file, which can be resumed if the connection drops.
+///
+/// Modeled on the core of the tus protocol (https://tus.io/protocols/resumable-upload),
+/// but the upload URL is just the file's URL:
+///  * The first `PATCH` (at `Upload-Offset: 0`) starts an upload, and responds with its `Upload-Token`.
+///  * Later `PATCH`es send that `Upload-Token`, and continue from where the upload left off.
+///    (`HEAD` the file with the token to get its `Upload-Offset`.)
+///  * Once all bytes have arrived, we verify the file's hash and save it.
+///
+/// Only the client that started an upload can add to it, so nobody else can corrupt it.
+pub(crate) async fn patch_file(
+    data: Data<AppData>,
+    path: Path<(UserID, Signature, String)>,
+    req: HttpRequest,
+    mut body: Payload,
+) -> Result<HttpResponse, Error> {
+    let (user_id, signature, file_name) = path.into_inner();
+    let backend = data.backend_factory.open()?;
+
+    let metadata = match backend.get_attachment_meta(&user_id, &signature, &file_name)? {
+        Some(metadata) => metadata,
+        None => return Ok(
+            HttpResponse::Forbidden()
+            .content_type(PLAINTEXT)
+            .body("No such attachment for this Item, or no such Item.")
+        ),
+    };
+
+    if metadata.exists {
+        drain(body).await;
+        return Ok(
+            HttpResponse::Accepted()
+            .content_type(PLAINTEXT)
+            .body("Attachment already exists")
+        );
+    }
+
+    if metadata.quota_exceeded {
+        return Ok(
+            HttpResponse::InsufficientStorage()
+            .content_type(PLAINTEXT)
+            .body("Uploading this attachment would voilate the users's quota.")
+        );
+    }
+
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    if content_type != Some(OFFSET_OCTET_STREAM) {
+        return Ok(
+            HttpResponse::UnsupportedMediaType()
+            .content_type(PLAINTEXT)
+            .body(format!("Content-Type must be {}", OFFSET_OCTET_STREAM))
+        );
+    }
+
+    let offset = req.headers().get("upload-offset")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let mut offset = match offset {
+        Some(offset) => offset,
+        None => return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body("Must include an Upload-Offset header.")
+        ),
+    };
+
+    let token = req.headers().get(UPLOAD_TOKEN).and_then(|value| value.to_str().ok());
+    let upload = match token {
+        Some(token) => {
+            let upload = PartialUpload{ user_id, signature, file_name, token: token.to_string() };
+            match backend.partial_upload_offset(&upload)? {
+                Some(current) if current == offset => upload,
+                Some(current) => return Ok(upload_conflict(current)),
+                None => return Ok(
+                    HttpResponse::NotFound()
+                    .content_type(PLAINTEXT)
+                    .body("No such upload. (It may have expired.) Start a new one without an Upload-Token.")
+                ),
+            }
+        },
+        None => {
+            if offset != 0 {
+                return Ok(upload_conflict(0));
+            }
+            let token = bs58::encode(randombytes(24)).into_string();
+            let upload = PartialUpload{ user_id, signature, file_name, token };
+            backend.start_partial_upload(&upload)?;
+            upload
+        },
+    };
+    // (Back to the pool. We take connections only as needed while receiving.)
+    drop(backend);
+
+    let mut buf = Vec::with_capacity(DB_WRITE_BYTES);
+    let mut received: u64 = 0;
+    loop {
+        let chunk = body.next().await;
+        let done = chunk.is_none();
+
+        // Save what we have if we've got enough, we're done, or the connection dropped:
+        let chunk = match chunk {
+            Some(Ok(chunk)) => Some(chunk),
+            Some(Err(err)) => {
+                debug!("Resumable upload interrupted: {}", err);
+                None
+            },
+            None => None,
+        };
+        if let Some(chunk) = &chunk {
+            received += chunk.len() as u64;
+            if offset + buf.len() as u64 + chunk.len() as u64 > metadata.size {
+                data.metrics.attachment_bytes_in(received);
+                return Ok(
+                    HttpResponse::BadRequest()
+                    .content_type(PLAINTEXT)
+                    .insert_header((UPLOAD_TOKEN, upload.token.as_str()))
+                    .body(format!("File should be {} bytes", metadata.size))
+                );
+            }
+            buf.extend_from_slice(chunk);
+        }
+
+        if (chunk.is_none() || buf.len() >= DB_WRITE_BYTES) && !buf.is_empty() {
+            let bytes = std::mem::replace(&mut buf, Vec::with_capacity(DB_WRITE_BYTES));
+            let (data, upload, metadata) = (data.clone(), upload.clone(), metadata.clone());
+            let written = blocking::unblock(move || {
+                let backend = data.backend_factory.open()?;
+                backend.write_partial_upload(&upload, &metadata, offset, &bytes)
+            }).await?;
+            offset = match written {
+                Some(offset) => offset,
+                // The same upload is being continued by another request at the same time:
+                None => return Ok(upload_conflict(offset)),
+            };
+        }
+
+        if chunk.is_none() {
+            if !done {
+                return Ok(
+                    HttpResponse::BadRequest()
+                    .content_type(PLAINTEXT)
+                    .insert_header(("Upload-Offset", offset.to_string()))
+                    .insert_header((UPLOAD_TOKEN, upload.token.as_str()))
+                    .body("Error reading upload. Resume from Upload-Offset.")
+                );
+            }
+            break;
+        }
+    }
+    data.metrics.attachment_bytes_in(received);
+
+    if offset < metadata.size {
+        return Ok(
+            HttpResponse::NoContent()
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .insert_header((UPLOAD_TOKEN, upload.token.as_str()))
+            .finish()
+        );
+    }
+
+    debug!("Resumable upload complete. Checking hash: {}", &upload.file_name);
+    let saved = blocking::unblock(move || -> Result<bool, anyhow::Error> {
         let backend = data.backend_factory.open()?;
-        backend.save_attachment(metadata.size, &metadata.hash, &mut file)?;
-        Ok(())
+        if !backend.finish_partial_upload(&upload, &metadata)? {
+            return Ok(false);
+        }
+        drop(backend);
+        notify_file_saved(&data, &upload.user_id, &upload.signature, &upload.file_name, &metadata)?;
+        Ok(true)
     }).await?;
 
-    return Ok(
+    if !saved {
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body("Invalid data. The file's hash didn't match, so the upload was discarded.")
+        );
+    }
+
+    Ok(
         HttpResponse::Created()
-        .body("")
-    );
+        .insert_header(("Upload-Offset", offset.to_string()))
+        .finish()
+    )
 }
 
+/// Check for abandoned resumable uploads this often.
+const EXPIRE_PARTIAL_UPLOADS_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Periodically delete resumable uploads that haven't received data in `expiry_hours`.
+/// Must be run inside the actix System.
+pub(crate) fn expire_partial_uploads(factory: Box<dyn Factory>, expiry_hours: u64) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(EXPIRE_PARTIAL_UPLOADS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let factory = factory.dyn_clone();
+            let expired = blocking::unblock(move || {
+                let expiry_ms = expiry_hours as i64 * 60 * 60 * 1000;
+                factory.open()?.expire_partial_uploads(Timestamp{ unix_utc_ms: Timestamp::now().unix_utc_ms - expiry_ms })
+            }).await;
+            match expired {
+                Ok(0) => {},
+                Ok(count) => debug!("Deleted {} expired partial uploads", count),
+                Err(err) => warn!("Error deleting expired partial uploads: {:?}", err),
+            }
+        }
+    });
+}
+
+fn upload_conflict(current_offset: u64) -> HttpResponse {
+    HttpResponse::Conflict()
+        .content_type(PLAINTEXT)
+        .insert_header(("Upload-Offset", current_offset.to_string()))
+        .body(format!("Upload-Offset should be {}", current_offset))
+}
 
 /// If you don't wait to read all the Payload bytes, Actix-Web may close
 /// the connection before the client has sent them all. Then the client
@@ -228,6 +738,7 @@ pub(crate) async fn drain(mut payload: Payload) {
 pub(crate) async fn head_file(
     data: Data<AppData>,
     path: Path<(UserID, Signature, String)>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature, file_name) = path.into_inner();
     let backend = data.backend_factory.open()?;
@@ -246,10 +757,12 @@ pub(crate) async fn head_file(
     };
     
     if metadata.exists {
-        // I'd love to set a content-length here, but apparently Actix just won't let you for a HEAD?
+        // Actix won't send a body for a HEAD, but it still needs one to set the Content-Length.
+        // An empty stream with no_chunking() lets us report the real size.
         // See: https://github.com/actix/actix-web/issues/1439
-        // See workaround here: https://github.com/actix/examples/blob/master/forms/multipart-s3/src/main.rs#L67-L79
-        let response = HttpResponse::Ok().finish();
+        let response = file_headers(&mut HttpResponse::Ok(), &data, &file_name, &metadata)
+            .no_chunking(metadata.size)
+            .streaming(futures::stream::empty::<Result<Bytes, std::io::Error>>());
         return Ok(response);
     }
 
@@ -259,7 +772,387 @@ pub(crate) async fn head_file(
         return Ok(response)
     }
 
-    let response = HttpResponse::NotFound().finish();
+    let mut response = HttpResponse::NotFound();
+    response
+        .insert_header(("Upload-Length", metadata.size.to_string()))
+        .insert_header((header::CACHE_CONTROL, "no-store"));
 
-    Ok(response)
-}
\ No newline at end of file
+    // Where to resume an upload from. See: patch_file()
+    if let Some(token) = req.headers().get(UPLOAD_TOKEN).and_then(|value| value.to_str().ok()) {
+        let upload = PartialUpload{ user_id, signature, file_name, token: token.to_string() };
+        if let Some(offset) = backend.partial_upload_offset(&upload)? {
+            response.insert_header(("Upload-Offset", offset.to_string()));
+        }
+    }
+
+    Ok(response.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::{BoxedPayloadStream, error::PayloadError};
+    use actix_web::{App, FromRequest, http::{Method, header::HeaderMap}, test::{TestRequest, call_service, init_service, read_body}};
+
+    use crate::server::config::{AttachmentsConfig, Config};
+    use crate::server::test_util::{TestDb, post_with_files};
+
+    use super::*;
+
+    const CONTENTS: &[u8] = b"0123456789";
+
+    fn etag() -> EntityTag {
+        file_etag(&SHA512::from_digest(sha512::hash(CONTENTS)))
+    }
+
+    /// A server with an attachment that hasn't been uploaded yet, and its URL.
+    fn without_file() -> (TestDb, String) {
+        let db = TestDb::new();
+        let user = db.new_user();
+        let signature = db.save(&user, &post_with_files(100, &[("digits.txt", CONTENTS)]));
+        let url = format!("/diskuto/users/{}/items/{}/files/digits.txt", user.0, signature.to_base58());
+        (db, url)
+    }
+
+    /// A server with one attachment, and its URL.
+    fn with_file() -> (TestDb, String) {
+        let (db, url) = without_file();
+        db.save_file(CONTENTS);
+        (db, url)
+    }
+
+    fn patch_request(url: &str, offset: u64, token: Option<&str>, bytes: &[u8]) -> actix_http::Request {
+        let mut req = TestRequest::patch()
+            .uri(url)
+            .insert_header((header::CONTENT_TYPE, OFFSET_OCTET_STREAM))
+            .insert_header(("Upload-Offset", offset.to_string()));
+        if let Some(token) = token {
+            req = req.insert_header((UPLOAD_TOKEN, token));
+        }
+        req.set_payload(bytes.to_vec()).to_request()
+    }
+
+    fn put_request(url: &str, bytes: &[u8]) -> actix_http::Request {
+        // The Content-Length is right, even when the body isn't:
+        TestRequest::put()
+            .uri(url)
+            .insert_header((header::CONTENT_LENGTH, CONTENTS.len()))
+            .set_payload(bytes.to_vec())
+            .to_request()
+    }
+
+    async fn payload(bytes: &'static [u8]) -> Payload {
+        let (req, mut payload) = TestRequest::default().set_payload(bytes).to_http_parts();
+        Payload::from_request(&req, &mut payload).await.unwrap()
+    }
+
+    fn head_request(url: &str, token: Option<&str>) -> actix_http::Request {
+        let mut req = TestRequest::default().method(Method::HEAD).uri(url);
+        if let Some(token) = token {
+            req = req.insert_header((UPLOAD_TOKEN, token));
+        }
+        req.to_request()
+    }
+
+    #[actix_web::test]
+    async fn ranges() {
+        let (db, url) = with_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let get = |range: &str| TestRequest::get().uri(&url).insert_header((header::RANGE, range)).to_request();
+
+        let resp = call_service(&app, get("bytes=2-4")).await;
+        assert_eq!(206, resp.status());
+        assert_eq!("bytes 2-4/10", resp.headers().get(header::CONTENT_RANGE).unwrap());
+        assert_eq!("bytes", resp.headers().get(header::ACCEPT_RANGES).unwrap());
+        assert_eq!(&read_body(resp).await[..], b"234");
+
+        // Open-ended, and suffix ranges:
+        let resp = call_service(&app, get("bytes=7-")).await;
+        assert_eq!("bytes 7-9/10", resp.headers().get(header::CONTENT_RANGE).unwrap());
+        assert_eq!(&read_body(resp).await[..], b"789");
+        let resp = call_service(&app, get("bytes=-2")).await;
+        assert_eq!("bytes 8-9/10", resp.headers().get(header::CONTENT_RANGE).unwrap());
+        assert_eq!(&read_body(resp).await[..], b"89");
+
+        let resp = call_service(&app, get("bytes=10-")).await;
+        assert_eq!(416, resp.status());
+        assert_eq!("bytes */10", resp.headers().get(header::CONTENT_RANGE).unwrap());
+
+        // Multiple ranges aren't supported, so get the whole file:
+        let resp = call_service(&app, get("bytes=0-1,5-6")).await;
+        assert_eq!(200, resp.status());
+        assert_eq!(&read_body(resp).await[..], CONTENTS);
+    }
+
+    #[actix_web::test]
+    async fn if_range() {
+        let (db, url) = with_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let get = |if_range: &str| TestRequest::get()
+            .uri(&url)
+            .insert_header((header::RANGE, "bytes=0-0"))
+            .insert_header((header::IF_RANGE, if_range))
+            .to_request();
+
+        let resp = call_service(&app, get(&etag().to_string())).await;
+        assert_eq!(206, resp.status());
+        assert_eq!(&read_body(resp).await[..], b"0");
+
+        // A stale ETag gets the whole (new) file:
+        let resp = call_service(&app, get(r#""stale""#)).await;
+        assert_eq!(200, resp.status());
+        assert_eq!(&read_body(resp).await[..], CONTENTS);
+    }
+
+    #[actix_web::test]
+    async fn not_modified() {
+        let (db, url) = with_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let get = |if_none_match: &str| TestRequest::get()
+            .uri(&url)
+            .insert_header((header::IF_NONE_MATCH, if_none_match))
+            .to_request();
+
+        let resp = call_service(&app, get(&etag().to_string())).await;
+        assert_eq!(304, resp.status());
+        assert_eq!(etag().to_string(), resp.headers().get(header::ETAG).unwrap().to_str().unwrap());
+        assert!(resp.headers().get(header::CACHE_CONTROL).unwrap().to_str().unwrap().contains("immutable"));
+
+        // Some other file's ETag:
+        let other = file_etag(&SHA512::from_digest(sha512::hash(b"other")));
+        let resp = call_service(&app, get(&other.to_string())).await;
+        assert_eq!(200, resp.status());
+        assert_eq!(&read_body(resp).await[..], CONTENTS);
+    }
+
+    #[actix_web::test]
+    async fn head() {
+        let (db, url) = with_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+
+        let resp = call_service(&app, head_request(&url, None)).await;
+        assert_eq!(200, resp.status());
+        let headers = resp.headers();
+        assert_eq!("10", headers.get(header::CONTENT_LENGTH).unwrap());
+        assert_eq!(etag().to_string(), headers.get(header::ETAG).unwrap().to_str().unwrap());
+        let digest = format!("sha-512=:{}:", BASE64.encode(sha512::hash(CONTENTS).0));
+        assert_eq!(digest, headers.get("Repr-Digest").unwrap().to_str().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn put() {
+        let (db, url) = without_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+
+        // Too short, too long, and the wrong contents are all discarded:
+        for bytes in [&b"01234"[..], b"0123456789X", b"9876543210"] {
+            let resp = call_service(&app, put_request(&url, bytes)).await;
+            assert_eq!(400, resp.status(), "{:?}", bytes);
+            assert_eq!(0, db.builder.unfinished_attachments());
+        }
+        assert_eq!(404, call_service(&app, TestRequest::get().uri(&url).to_request()).await.status());
+
+        assert_eq!(201, call_service(&app, put_request(&url, CONTENTS)).await.status());
+        let resp = call_service(&app, TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(&read_body(resp).await[..], CONTENTS);
+        assert_eq!(202, call_service(&app, put_request(&url, CONTENTS)).await.status());
+    }
+
+    #[actix_web::test]
+    async fn interrupted_put() {
+        let (db, url) = without_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+
+        let body = futures::stream::iter(vec![Ok(Bytes::from_static(b"01234")), Err(PayloadError::Incomplete(None))]);
+        let (req, _) = put_request(&url, b"").replace_payload(actix_http::Payload::from(Box::pin(body) as BoxedPayloadStream));
+        let resp = call_service(&app, req).await;
+        assert!(resp.status().is_server_error());
+
+        // The unfinished upload is discarded in the background:
+        for _ in 0..100 {
+            if db.builder.unfinished_attachments() == 0 { break; }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(0, db.builder.unfinished_attachments());
+        assert_eq!(404, call_service(&app, TestRequest::get().uri(&url).to_request()).await.status());
+    }
+
+    /// For backends that can't start_attachment().
+    #[actix_web::test]
+    async fn put_via_temp_file() {
+        let db = TestDb::new();
+        let user = db.new_user();
+        let signature = db.save(&user, &post_with_files(100, &[("digits.txt", CONTENTS)]));
+        let backend = db.factory.open().unwrap();
+        let meta = || backend.get_attachment_meta(&user.0, &signature, "digits.txt").unwrap().unwrap();
+        let data = Data::new(db.app_data());
+
+        let rejected = receive_to_temp_file(&data, &meta(), payload(b"9876543210").await).await.unwrap();
+        assert_eq!(400, rejected.unwrap().status());
+        assert!(!meta().exists);
+
+        let rejected = receive_to_temp_file(&data, &meta(), payload(CONTENTS).await).await.unwrap();
+        assert!(rejected.is_none());
+        assert!(meta().exists);
+        assert_eq!(0, db.builder.unfinished_attachments());
+    }
+
+    #[actix_web::test]
+    async fn resumable_upload() {
+        let (db, url) = without_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+
+        let resp = call_service(&app, patch_request(&url, 0, None, b"012")).await;
+        assert_eq!(204, resp.status());
+        assert_eq!("3", resp.headers().get("Upload-Offset").unwrap());
+        let token = resp.headers().get(UPLOAD_TOKEN).unwrap().to_str().unwrap().to_string();
+
+        // Only the upload's owner can see its progress:
+        let resp = call_service(&app, head_request(&url, Some(&token))).await;
+        assert_eq!(404, resp.status());
+        assert_eq!("3", resp.headers().get("Upload-Offset").unwrap());
+        assert_eq!("10", resp.headers().get("Upload-Length").unwrap());
+        let resp = call_service(&app, head_request(&url, None)).await;
+        assert!(resp.headers().get("Upload-Offset").is_none());
+
+        // ... or add to it:
+        assert_eq!(404, call_service(&app, patch_request(&url, 3, Some("guess"), b"xyz")).await.status());
+        let resp = call_service(&app, patch_request(&url, 3, None, b"xyz")).await;
+        assert_eq!(409, resp.status());
+        assert_eq!("0", resp.headers().get("Upload-Offset").unwrap());
+
+        let resp = call_service(&app, patch_request(&url, 5, Some(&token), b"56789")).await;
+        assert_eq!(409, resp.status());
+        assert_eq!("3", resp.headers().get("Upload-Offset").unwrap());
+
+        let resp = call_service(&app, patch_request(&url, 3, Some(&token), b"3456789")).await;
+        assert_eq!(201, resp.status());
+        assert_eq!("10", resp.headers().get("Upload-Offset").unwrap());
+
+        let resp = call_service(&app, TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(200, resp.status());
+        assert_eq!(&read_body(resp).await[..], CONTENTS);
+    }
+
+    #[actix_web::test]
+    async fn interrupted_upload() {
+        let (db, url) = without_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+
+        let resp = call_service(&app, patch_request(&url, 0, None, b"01")).await;
+        let token = resp.headers().get(UPLOAD_TOKEN).unwrap().to_str().unwrap().to_string();
+
+        // The connection drops partway through a body:
+        let body = futures::stream::iter(vec![Ok(Bytes::from_static(b"234")), Err(PayloadError::Incomplete(None))]);
+        let (req, _) = patch_request(&url, 2, Some(&token), b"").replace_payload(actix_http::Payload::from(Box::pin(body) as BoxedPayloadStream));
+        let resp = call_service(&app, req).await;
+        assert_eq!(400, resp.status());
+        assert_eq!("5", resp.headers().get("Upload-Offset").unwrap());
+
+        let resp = call_service(&app, head_request(&url, Some(&token))).await;
+        assert_eq!("5", resp.headers().get("Upload-Offset").unwrap());
+
+        let resp = call_service(&app, patch_request(&url, 5, Some(&token), b"56789")).await;
+        assert_eq!(201, resp.status());
+        let resp = call_service(&app, TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(&read_body(resp).await[..], CONTENTS);
+    }
+
+    #[actix_web::test]
+    async fn rejected_uploads() {
+        let (db, url) = without_file();
+        let app = init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+
+        // Longer than the file:
+        let resp = call_service(&app, patch_request(&url, 0, None, b"0123456789X")).await;
+        assert_eq!(400, resp.status());
+        let token = resp.headers().get(UPLOAD_TOKEN).unwrap().to_str().unwrap().to_string();
+        let resp = call_service(&app, head_request(&url, Some(&token))).await;
+        assert_eq!("0", resp.headers().get("Upload-Offset").unwrap());
+
+        // The right size, but the wrong contents:
+        let resp = call_service(&app, patch_request(&url, 0, Some(&token), b"9876543210")).await;
+        assert_eq!(400, resp.status());
+        assert!(String::from_utf8_lossy(&read_body(resp).await).contains("discarded"));
+        assert_eq!(404, call_service(&app, patch_request(&url, 10, Some(&token), b"")).await.status());
+        assert_eq!(404, call_service(&app, TestRequest::get().uri(&url).to_request()).await.status());
+
+        // Abandoned uploads expire:
+        let resp = call_service(&app, patch_request(&url, 0, None, b"01")).await;
+        let token = resp.headers().get(UPLOAD_TOKEN).unwrap().to_str().unwrap().to_string();
+        let expired = db.factory.open().unwrap().expire_partial_uploads(Timestamp{ unix_utc_ms: Timestamp::now().unix_utc_ms + 1 }).unwrap();
+        assert_eq!(1, expired);
+        assert_eq!(404, call_service(&app, patch_request(&url, 2, Some(&token), b"23")).await.status());
+    }
+
+    /// Headers that an attachment with this name would be served with, by default.
+    fn served(file_name: &str) -> HeaderMap {
+        let mime_type = AttachmentsConfig::default().mime_type(file_name);
+        let mut response = HttpResponse::Ok();
+        security_headers(&mut response, &mime_type, file_name)
+            .content_type(mime_type.to_string());
+        response.finish().headers().clone()
+    }
+
+    fn get(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+        headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn inline_types() {
+        let types = [
+            ("a.txt", "text/plain"),
+            ("a.avif", "image/avif"),
+            ("a.gif", "image/gif"),
+            ("a.jpg", "image/jpeg"),
+            ("a.png", "image/png"),
+            ("a.webp", "image/webp"),
+            ("a.mp3", "audio/mpeg"),
+            ("a.ogg", "audio/ogg"),
+            ("a.mp4", "video/mp4"),
+            ("a.webm", "video/webm"),
+        ];
+        for (file_name, content_type) in types {
+            let headers = served(file_name);
+            assert_eq!(get(&headers, header::CONTENT_TYPE), Some(content_type), "{}", file_name);
+            assert_eq!(get(&headers, header::X_CONTENT_TYPE_OPTIONS), Some("nosniff"), "{}", file_name);
+            assert_eq!(get(&headers, header::CONTENT_SECURITY_POLICY), None, "{}", file_name);
+            assert_eq!(get(&headers, header::CONTENT_DISPOSITION), None, "{}", file_name);
+        }
+    }
+
+    #[test]
+    fn svg_is_sandboxed() {
+        let headers = served("drawing.svg");
+        assert_eq!(get(&headers, header::CONTENT_TYPE), Some("image/svg+xml"));
+        assert_eq!(get(&headers, header::X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+        let csp = get(&headers, header::CONTENT_SECURITY_POLICY).unwrap();
+        assert!(csp.contains("sandbox"));
+        assert!(csp.contains("default-src 'none'"));
+        assert_eq!(get(&headers, header::CONTENT_DISPOSITION), None);
+    }
+
+    #[test]
+    fn downloads() {
+        let headers = served("paper.pdf");
+        assert_eq!(get(&headers, header::CONTENT_TYPE), Some("application/pdf"));
+        assert_eq!(get(&headers, header::X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+        assert_eq!(get(&headers, header::CONTENT_SECURITY_POLICY), Some(SANDBOX_CSP));
+        assert_eq!(get(&headers, header::CONTENT_DISPOSITION), Some(r#"attachment; filename="paper.pdf""#));
+
+        // Types that aren't allowed (or are unknown) are downloaded as application/octet-stream:
+        for file_name in ["page.html", "script.js", "program.exe", "no_extension"] {
+            let headers = served(file_name);
+            assert_eq!(get(&headers, header::CONTENT_TYPE), Some("application/octet-stream"), "{}", file_name);
+            assert_eq!(get(&headers, header::CONTENT_SECURITY_POLICY), Some(SANDBOX_CSP), "{}", file_name);
+            assert!(get(&headers, header::CONTENT_DISPOSITION).unwrap().starts_with("attachment;"), "{}", file_name);
+        }
+
+        let headers = served("résumé.pdf");
+        assert_eq!(get(&headers, header::CONTENT_DISPOSITION), Some("attachment; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"));
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+}
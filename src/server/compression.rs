@@ -0,0 +1,242 @@
+//! gzip/brotli/zstd compression of responses, for clients that accept it.
+//!
+//! Like actix-web's own `Compress` middleware, but only for the MIME types and sizes
+//! configured in `[compression]`. (Compressing a JPEG just wastes CPU.)
+//!
+//! Responses marked `Cache-Control: no-transform` (like items and attachments, by `immutable()`) are sent as-is.
+
+use std::future::Future;
+
+use actix_http::encoding::Encoder;
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{Method, StatusCode, header::{self, AcceptEncoding, ContentEncoding, Encoding, EntityTag, Header, HeaderValue}},
+    web::Data,
+    HttpResponse,
+};
+
+use super::{AppData, config::CompressionConfig};
+
+/// Encodings we can send. Among those the client likes equally, actix-web prefers brotli.
+const SUPPORTED_ENCODINGS: [Encoding; 4] = [
+    Encoding::zstd(),
+    Encoding::brotli(),
+    Encoding::gzip(),
+    Encoding::identity(),
+];
+
+/// Middleware that compresses responses.
+pub(crate) fn compress<S, B>(req: ServiceRequest, srv: &S)
+-> impl Future<Output = Result<ServiceResponse<BoxBody>, S::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>>,
+    B: MessageBody + 'static,
+{
+    // HEAD should report the size of the file, not of some compressed copy of it:
+    let encoding = if req.method() == Method::GET { negotiate(&req) } else { None };
+    let data = req.app_data::<Data<AppData>>().cloned();
+    let response = srv.call(req);
+
+    async move {
+        let mut response = response.await?;
+        let compressible = data.is_some_and(|data| {
+            should_compress(&data.config.compression, response.response())
+        });
+        if !compressible {
+            return Ok(response.map_into_boxed_body());
+        }
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => {
+                // Other clients may get a compressed copy, so caches must keep them apart:
+                response.headers_mut().append(header::VARY, HeaderValue::from_static("accept-encoding"));
+                return Ok(response.map_into_boxed_body());
+            }
+        };
+
+        let response = response.map_body(|head, body| {
+            // The compressed bytes are a different representation, with a different hash:
+            let headers = &mut head.headers;
+            headers.remove("repr-digest");
+            // (ex: from no_chunking()) The compressed size isn't known until it's sent:
+            headers.remove(header::CONTENT_LENGTH);
+            let weak = headers.get(header::ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .and_then(|etag| etag.parse::<EntityTag>().ok())
+                .map(|etag| EntityTag::new_weak(etag.tag().to_string()));
+            if let Some(weak) = weak {
+                headers.insert(header::ETAG, weak.to_string().parse().expect("valid ETag"));
+            }
+
+            Encoder::response(encoding, head, body)
+        });
+        Ok(response.map_into_boxed_body())
+    }
+}
+
+/// The client's preferred encoding, if it's one that compresses.
+fn negotiate(req: &ServiceRequest) -> Option<ContentEncoding> {
+    let accept = AcceptEncoding::parse(req).ok()?;
+    match accept.negotiate(SUPPORTED_ENCODINGS.iter()) {
+        Some(Encoding::Known(ContentEncoding::Identity)) | Some(Encoding::Unknown(_)) | None => None,
+        Some(Encoding::Known(encoding)) => Some(encoding),
+    }
+}
+
+fn should_compress<B: MessageBody>(config: &CompressionConfig, response: &HttpResponse<B>) -> bool {
+    // Not 206 Partial Content: a compressed range of a file is nonsense.
+    if response.status() != StatusCode::OK {
+        return false;
+    }
+
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+
+    let no_transform = headers.get_all(header::CACHE_CONTROL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return false;
+    }
+
+    let content_type = headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime_guess::Mime>().ok());
+    let compressible = content_type.is_some_and(|mime| {
+        config.types.iter().any(|t| t == mime.essence_str())
+    });
+    if !compressible {
+        return false;
+    }
+
+    match response.body().size() {
+        BodySize::Sized(size) => size >= config.min_bytes,
+        BodySize::None => false,
+        // Unknown. Probably large. (A Content-Length header may not match what the stream sends.)
+        BodySize::Stream => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, test, web::{self, Bytes}};
+
+    use crate::server::test_util::TestDb;
+    use super::*;
+
+    const BIG: usize = 2048;
+
+    fn text(size: usize) -> String {
+        "Hello! ".repeat(size / 7 + 1)[..size].to_string()
+    }
+
+    async fn get(uri: &str, accept_encoding: Option<&str>) -> ServiceResponse {
+        let db = TestDb::new();
+        let app = test::init_service(
+            App::new()
+            .wrap_fn(compress)
+            .app_data(Data::new(db.app_data()))
+            .route("/text/{size}", web::get().to(|size: web::Path<usize>| async move {
+                HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .insert_header((header::ETAG, "\"abc\""))
+                    .insert_header(("repr-digest", "sha-512=:abc=:"))
+                    .body(text(*size))
+            }))
+            .route("/stream", web::get().to(|| async {
+                HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .no_chunking(BIG as u64)
+                    .streaming(futures::stream::once(async { Ok::<_, std::io::Error>(Bytes::from(text(BIG))) }))
+            }))
+            .route("/jpeg", web::get().to(|| async {
+                HttpResponse::Ok().content_type("image/jpeg").body(text(BIG))
+            }))
+            .route("/encoded", web::get().to(|| async {
+                HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .insert_header((header::CONTENT_ENCODING, "gzip"))
+                    .body(text(BIG))
+            }))
+            .route("/range", web::get().to(|| async {
+                HttpResponse::PartialContent().content_type("text/plain").body(text(BIG))
+            }))
+            .route("/no-transform", web::get().to(|| async {
+                HttpResponse::Ok()
+                    .content_type("text/plain")
+                    .insert_header((header::CACHE_CONTROL, "public, max-age=31536000, no-transform, immutable"))
+                    .body(text(BIG))
+            }))
+        ).await;
+
+        let mut req = test::TestRequest::get().uri(uri);
+        if let Some(accept_encoding) = accept_encoding {
+            req = req.insert_header((header::ACCEPT_ENCODING, accept_encoding));
+        }
+        test::call_service(&app, req.to_request()).await
+    }
+
+    fn header(response: &ServiceResponse, name: impl header::AsHeaderName) -> Option<&str> {
+        response.headers().get(name).map(|value| value.to_str().unwrap())
+    }
+
+    #[actix_web::test]
+    async fn negotiation() {
+        let uri = format!("/text/{}", BIG);
+        let cases = [
+            (Some("gzip"), Some("gzip")),
+            (Some("gzip, br, zstd"), Some("br")),
+            (Some("gzip, zstd;q=0.9"), Some("gzip")),
+            (Some("gzip;q=0.5, br"), Some("br")),
+            (Some("gzip;q=0, br;q=0"), None),
+            (Some("identity"), None),
+            (None, None),
+        ];
+        for (accept, expected) in cases {
+            let response = get(&uri, accept).await;
+            assert_eq!(expected, header(&response, header::CONTENT_ENCODING), "{:?}", accept);
+            // Either way, another client could get a different encoding:
+            assert_eq!(Some("accept-encoding"), header(&response, header::VARY), "{:?}", accept);
+        }
+    }
+
+    #[actix_web::test]
+    async fn headers() {
+        let response = get(&format!("/text/{}", BIG), Some("gzip")).await;
+        assert_eq!(Some("gzip"), header(&response, header::CONTENT_ENCODING));
+        assert_eq!(Some("W/\"abc\""), header(&response, header::ETAG));
+        assert_eq!(None, header(&response, "repr-digest"));
+        let body = test::read_body(response).await;
+        assert!(body.len() < BIG);
+
+        let response = get(&format!("/text/{}", BIG), None).await;
+        assert_eq!(Some("\"abc\""), header(&response, header::ETAG));
+        assert!(header(&response, "repr-digest").is_some());
+
+        // A Content-Length for the uncompressed stream would be wrong:
+        let response = get("/stream", Some("gzip")).await;
+        assert_eq!(Some("gzip"), header(&response, header::CONTENT_ENCODING));
+        assert_eq!(None, header(&response, header::CONTENT_LENGTH));
+        assert!(test::read_body(response).await.len() < BIG);
+    }
+
+    #[actix_web::test]
+    async fn skipped() {
+        // Too small, not a compressible type, only part of the file, or marked no-transform:
+        for (uri, size) in [("/text/100", 100), ("/jpeg", BIG), ("/range", BIG), ("/no-transform", BIG)] {
+            let response = get(uri, Some("gzip")).await;
+            assert_eq!(None, header(&response, header::CONTENT_ENCODING), "{}", uri);
+            assert_eq!(None, header(&response, header::VARY), "{}", uri);
+            assert_eq!(size, test::read_body(response).await.len(), "{}", uri);
+        }
+
+        // Already encoded, so passed through untouched:
+        let response = get("/encoded", Some("br")).await;
+        assert_eq!(Some("gzip"), header(&response, header::CONTENT_ENCODING));
+        assert_eq!(BIG, test::read_body(response).await.len());
+    }
+}
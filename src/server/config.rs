@@ -0,0 +1,510 @@
+//! Settings for `diskuto serve`.
+//!
+//! Each setting comes from (in increasing order of precedence):
+//!  * Its default, below.
+//!  * The TOML file passed with `--config`. (See: `docs/config.md`)
+//!  * A `DISKUTO_*` environment variable. Handy for containers.
+//!  * Command-line options, like `--bind`.
+//!
+//! Everything is validated once, at startup, so that a typo doesn't surface as
+//! a confusing error on some later request.
+
+use std::{net::{IpAddr, SocketAddr}, path::{Path, PathBuf}};
+
+use anyhow::{Context, bail};
+use mime_guess::mime;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub server: ServerConfig,
+    pub limits: LimitsConfig,
+    pub database: DatabaseConfig,
+    pub attachments: AttachmentsConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub compression: CompressionConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    /// Local addresses to listen on for HTTP, when there's no `--bind` or `--tls-bind`.
+    pub binds: Vec<String>,
+
+    /// Local addresses to listen on for HTTPS.
+    pub tls_binds: Vec<String>,
+
+    /// PEM certificate chain for `tls_binds`.
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            binds: vec!["127.0.0.1:8080".into()],
+            tls_binds: vec![],
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LimitsConfig {
+    /// The largest Item that can be uploaded.
+    pub max_item_bytes: usize,
+
+    /// The max page size for lists of ItemListEntry.
+    /// We're only holding ItemListEntries in memory, so this can be much higher than the Paginator default.
+    pub max_list_items: usize,
+
+    /// The most Item bytes to put in one page of a list, with `?include=items`.
+    /// Pages stop after the entry that goes over this, so each holds at least one.
+    pub max_list_bytes: usize,
+
+    /// The most Items that can be fetched at once from `POST /diskuto/items/batch`.
+    pub max_batch_items: usize,
+
+    /// Number of seconds a browser can cache the CORS allows.
+    /// https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Access-Control-Max-Age
+    /// FF caps this at 24 hours, and is the most permissive there, so that's the default.
+    pub cors_max_age_secs: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_item_bytes: 1024 * 32,
+            max_list_items: 1000,
+            max_list_bytes: 1024 * 1024,
+            max_batch_items: 1000,
+            cors_max_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DatabaseConfig {
+    /// The most DB connections to have open at once.
+    pub pool_max_size: u32,
+
+    /// How many idle connections to keep open.
+    pub pool_min_idle: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_size: 10,
+            pool_min_idle: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AttachmentsConfig {
+    /// MIME types that attachments may be served as.
+    /// Anything else is served as application/octet-stream.
+    /// Types that might be risky are sandboxed or downloaded. See: `attachments::Handling`
+    pub allowed_types: Vec<String>,
+
+    /// Sizes (in pixels) that `?thumb=` may ask for. Empty to disable thumbnails.
+    /// Each size is cached separately, so keep this list short.
+    pub thumbnail_sizes: Vec<u32>,
+
+    /// Resumable uploads that haven't received any bytes in this long are deleted.
+    pub partial_upload_expiry_hours: u64,
+}
+
+impl Default for AttachmentsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_types: DEFAULT_ALLOWED_TYPES.iter().map(|t| t.to_string()).collect(),
+            thumbnail_sizes: vec![128, 256, 512, 1024],
+            partial_upload_expiry_hours: 24,
+        }
+    }
+}
+
+/// Token-bucket rate limits, per client IP address.
+/// Successful PUTs are also limited per user ID. A limit of 0 means unlimited.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimitsConfig {
+    /// Item uploads.
+    pub item_puts_per_minute: u64,
+
+    /// Attachment uploads, by their Content-Length.
+    pub attachment_bytes_per_minute: u64,
+
+    /// GETs of lists and feeds of items. (ex: /diskuto/homepage)
+    pub list_gets_per_minute: u64,
+
+    /// POSTs to ActivityPub inboxes, which may make us fetch remote keys.
+    pub inbox_posts_per_minute: u64,
+
+    /// Reverse proxies whose X-Forwarded-For header we believe.
+    /// Otherwise, all clients behind a proxy would share one limit.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            // Bulk tools (like diskuto-sync) make many of these requests, so they're unlimited by default.
+            item_puts_per_minute: 0,
+            attachment_bytes_per_minute: 0,
+            list_gets_per_minute: 0,
+            // ... but only other ActivityPub servers should be POSTing to inboxes.
+            inbox_posts_per_minute: 60,
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+/// gzip/brotli/zstd compression of responses. See: `compression::compress`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CompressionConfig {
+    /// MIME types to compress, when the client accepts it. Empty disables compression.
+    pub types: Vec<String>,
+
+    /// Smaller responses aren't worth the CPU time. (Or the compression headers.)
+    pub min_bytes: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            types: DEFAULT_COMPRESSED_TYPES.iter().map(|t| t.to_string()).collect(),
+            min_bytes: 1024,
+        }
+    }
+}
+
+/// Item lists (and other API responses), plus text.
+const DEFAULT_COMPRESSED_TYPES: [&str; 9] = [
+    "application/protobuf3",
+    "application/json",
+    "application/activity+json",
+    "application/atom+xml",
+    "application/rss+xml",
+    "text/plain",
+    "text/html",
+    "text/css",
+    "image/svg+xml",
+];
+
+/// Compressed formats, which just waste CPU time to compress again.
+/// (All image, audio and video types are assumed to be compressed, except SVG.)
+const PRECOMPRESSED_TYPES: [&str; 5] = [
+    "application/gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+];
+
+/// Types that are useful to share in posts.
+const DEFAULT_ALLOWED_TYPES: [&str; 12] = [
+    "text/plain",
+    "image/avif",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+    "audio/mpeg",
+    "audio/ogg",
+    "video/mp4",
+    "video/webm",
+    // These can embed JavaScript, so are served in a sandbox:
+    "image/svg+xml",
+    "application/pdf",
+];
+
+/// Batches are fetched in a single SQLite query, which can have at most 32766 parameters.
+/// (Two per Item.)
+const MAX_BATCH_ITEMS: usize = 10_000;
+
+/// Larger thumbnails aren't much of a savings, and take a lot of memory to generate.
+const MAX_THUMBNAIL_SIZE: u32 = 2048;
+
+/// Types that can run JavaScript, which may never be allowed.
+///
+/// Diskuto is not meant to be a general web server.
+/// Plus, since the client also runs in the browser, any mime type that can run JavaScript
+/// could exfiltrate private keys.
+/// Javascript, obviously. But HTML can embed JavaScript.
+/// (So can SVG, but it's useful enough to serve in a sandbox.)
+const SCRIPTABLE_TYPES: [&str; 7] = [
+    "text/html",
+    "text/javascript",
+    "text/xml",
+    "application/javascript",
+    "application/ecmascript",
+    "application/xhtml+xml",
+    "application/xml",
+];
+
+impl Config {
+    /// Load settings from an optional config file, then environment variables.
+    pub fn load(file: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let mut config = match file {
+            None => Config::default(),
+            Some(file) => {
+                let text = std::fs::read_to_string(file)
+                    .with_context(|| format!("Error reading config file: {}", file.display()))?;
+                Self::parse(&text)
+                    .with_context(|| format!("Error in config file: {}", file.display()))?
+            }
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    fn parse(text: &str) -> Result<Self, anyhow::Error> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Override settings from `DISKUTO_*` environment variables.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), anyhow::Error> {
+        if let Some(value) = var("DISKUTO_BINDS") {
+            self.server.binds = list(&value);
+        }
+        if let Some(value) = var("DISKUTO_TLS_BINDS") {
+            self.server.tls_binds = list(&value);
+        }
+        if let Some(value) = var("DISKUTO_TLS_CERT") {
+            self.server.tls_cert = Some(value.into());
+        }
+        if let Some(value) = var("DISKUTO_TLS_KEY") {
+            self.server.tls_key = Some(value.into());
+        }
+        if let Some(value) = var("DISKUTO_MAX_ITEM_BYTES") {
+            self.limits.max_item_bytes = number("DISKUTO_MAX_ITEM_BYTES", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_MAX_LIST_ITEMS") {
+            self.limits.max_list_items = number("DISKUTO_MAX_LIST_ITEMS", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_MAX_LIST_BYTES") {
+            self.limits.max_list_bytes = number("DISKUTO_MAX_LIST_BYTES", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_MAX_BATCH_ITEMS") {
+            self.limits.max_batch_items = number("DISKUTO_MAX_BATCH_ITEMS", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_CORS_MAX_AGE_SECS") {
+            self.limits.cors_max_age_secs = number("DISKUTO_CORS_MAX_AGE_SECS", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_POOL_MAX_SIZE") {
+            self.database.pool_max_size = number("DISKUTO_POOL_MAX_SIZE", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_POOL_MIN_IDLE") {
+            self.database.pool_min_idle = number("DISKUTO_POOL_MIN_IDLE", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_ATTACHMENT_TYPES") {
+            self.attachments.allowed_types = list(&value);
+        }
+        if let Some(value) = var("DISKUTO_THUMBNAIL_SIZES") {
+            self.attachments.thumbnail_sizes = list(&value).iter()
+                .map(|size| number("DISKUTO_THUMBNAIL_SIZES", size))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(value) = var("DISKUTO_PARTIAL_UPLOAD_EXPIRY_HOURS") {
+            self.attachments.partial_upload_expiry_hours = number("DISKUTO_PARTIAL_UPLOAD_EXPIRY_HOURS", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_COMPRESS_TYPES") {
+            self.compression.types = list(&value);
+        }
+        if let Some(value) = var("DISKUTO_COMPRESS_MIN_BYTES") {
+            self.compression.min_bytes = number("DISKUTO_COMPRESS_MIN_BYTES", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_ITEM_PUTS_PER_MINUTE") {
+            self.rate_limits.item_puts_per_minute = number("DISKUTO_ITEM_PUTS_PER_MINUTE", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_ATTACHMENT_BYTES_PER_MINUTE") {
+            self.rate_limits.attachment_bytes_per_minute = number("DISKUTO_ATTACHMENT_BYTES_PER_MINUTE", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_LIST_GETS_PER_MINUTE") {
+            self.rate_limits.list_gets_per_minute = number("DISKUTO_LIST_GETS_PER_MINUTE", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_INBOX_POSTS_PER_MINUTE") {
+            self.rate_limits.inbox_posts_per_minute = number("DISKUTO_INBOX_POSTS_PER_MINUTE", &value)?;
+        }
+        if let Some(value) = var("DISKUTO_TRUSTED_PROXIES") {
+            self.rate_limits.trusted_proxies = list(&value).iter()
+                .map(|ip| ip.parse().with_context(|| format!("DISKUTO_TRUSTED_PROXIES: {:?} is not an IP address", ip)))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+
+    /// Check that settings are sensible, and normalize them.
+    pub fn validate(&mut self) -> Result<(), anyhow::Error> {
+        let server = &self.server;
+        if server.binds.is_empty() && server.tls_binds.is_empty() {
+            bail!("server.binds or server.tls_binds must list at least one address");
+        }
+        for (name, binds) in &[("server.binds", &server.binds), ("server.tls_binds", &server.tls_binds)] {
+            for bind in binds.iter() {
+                if bind.parse::<SocketAddr>().is_err() {
+                    bail!("{}: {:?} is not an address and port, like \"127.0.0.1:8080\"", name, bind);
+                }
+            }
+        }
+        match (server.tls_binds.is_empty(), &server.tls_cert, &server.tls_key) {
+            (true, None, None) | (false, Some(_), Some(_)) => {},
+            (true, _, _) => bail!("server.tls_cert and server.tls_key are only used with server.tls_binds"),
+            (false, _, _) => bail!("server.tls_binds requires both server.tls_cert and server.tls_key"),
+        }
+
+        if self.limits.max_item_bytes == 0 {
+            bail!("limits.max_item_bytes must be greater than 0");
+        }
+        if self.limits.max_list_items == 0 {
+            bail!("limits.max_list_items must be greater than 0");
+        }
+        if self.limits.max_list_bytes == 0 {
+            bail!("limits.max_list_bytes must be greater than 0");
+        }
+        if self.limits.max_batch_items == 0 || self.limits.max_batch_items > MAX_BATCH_ITEMS {
+            bail!("limits.max_batch_items must be between 1 and {}", MAX_BATCH_ITEMS);
+        }
+
+        let db = &self.database;
+        if db.pool_max_size == 0 {
+            bail!("database.pool_max_size must be greater than 0");
+        }
+        if db.pool_min_idle > db.pool_max_size {
+            bail!(
+                "database.pool_min_idle ({}) must be <= database.pool_max_size ({})",
+                db.pool_min_idle, db.pool_max_size,
+            );
+        }
+
+        for allowed in self.attachments.allowed_types.iter_mut() {
+            let parsed: mime::Mime = match allowed.parse() {
+                Ok(parsed) => parsed,
+                Err(_) => bail!("attachments.allowed_types: {:?} is not a MIME type", allowed),
+            };
+            let essence = parsed.essence_str().to_string();
+            if parsed.subtype() == mime::STAR || SCRIPTABLE_TYPES.contains(&essence.as_str()) {
+                bail!("attachments.allowed_types: {:?} is not allowed, since it could run scripts", allowed);
+            }
+            *allowed = essence;
+        }
+
+        if self.attachments.partial_upload_expiry_hours == 0 {
+            bail!("attachments.partial_upload_expiry_hours must be greater than 0");
+        }
+
+        for &size in &self.attachments.thumbnail_sizes {
+            if size == 0 || size > MAX_THUMBNAIL_SIZE {
+                bail!("attachments.thumbnail_sizes: {} must be between 1 and {}", size, MAX_THUMBNAIL_SIZE);
+            }
+        }
+
+        for compressed in self.compression.types.iter_mut() {
+            let parsed: mime::Mime = match compressed.parse() {
+                Ok(parsed) => parsed,
+                Err(_) => bail!("compression.types: {:?} is not a MIME type", compressed),
+            };
+            let essence = parsed.essence_str().to_string();
+            let media = parsed.type_() == mime::AUDIO || parsed.type_() == mime::VIDEO
+                || (parsed.type_() == mime::IMAGE && parsed.subtype() != mime::SVG);
+            if media || PRECOMPRESSED_TYPES.contains(&essence.as_str()) {
+                bail!("compression.types: {:?} is already compressed", compressed);
+            }
+            *compressed = essence;
+        }
+
+        Ok(())
+    }
+}
+
+/// A comma-separated list.
+fn list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, anyhow::Error> {
+    match value.trim().parse() {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("{} must be a non-negative integer, got {:?}", name, value),
+    }
+}
+
+impl AttachmentsConfig {
+    /// The MIME type we'll serve a file attachment as.
+    pub fn mime_type(&self, file_name: &str) -> mime::Mime {
+        let mime_type = mime_guess::from_path(file_name).first_or_octet_stream();
+        if !self.allowed_types.iter().any(|t| t == mime_type.essence_str()) {
+            return mime::APPLICATION_OCTET_STREAM;
+        }
+        mime_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layering() {
+        let mut config = Config::parse(r#"
+            [limits]
+            max_item_bytes = 65536
+
+            [attachments]
+            allowed_types = ["image/png", "Video/MP4"]
+        "#).unwrap();
+        config.apply_env(|name| match name {
+            "DISKUTO_BINDS" => Some("0.0.0.0:8080, [::]:8080".into()),
+            "DISKUTO_MAX_LIST_ITEMS" => Some("500".into()),
+            _ => None,
+        }).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.server.binds, vec!["0.0.0.0:8080", "[::]:8080"]);
+        assert_eq!(config.limits.max_item_bytes, 65536);
+        assert_eq!(config.limits.max_list_items, 500);
+        assert_eq!(config.limits.cors_max_age_secs, 86400);
+        assert_eq!(config.database.pool_max_size, 10);
+        assert_eq!(config.attachments.mime_type("a.mp4").essence_str(), "video/mp4");
+        assert_eq!(config.attachments.mime_type("a.txt"), mime::APPLICATION_OCTET_STREAM);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Config::parse("[limits]\nmax_items = 5").is_err());
+        assert!(Config::parse("[limits]\nmax_item_bytes = -1").is_err());
+        assert!(Config::default().apply_env(|_| Some("lots".into())).is_err());
+
+        let check = |toml: &str| Config::parse(toml).unwrap().validate().unwrap_err().to_string();
+        assert!(check("[server]\nbinds = [\"localhost\"]").contains("server.binds"));
+        assert!(check("[server]\nbinds = []").contains("at least one"));
+        assert!(check("[server]\ntls_binds = [\"0.0.0.0:443\"]\ntls_cert = \"cert.pem\"").contains("tls_key"));
+        assert!(check("[server]\ntls_cert = \"cert.pem\"\ntls_key = \"key.pem\"").contains("only used with"));
+        assert!(check("[limits]\nmax_list_items = 0").contains("max_list_items"));
+        assert!(check("[limits]\nmax_list_bytes = 0").contains("max_list_bytes"));
+        assert!(check("[limits]\nmax_batch_items = 20000").contains("max_batch_items"));
+        assert!(check("[database]\npool_max_size = 2\npool_min_idle = 3").contains("pool_min_idle"));
+        assert!(check("[attachments]\nallowed_types = [\"text/html\"]").contains("run scripts"));
+        assert!(check("[attachments]\nallowed_types = [\"image/*\"]").contains("run scripts"));
+        assert!(check("[attachments]\nallowed_types = [\"png\"]").contains("not a MIME type"));
+        assert!(check("[attachments]\nthumbnail_sizes = [128, 4096]").contains("thumbnail_sizes"));
+        assert!(check("[compression]\ntypes = [\"image/jpeg\"]").contains("already compressed"));
+        assert!(check("[compression]\ntypes = [\"audio/*\"]").contains("already compressed"));
+        assert!(check("[compression]\ntypes = [\"application/zip\"]").contains("already compressed"));
+    }
+}
@@ -0,0 +1,386 @@
+//! Atom and RSS feeds of posts.
+//!
+//! These let people follow Diskuto users with any feed reader. Markdown is
+//! rendered to HTML here, since feed readers can't do that for us.
+
+use std::{fmt::Write, time::{Duration, SystemTime}};
+
+use actix_web::{HttpRequest, HttpResponse, http::header::{self, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch}, web::{Data, Path}};
+use protobuf::Message;
+use sodiumoxide::crypto::hash::sha512;
+use url::Url;
+
+use crate::{backend::{ItemDisplayRow, ItemRow, Signature, TimeSpan, Timestamp, UserID}, markdown, protos::{Item, ItemType}, util::AsHex};
+
+use super::{AppData, Error, config::AttachmentsConfig, html::not_found, http_not_modified};
+
+/// Feed readers poll often, so only send the most recent posts.
+const MAX_FEED_ITEMS: usize = 50;
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Atom,
+    Rss,
+}
+
+/// `/diskuto/users/{userID}/feed.atom`
+pub(crate) async fn user_atom(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    user_feed(data, user_id, req, Format::Atom).await
+}
+
+/// `/diskuto/users/{userID}/feed.rss`
+pub(crate) async fn user_rss(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    user_feed(data, user_id, req, Format::Rss).await
+}
+
+/// `/diskuto/homepage.atom`
+pub(crate) async fn homepage_atom(
+    data: Data<AppData>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let origin = origin(&req)?;
+    let mut feed = Feed::new(&req, &data, origin.host_str().unwrap_or("Diskuto").to_string(), origin.clone())?;
+
+    let backend = data.backend_factory.open()?;
+    backend.homepage_items(TimeSpan::Before(Timestamp::now()), &[ItemType::POST], &mut |row| {
+        let ItemDisplayRow{item, display_name} = row;
+        let author = display_name.unwrap_or_else(|| item.user.to_base58());
+        feed.add(item, author)
+    })?;
+
+    feed.respond(&req, Format::Atom)
+}
+
+/// A feed of a single user's posts.
+async fn user_feed(
+    data: Data<AppData>,
+    user_id: UserID,
+    req: HttpRequest,
+    format: Format,
+) -> Result<HttpResponse, Error> {
+    let backend = data.backend_factory.open()?;
+    if !backend.user_known(&user_id)? {
+        return not_found().await;
+    }
+
+    let profile = backend.user_profile(&user_id)?;
+    let display_name = match &profile {
+        None => None,
+        Some(row) => {
+            let mut item = Item::new();
+            item.merge_from_bytes(&row.item_bytes)?;
+            Some(item.profile().display_name.trim().to_string()).filter(|name| !name.is_empty())
+        },
+    };
+    let author = display_name.unwrap_or_else(|| user_id.to_base58());
+
+    let link = origin(&req)?.join(&format!("u/{}/", user_id))?;
+    let mut feed = Feed::new(&req, &data, author.clone(), link)?;
+    if let Some(row) = &profile {
+        feed.versions.push(row.signature.clone());
+        feed.updated = row.received;
+    }
+
+    backend.user_items(&user_id, TimeSpan::Before(Timestamp::now()), &[ItemType::POST], &mut |row| {
+        feed.add(row, author.clone())
+    })?;
+
+    feed.respond(&req, format)
+}
+
+/// The scheme & host that the client used to reach us. ex: `https://example.com/`
+pub(super) fn origin(req: &HttpRequest) -> Result<Url, url::ParseError> {
+    let info = req.connection_info();
+    Url::parse(&format!("{}://{}/", info.scheme(), info.host()))
+}
+
+struct Feed {
+    title: String,
+
+    /// The URL for this feed.
+    self_url: Url,
+
+    /// A web page for this feed.
+    link: Url,
+
+    entries: Vec<Entry>,
+
+    /// Signatures of everything that went into this feed. Used to calculate an ETag.
+    versions: Vec<Signature>,
+
+    /// The time we last received something that went into this feed.
+    updated: Timestamp,
+
+    /// Used to give enclosures the same type that we serve them as.
+    attachments: AttachmentsConfig,
+}
+
+struct Entry {
+    user_id: UserID,
+    signature: Signature,
+    author: String,
+    timestamp: Timestamp,
+    item: Item,
+}
+
+impl Feed {
+    fn new(req: &HttpRequest, data: &AppData, title: String, link: Url) -> Result<Self, Error> {
+        Ok(Self {
+            attachments: data.config.attachments.clone(),
+            title,
+            self_url: origin(req)?.join(req.path())?,
+            link,
+            entries: vec![],
+            versions: vec![],
+            updated: Timestamp{ unix_utc_ms: 0 },
+        })
+    }
+
+    /// Callback for Backend queries. Adds posts to the feed until it is full.
+    fn add(&mut self, row: ItemRow, author: String) -> Result<bool, anyhow::Error> {
+        let mut item = Item::new();
+        item.merge_from_bytes(&row.item_bytes)?;
+        if !item.has_post() {
+            return Ok(true); // continue
+        }
+
+        self.versions.push(row.signature.clone());
+        if row.received.unix_utc_ms > self.updated.unix_utc_ms {
+            self.updated = row.received;
+        }
+
+        self.entries.push(Entry {
+            user_id: row.user,
+            signature: row.signature,
+            author,
+            timestamp: row.timestamp,
+            item,
+        });
+
+        Ok(self.entries.len() < MAX_FEED_ITEMS)
+    }
+
+    fn etag(&self) -> EntityTag {
+        let mut bytes = Vec::with_capacity(self.versions.len() * 64);
+        for signature in &self.versions {
+            bytes.extend_from_slice(signature.bytes());
+        }
+        let hash = sha512::hash(&bytes);
+        // Weak, since the rendered URLs depend on the Host we were reached by:
+        EntityTag::new_weak(format!("{}", (&hash.0[..16]).as_hex()))
+    }
+
+    fn last_modified(&self) -> SystemTime {
+        // HTTP dates only have 1s resolution:
+        let seconds = clamp_date(self.updated) / 1000;
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    }
+
+    /// Handles conditional requests so that feed readers can poll cheaply.
+    fn respond(&self, req: &HttpRequest, format: Format) -> Result<HttpResponse, Error> {
+        let etag = self.etag();
+        let last_modified = self.last_modified();
+
+        if not_modified(req, &etag, last_modified) {
+            return Ok(http_not_modified());
+        }
+
+        let (content_type, body) = match format {
+            Format::Atom => ("application/atom+xml; charset=utf-8", self.atom()?),
+            Format::Rss => ("application/rss+xml; charset=utf-8", self.rss()?),
+        };
+
+        Ok(
+            HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(header::ETag(etag))
+            .insert_header(header::LastModified(HttpDate::from(last_modified)))
+            .body(body)
+        )
+    }
+
+    fn atom(&self) -> Result<String, std::fmt::Error> {
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writeln!(xml, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#)?;
+        writeln!(xml, "<title>{}</title>", escape(&self.title))?;
+        writeln!(xml, "<id>{}</id>", escape(self.self_url.as_str()))?;
+        writeln!(xml, r#"<link rel="self" href="{}"/>"#, escape(self.self_url.as_str()))?;
+        writeln!(xml, r#"<link rel="alternate" type="text/html" href="{}"/>"#, escape(self.link.as_str()))?;
+        writeln!(xml, "<updated>{}</updated>", rfc3339(self.updated))?;
+
+        for entry in &self.entries {
+            let link = entry.link(&self.link)?;
+            let post = entry.item.post();
+            writeln!(xml, "<entry>")?;
+            writeln!(xml, "<title>{}</title>", escape(&post.title))?;
+            writeln!(xml, "<id>{}</id>", escape(link.as_str()))?;
+            writeln!(xml, r#"<link rel="alternate" type="text/html" href="{}"/>"#, escape(link.as_str()))?;
+            for file in &post.attachments.file {
+                writeln!(
+                    xml,
+                    r#"<link rel="enclosure" type="{}" length="{}" href="{}"/>"#,
+                    escape(self.attachments.mime_type(&file.name).as_ref()),
+                    file.size,
+                    escape(entry.file_url(&self.link, &file.name)?.as_str()),
+                )?;
+            }
+            writeln!(xml, "<author><name>{}</name></author>", escape(&entry.author))?;
+            writeln!(xml, "<published>{}</published>", rfc3339(entry.timestamp))?;
+            writeln!(xml, "<updated>{}</updated>", rfc3339(entry.timestamp))?;
+            writeln!(xml, r#"<content type="html">{}</content>"#, escape(&entry.html(&link)))?;
+            writeln!(xml, "</entry>")?;
+        }
+
+        writeln!(xml, "</feed>")?;
+        Ok(xml)
+    }
+
+    fn rss(&self) -> Result<String, std::fmt::Error> {
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writeln!(xml, r#"<rss version="2.0">"#)?;
+        writeln!(xml, "<channel>")?;
+        writeln!(xml, "<title>{}</title>", escape(&self.title))?;
+        writeln!(xml, "<link>{}</link>", escape(self.link.as_str()))?;
+        writeln!(xml, "<description>{}</description>", escape(&self.title))?;
+        writeln!(xml, "<lastBuildDate>{}</lastBuildDate>", HttpDate::from(self.last_modified()))?;
+
+        for entry in &self.entries {
+            let link = entry.link(&self.link)?;
+            let post = entry.item.post();
+            writeln!(xml, "<item>")?;
+            if !post.title.is_empty() {
+                writeln!(xml, "<title>{}</title>", escape(&post.title))?;
+            }
+            writeln!(xml, "<link>{}</link>", escape(link.as_str()))?;
+            writeln!(xml, r#"<guid isPermaLink="true">{}</guid>"#, escape(link.as_str()))?;
+            writeln!(xml, "<pubDate>{}</pubDate>", HttpDate::from(to_system_time(entry.timestamp)))?;
+            for file in &post.attachments.file {
+                writeln!(
+                    xml,
+                    r#"<enclosure url="{}" length="{}" type="{}"/>"#,
+                    escape(entry.file_url(&self.link, &file.name)?.as_str()),
+                    file.size,
+                    escape(self.attachments.mime_type(&file.name).as_ref()),
+                )?;
+            }
+            writeln!(xml, "<description>{}</description>", escape(&entry.html(&link)))?;
+            writeln!(xml, "</item>")?;
+        }
+
+        writeln!(xml, "</channel>")?;
+        writeln!(xml, "</rss>")?;
+        Ok(xml)
+    }
+}
+
+impl Entry {
+    /// Link to a web page for this Item, using the standard URL layout. (See: docs/url_layout.md)
+    fn link(&self, base: &Url) -> Result<Url, std::fmt::Error> {
+        base.join(&format!("/u/{}/i/{}/", self.user_id, self.signature.to_base58()))
+            .map_err(|_| std::fmt::Error)
+    }
+
+    /// The REST URL for a file attachment.
+    fn file_url(&self, base: &Url, file_name: &str) -> Result<Url, std::fmt::Error> {
+        let mut url = base.join(&format!(
+            "/diskuto/users/{}/items/{}/files/",
+            self.user_id,
+            self.signature.to_base58(),
+        )).map_err(|_| std::fmt::Error)?;
+        url.path_segments_mut()
+            .map_err(|_| std::fmt::Error)?
+            .pop_if_empty()
+            .push(file_name);
+        Ok(url)
+    }
+
+    fn html(&self, link: &Url) -> String {
+        markdown::to_html(&self.item.post().body, Some(link))
+    }
+}
+
+fn not_modified(req: &HttpRequest, etag: &EntityTag, last_modified: SystemTime) -> bool {
+    // If-None-Match takes precedence, if present:
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => return true,
+        Ok(IfNoneMatch::Items(tags)) if !tags.is_empty() => {
+            return tags.iter().any(|tag| tag.weak_eq(etag));
+        },
+        _ => {},
+    }
+
+    match IfModifiedSince::parse(req) {
+        Ok(IfModifiedSince(since)) => last_modified <= SystemTime::from(since),
+        Err(_) => false,
+    }
+}
+
+/// 9999-12-31T23:59:59.999Z
+const MAX_DATE_MS: i64 = 253_402_300_799_999;
+
+/// Items may claim any timestamp, but HTTP dates can't represent times before 1970, and neither they
+/// nor RFC 3339 can represent times after 9999. (The libraries that format them panic.)
+/// So, clamp timestamps into that range before formatting them.
+fn clamp_date(timestamp: Timestamp) -> i64 {
+    timestamp.unix_utc_ms.clamp(0, MAX_DATE_MS)
+}
+
+fn to_system_time(timestamp: Timestamp) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(clamp_date(timestamp) as u64)
+}
+
+pub(super) fn rfc3339(timestamp: Timestamp) -> String {
+    use time::{Format, OffsetDateTime};
+    OffsetDateTime::from_unix_timestamp(clamp_date(timestamp) / 1000).format(Format::Rfc3339)
+}
+
+/// Escape text for use in XML (or HTML) content or attributes.
+pub(super) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_dates() {
+        let date = |unix_utc_ms| {
+            let timestamp = Timestamp{ unix_utc_ms };
+            (rfc3339(timestamp), HttpDate::from(to_system_time(timestamp)).to_string())
+        };
+
+        let epoch = ("1970-01-01T00:00:00+00:00".to_string(), "Thu, 01 Jan 1970 00:00:00 GMT".to_string());
+        assert_eq!(epoch, date(-1));
+        assert_eq!(epoch, date(i64::MIN));
+
+        let max = ("9999-12-31T23:59:59+00:00".to_string(), "Fri, 31 Dec 9999 23:59:59 GMT".to_string());
+        assert_eq!(max, date(MAX_DATE_MS + 1));
+        assert_eq!(max, date(i64::MAX));
+
+        assert_eq!(("2023-11-14T22:13:20+00:00".to_string(), "Tue, 14 Nov 2023 22:13:20 GMT".to_string()), date(1_700_000_000_000));
+    }
+}
@@ -1,8 +1,18 @@
 //! Endpoints that serve plain HTML web pages.
 
-use actix_web::{web::{self, get, route, ServiceConfig}, HttpResponse};
+use std::fmt::Write;
 
-use super::Error;
+use actix_web::{web::{self, get, route, Data, Path, ServiceConfig}, HttpRequest, HttpResponse};
+use mime_guess::mime;
+use protobuf::Message;
+use url::Url;
+
+use crate::{backend::{Signature, UserID}, markdown, protos::Item};
+
+use super::{AppData, Error, feeds::{escape, origin, rfc3339}};
+
+/// Longest og:description we'll generate.
+const MAX_DESCRIPTION_CHARS: usize = 200;
 
 
 pub fn routes(cfg: &mut ServiceConfig) {
@@ -51,4 +61,121 @@ pub async fn api_docs_link() -> HttpResponse {
     HttpResponse::Ok()
         .append_header(("content-type", "text/html"))
         .body(body)
-}
\ No newline at end of file
+}
+
+
+/// A minimal server-rendered view of an Item.
+///
+/// This is mostly for link previews, via the Open Graph Protocol. Chat apps and
+/// social networks won't run the web client's JavaScript to find out what a link is.
+///
+/// `/diskuto/users/{userID}/items/{signature}.html`
+pub async fn item_preview(
+    data: Data<AppData>,
+    path: Path<(UserID, Signature)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (user_id, signature) = path.into_inner();
+    let backend = data.backend_factory.open()?;
+    let row = match backend.user_item(&user_id, &signature)? {
+        Some(row) => row,
+        None => return not_found().await,
+    };
+    let mut item = Item::new();
+    item.merge_from_bytes(&row.item_bytes)?;
+
+    let mut author = user_id.to_base58();
+    if let Some(profile) = backend.user_profile(&user_id)? {
+        let mut profile_item = Item::new();
+        profile_item.merge_from_bytes(&profile.item_bytes)?;
+        let display_name = profile_item.profile().display_name.trim();
+        if !display_name.is_empty() {
+            author = display_name.to_string();
+        }
+    }
+
+    // Links into the web client, using the standard URL layout. (See: docs/url_layout.md)
+    let origin = origin(&req)?;
+    let page = origin.join(&format!("u/{}/i/{}/", user_id, signature.to_base58()))?;
+    let profile_page = origin.join(&format!("u/{}/", user_id))?;
+
+    use crate::protos::item::Item_type::*;
+    let (title, markdown, image) = match &item.item_type {
+        Some(Post(post)) => {
+            let title = match post.title.trim() {
+                "" => format!("Post by {}", author),
+                title => title.to_string(),
+            };
+            let image = post.attachments.file.iter()
+                .find(|file| {
+                    let mime_type = data.config.attachments.mime_type(&file.name);
+                    // Link previews don't show SVGs:
+                    mime_type.type_() == mime::IMAGE && mime_type.subtype() != mime::SVG
+                })
+                .map(|file| file_url(&origin, &user_id, &signature, &file.name))
+                .transpose()?;
+            (title, post.body.as_str(), image)
+        },
+        Some(Comment(comment)) => (format!("Comment by {}", author), comment.text.as_str(), None),
+        Some(Profile(profile)) => (author.clone(), profile.about.as_str(), None),
+        None => return not_found().await,
+    };
+    let image = match image {
+        Some(image) => image,
+        None => origin.join(&format!("diskuto/users/{}/icon.png", user_id))?,
+    };
+
+    let description = markdown::to_text(markdown, MAX_DESCRIPTION_CHARS);
+    let published = rfc3339(row.timestamp);
+
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>")?;
+    writeln!(html, "<html>")?;
+    writeln!(html, "<head>")?;
+    writeln!(html, r#"<meta charset="utf-8">"#)?;
+    writeln!(html, r#"<meta name="viewport" content="width=device-width, initial-scale=1">"#)?;
+    writeln!(html, "<title>{}</title>", escape(&title))?;
+    writeln!(html, r#"<link rel="canonical" href="{}">"#, escape(page.as_str()))?;
+    writeln!(html, r#"<meta name="description" content="{}">"#, escape(&description))?;
+    writeln!(html, r#"<meta property="og:type" content="article">"#)?;
+    writeln!(html, r#"<meta property="og:title" content="{}">"#, escape(&title))?;
+    writeln!(html, r#"<meta property="og:description" content="{}">"#, escape(&description))?;
+    writeln!(html, r#"<meta property="og:image" content="{}">"#, escape(image.as_str()))?;
+    writeln!(html, r#"<meta property="og:url" content="{}">"#, escape(page.as_str()))?;
+    writeln!(html, r#"<meta property="article:published_time" content="{}">"#, published)?;
+    writeln!(html, r#"<meta name="twitter:card" content="summary">"#)?;
+    writeln!(html, "<style>body {{ max-width: 40em; margin: auto; padding: 1em; font-family: sans-serif; }} img {{ max-width: 100%; }}</style>")?;
+    writeln!(html, "</head>")?;
+    writeln!(html, "<body>")?;
+    writeln!(html, "<article>")?;
+    writeln!(html, "<h1>{}</h1>", escape(&title))?;
+    writeln!(
+        html,
+        r#"<p>By <a href="{}">{}</a>, <time datetime="{}">{}</time></p>"#,
+        escape(profile_page.as_str()),
+        escape(&author),
+        published,
+        published,
+    )?;
+    writeln!(html, "{}", markdown::to_html(markdown, Some(&page)))?;
+    writeln!(html, "</article>")?;
+    writeln!(html, r#"<p><a href="{}">View this in the Diskuto web client</a></p>"#, escape(page.as_str()))?;
+    writeln!(html, "</body>")?;
+    writeln!(html, "</html>")?;
+
+    Ok(
+        HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+    )
+}
+
+/// The REST URL for a file attachment.
+fn file_url(origin: &Url, user_id: &UserID, signature: &Signature, file_name: &str) -> Result<Url, Error> {
+    let mut url = origin.join(&format!("diskuto/users/{}/items/{}/files/", user_id, signature.to_base58()))?;
+    url.path_segments_mut()
+        .map_err(|_| "Not a base URL")?
+        .pop_if_empty()
+        .push(file_name);
+    Ok(url)
+}
@@ -0,0 +1,132 @@
+//! JSON versions of our protobuf responses.
+//!
+//! Clients can ask for JSON with `Accept: application/json`, or `?format=json`.
+//! Messages use the canonical proto3 JSON mapping, plus base58 user IDs and
+//! signatures, since that's how they're displayed everywhere else.
+//!
+//! Item signatures cover the exact protobuf bytes, which can't be recreated
+//! from JSON. So responses with an Item also include its bytes, as base64.
+
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, http::header::{self, Header}, web::Query};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use protobuf::MessageDyn;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{backend::ItemRow, protos::{Item, ItemList, ItemListEntry}};
+
+use super::Error;
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    format: Option<String>,
+}
+
+/// Did the client ask for JSON instead of protobuf?
+pub(crate) fn wants_json(req: &HttpRequest) -> bool {
+    // An explicit ?format= wins:
+    if let Ok(query) = Query::<FormatQuery>::from_query(req.query_string()) {
+        if let Some(format) = &query.format {
+            return format == "json";
+        }
+    }
+
+    let accept = match header::Accept::parse(req) {
+        Ok(accept) => accept,
+        Err(_) => return false,
+    };
+    for mime in accept.ranked() {
+        match mime.essence_str() {
+            "application/json" => return true,
+            // Anything that includes protobuf, which is our default:
+            "application/protobuf3" | "application/*" | "*/*" => return false,
+            _ => {},
+        }
+    }
+    false
+}
+
+/// Start building a response w/ JSON data.
+pub(crate) fn json_ok() -> HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/json");
+    builder.insert_header((header::VARY, "Accept"));
+    builder
+}
+
+/// An Item and its signed bytes.
+pub(crate) fn item(row: &ItemRow, item: &Item) -> Result<Value, Error> {
+    Ok(json!({
+        "userIdBase58": row.user.to_base58(),
+        "signatureBase58": row.signature.to_base58(),
+        "itemBytes": BASE64.encode(&row.item_bytes),
+        "item": proto(item)?,
+    }))
+}
+
+pub(crate) fn item_list(list: &ItemList) -> Result<Value, Error> {
+    let mut value = proto(list)?;
+
+    if let Some(entries) = value["items"].as_array_mut() {
+        for (entry, json_entry) in list.items.iter().zip(entries) {
+            add_base58_ids(entry, json_entry);
+        }
+    }
+
+    Ok(value)
+}
+
+pub(crate) fn item_list_entry(entry: &ItemListEntry) -> Result<Value, Error> {
+    let mut value = proto(entry)?;
+    add_base58_ids(entry, &mut value);
+    Ok(value)
+}
+
+fn add_base58_ids(entry: &ItemListEntry, json_entry: &mut Value) {
+    json_entry["userIdBase58"] = json!(bs58::encode(&entry.user_id.bytes).into_string());
+    json_entry["signatureBase58"] = json!(bs58::encode(&entry.signature.bytes).into_string());
+}
+
+/// The canonical JSON mapping of a protobuf message.
+fn proto(message: &dyn MessageDyn) -> Result<Value, Error> {
+    let json = protobuf_json_mapping::print_to_string(message)?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::{EnumOrUnknown, MessageField};
+
+    use crate::protos::{ItemType, Signature, UserID};
+
+    use super::*;
+
+    #[test]
+    fn item_list_ids() {
+        let mut entry = ItemListEntry::new();
+        entry.user_id = MessageField::some({
+            let mut uid = UserID::new();
+            uid.bytes = vec![0, 0, 1];
+            uid
+        });
+        entry.signature = MessageField::some({
+            let mut sig = Signature::new();
+            sig.bytes = vec![2, 3];
+            sig
+        });
+        entry.timestamp_ms_utc = 1234;
+        entry.item_type = EnumOrUnknown::new(ItemType::POST);
+
+        let mut list = ItemList::new();
+        list.items.push(entry);
+
+        let value = item_list(&list).unwrap();
+        let entry = &value["items"][0];
+        assert_eq!(entry["userIdBase58"], json!("112"));
+        assert_eq!(entry["signatureBase58"], json!("9t"));
+        assert_eq!(entry["userId"]["bytes"], json!("AAAB"));
+        // int64s are strings in the canonical mapping:
+        assert_eq!(entry["timestampMsUtc"], json!("1234"));
+        assert_eq!(entry["itemType"], json!("POST"));
+    }
+}
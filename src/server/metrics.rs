@@ -0,0 +1,279 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! Only served with `diskuto serve --metrics`, or on a separate admin listener
+//! with `--metrics-bind`. Metrics are always collected, since it's cheap.
+
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+use actix_web::{HttpResponse, body::MessageBody, dev::{Service, ServiceRequest, ServiceResponse}, web::{Data, ServiceConfig, get}};
+use futures::Future;
+use log::warn;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Counter, Opts, Registry, TextEncoder};
+
+use crate::backend::Factory;
+
+use super::{AppData, Error};
+
+/// `usage_by_user` scans everything, so don't run it on every scrape.
+const DB_USAGE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The result of a PUT of an Item.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ItemPut {
+    Created,
+    Exists,
+    /// The user isn't known to this server.
+    Forbidden,
+    /// The user is over their quota.
+    Quota,
+    InvalidSignature,
+    /// Any other rejected Item. (Too large, invalid, etc.)
+    Invalid,
+}
+
+impl ItemPut {
+    fn as_str(self) -> &'static str {
+        use ItemPut::*;
+        match self {
+            Created => "created",
+            Exists => "exists",
+            Forbidden => "forbidden",
+            Quota => "quota",
+            InvalidSignature => "invalid_signature",
+            Invalid => "invalid",
+        }
+    }
+}
+
+pub(crate) struct Metrics {
+    registry: Registry,
+
+    requests: IntCounterVec,
+    request_seconds: HistogramVec,
+    item_puts: IntCounterVec,
+    attachment_bytes: IntCounterVec,
+
+    pool_connections: IntGauge,
+    pool_idle_connections: IntGauge,
+    pool_max_size: IntGauge,
+    pool_gets: IntCounter,
+    pool_wait_seconds: Counter,
+
+    db_bytes: IntGaugeVec,
+    db_users: IntGauge,
+    db_usage_updated: Mutex<Option<Instant>>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("diskuto_http_requests_total", "HTTP requests, by route."),
+            &["route", "method", "status"],
+        )?;
+        let request_seconds = HistogramVec::new(
+            HistogramOpts::new("diskuto_http_request_duration_seconds", "Time to handle HTTP requests, by route. (Excludes streaming the response body.)"),
+            &["route", "method"],
+        )?;
+        let item_puts = IntCounterVec::new(
+            Opts::new("diskuto_item_puts_total", "Item uploads, by outcome."),
+            &["outcome"],
+        )?;
+        let attachment_bytes = IntCounterVec::new(
+            Opts::new("diskuto_attachment_bytes_total", "Attachment bytes uploaded (in) and served (out)."),
+            &["direction"],
+        )?;
+
+        let pool_connections = IntGauge::new("diskuto_db_pool_connections", "Open database connections, including idle ones.")?;
+        let pool_idle_connections = IntGauge::new("diskuto_db_pool_idle_connections", "Idle database connections.")?;
+        let pool_max_size = IntGauge::new("diskuto_db_pool_max_size", "The maximum number of database connections.")?;
+        let pool_gets = IntCounter::new("diskuto_db_pool_gets_total", "Database connections taken from the pool.")?;
+        let pool_wait_seconds = Counter::new("diskuto_db_pool_wait_seconds_total", "Time spent waiting for database connections from the pool.")?;
+
+        let db_bytes = IntGaugeVec::new(
+            Opts::new("diskuto_db_bytes", "Bytes stored for all users. (Updated every few minutes.)"),
+            &["kind"],
+        )?;
+        let db_users = IntGauge::new("diskuto_db_users", "Users with data stored on this server. (Updated every few minutes.)")?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_seconds.clone()))?;
+        registry.register(Box::new(item_puts.clone()))?;
+        registry.register(Box::new(attachment_bytes.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_idle_connections.clone()))?;
+        registry.register(Box::new(pool_max_size.clone()))?;
+        registry.register(Box::new(pool_gets.clone()))?;
+        registry.register(Box::new(pool_wait_seconds.clone()))?;
+        registry.register(Box::new(db_bytes.clone()))?;
+        registry.register(Box::new(db_users.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_seconds,
+            item_puts,
+            attachment_bytes,
+            pool_connections,
+            pool_idle_connections,
+            pool_max_size,
+            pool_gets,
+            pool_wait_seconds,
+            db_bytes,
+            db_users,
+            db_usage_updated: Mutex::new(None),
+        })
+    }
+
+    pub fn item_put(&self, outcome: ItemPut) {
+        self.item_puts.with_label_values(&[outcome.as_str()]).inc();
+    }
+
+    pub fn attachment_bytes_in(&self, bytes: u64) {
+        self.attachment_bytes.with_label_values(&["in"]).inc_by(bytes);
+    }
+
+    pub fn attachment_bytes_out(&self, bytes: u64) {
+        self.attachment_bytes.with_label_values(&["out"]).inc_by(bytes);
+    }
+
+    fn update_pool(&self, factory: &dyn Factory) {
+        let stats = factory.pool_stats();
+        self.pool_connections.set(stats.connections.into());
+        self.pool_idle_connections.set(stats.idle_connections.into());
+        self.pool_max_size.set(stats.max_size.into());
+        // Counters can't be set, so add whatever's new since the last scrape:
+        self.pool_gets.inc_by(stats.gets.saturating_sub(self.pool_gets.get()));
+        self.pool_wait_seconds.inc_by((stats.wait.as_secs_f64() - self.pool_wait_seconds.get()).max(0.0));
+    }
+
+    /// Whether it's time to update DB usage. Assumes that we will.
+    fn db_usage_due(&self) -> bool {
+        let mut updated = self.db_usage_updated.lock().expect("db_usage_updated lock");
+        let due = match *updated {
+            None => true,
+            Some(at) => at.elapsed() >= DB_USAGE_INTERVAL,
+        };
+        if due {
+            *updated = Some(Instant::now());
+        }
+        due
+    }
+
+    fn update_db_usage(&self, factory: &dyn Factory) -> Result<(), anyhow::Error> {
+        let (mut users, mut items, mut attachments, mut total) = (0, 0, 0, 0);
+        factory.open()?.usage_by_user(&mut |row| {
+            users += 1;
+            items += row.items_bytes;
+            attachments += row.attachments_bytes;
+            total += row.total_bytes;
+            Ok(true)
+        })?;
+
+        self.db_users.set(users);
+        self.db_bytes.with_label_values(&["items"]).set(items as i64);
+        self.db_bytes.with_label_values(&["attachments"]).set(attachments as i64);
+        self.db_bytes.with_label_values(&["total"]).set(total as i64);
+        Ok(())
+    }
+}
+
+pub(crate) fn routes(cfg: &mut ServiceConfig) {
+    cfg.route("/metrics", get().to(metrics));
+}
+
+async fn metrics(data: Data<AppData>) -> Result<HttpResponse, Error> {
+    let metrics = &data.metrics;
+    metrics.update_pool(data.backend_factory.as_ref());
+
+    if metrics.db_usage_due() {
+        let factory = data.backend_factory.dyn_clone();
+        let metrics = metrics.clone();
+        let updated = blocking::unblock(move || metrics.update_db_usage(factory.as_ref())).await;
+        if let Err(err) = updated {
+            warn!("Couldn't update DB usage metrics: {:?}", err);
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = vec![];
+    encoder.encode(&metrics.registry.gather(), &mut body)?;
+
+    Ok(
+        HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+    )
+}
+
+/// Middleware that counts and times requests.
+pub(crate) fn record<S, B>(req: ServiceRequest, srv: &S)
+-> impl Future<Output = Result<ServiceResponse<B>, S::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>>,
+    B: MessageBody,
+{
+    let start = Instant::now();
+    // Label by route pattern, not path, to keep the number of series bounded:
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+    let method = req.method().to_string();
+    let metrics = req.app_data::<Data<AppData>>().map(|data| data.metrics.clone());
+    let response = srv.call(req);
+
+    async move {
+        let response = response.await?;
+        if let Some(metrics) = metrics {
+            let status = response.status().as_u16().to_string();
+            metrics.requests.with_label_values(&[&route, &method, &status]).inc();
+            metrics.request_seconds.with_label_values(&[&route, &method]).observe(start.elapsed().as_secs_f64());
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test};
+
+    use crate::server::test_util::{TestDb, post, sign_item};
+    use super::*;
+
+    #[actix_web::test]
+    async fn counts_requests() {
+        let db = TestDb::new();
+        let user = db.new_user();
+        let app = test::init_service(
+            App::new()
+            .app_data(Data::new(db.app_data()))
+            .wrap_fn(record)
+            .configure(super::super::api_routes)
+            .configure(routes)
+        ).await;
+
+        let req = test::TestRequest::get().uri("/diskuto/homepage").to_request();
+        assert_eq!(200, test::call_service(&app, req).await.status());
+
+        let (row, signature) = sign_item(&user, &post(100, "Hello"));
+        let req = test::TestRequest::put()
+            .uri(&format!("/diskuto/users/{}/items/{}", user.0, signature.to_base58()))
+            .insert_header(("content-length", row.item_bytes.len()))
+            .set_payload(row.item_bytes)
+            .to_request();
+        assert_eq!(201, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let value = |series: &str| -> f64 {
+            let line = body.lines().find(|line| line.starts_with(series)).unwrap_or_else(|| panic!("No {} in:\n{}", series, body));
+            line[series.len()..].trim().parse().unwrap()
+        };
+        assert_eq!(1.0, value(r#"diskuto_http_requests_total{method="GET",route="/diskuto/homepage",status="200"}"#));
+        assert_eq!(1.0, value(r#"diskuto_http_requests_total{method="PUT",route="/diskuto/users/{userID}/items/{signature}",status="201"}"#));
+        assert_eq!(1.0, value(r#"diskuto_item_puts_total{outcome="created"}"#));
+        assert!(value("diskuto_db_pool_gets_total ") >= 2.0);
+        assert!(value("diskuto_db_pool_connections ") >= 1.0);
+        assert_eq!(1.0, value("diskuto_db_users "));
+    }
+}
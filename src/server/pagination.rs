@@ -37,6 +37,11 @@ where
     pub params: Pagination,
     pub max_items: usize,
 
+    /// If set, stop once the items' sizes add up to more than this many bytes.
+    max_bytes: Option<usize>,
+    size: fn(&T) -> usize,
+    bytes: usize,
+
     mapper: Mapper,
     filter: Filter,
     have_flipped: bool,
@@ -58,13 +63,22 @@ where
             return Ok(true); // continue
         }
 
-        if self.items.len() >= max_len {
+        let over_budget = self.max_bytes.is_some_and(|max_bytes| self.bytes > max_bytes);
+        if self.items.len() >= max_len || over_budget {
             self.has_more = true;
             return Ok(false); // stop
         }
 
+        self.bytes += (self.size)(&item);
         self.items.push(item);
-        return Ok(true)
+        Ok(true)
+    }
+
+    /// Limit the page by the total size of its items, as measured by `size`.
+    /// The item that goes over `max_bytes` is still included, so each page has at least one.
+    pub fn limit_bytes(&mut self, max_bytes: usize, size: fn(&T) -> usize) {
+        self.max_bytes = Some(max_bytes);
+        self.size = size;
     }
 
     pub fn callback<'a>(&'a mut self) -> impl FnMut(In) -> Result<bool, E> + 'a {
@@ -80,6 +94,9 @@ where
             items: vec![],
             // Seems like a reasonable sane default for things that have to hold Item in memory:
             max_items: 100,
+            max_bytes: None,
+            size: |_| 0,
+            bytes: 0,
             has_more: false,
             mapper,
             filter,
@@ -93,7 +110,7 @@ where
     /// Prefer time_span() if bidirectional pagination is supported.
     /// TODO: Deprecate pagination by "before" only:
     pub fn before(&self) -> Timestamp {
-        self.params.before.map(|t| Timestamp{ unix_utc_ms: t}).unwrap_or_else(|| Timestamp::now())
+        self.params.before.map(|t| Timestamp{ unix_utc_ms: t}).unwrap_or_else(Timestamp::now)
     }
 
     /// The time span we should display for the current request:
@@ -0,0 +1,353 @@
+//! Token-bucket rate limits for uploads and expensive reads.
+//!
+//! Each client IP address (and, for PUTs, each user ID) gets a bucket which
+//! holds up to one minute's worth of tokens, and refills continuously.
+//! Requests that would overdraw a bucket get a `429 Too Many Requests`, with a
+//! `Retry-After` header saying when there will be enough tokens.
+//!
+//! A user's bucket is only charged once their upload has succeeded. Otherwise,
+//! anyone could use up a user's limit by sending junk to their URLs.
+//!
+//! Limits are set in the `[rate_limits]` section of the config file. See: [`super::config`]
+
+use std::{collections::HashMap, net::IpAddr, sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use actix_web::{HttpResponse, dev::{Service, ServiceRequest, ServiceResponse}, http::{Method, StatusCode, header}, web::Data};
+use futures::{Future, future::Either};
+
+use crate::backend::UserID;
+
+use super::{AppData, PLAINTEXT, config::RateLimitsConfig};
+
+/// Forget buckets (which would be full anyway) at most this often.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub(crate) struct RateLimits {
+    item_puts: Option<Limiter>,
+    attachment_bytes: Option<Limiter>,
+    list_gets: Option<Limiter>,
+    inbox_posts: Option<Limiter>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitsConfig) -> Self {
+        Self {
+            item_puts: Limiter::new(config.item_puts_per_minute),
+            attachment_bytes: Limiter::new(config.attachment_bytes_per_minute),
+            list_gets: Limiter::new(config.list_gets_per_minute),
+            inbox_posts: Limiter::new(config.inbox_posts_per_minute),
+            trusted_proxies: config.trusted_proxies.clone(),
+        }
+    }
+
+    /// The IP address of the client that sent this request.
+    ///
+    /// X-Forwarded-For is only believed when it's added by one of our trusted proxies.
+    /// In that case, the client is the last address that wasn't one of them.
+    fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+
+        let mut client = peer;
+        let forwarded = req.headers().get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for addr in forwarded.into_iter().rev() {
+            match addr.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.trusted_proxies.contains(&ip) {
+                        break;
+                    }
+                },
+                // Can't trust anything to the left of garbage:
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    User(UserID),
+}
+
+struct Limiter {
+    /// Tokens added per second.
+    rate: f64,
+    /// The most tokens a bucket can hold.
+    capacity: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    buckets: HashMap<Key, Bucket>,
+    pruned: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// May be negative, if a single request cost more than the capacity.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Limiter {
+    fn new(per_minute: u64) -> Option<Self> {
+        if per_minute == 0 {
+            return None;
+        }
+        Some(Self {
+            rate: per_minute as f64 / 60.0,
+            capacity: per_minute as f64,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        })
+    }
+
+    /// Take `cost` tokens from every key's bucket, or none of them.
+    /// The `check` keys must also have enough tokens, but aren't charged. (See: [`Self::charge`])
+    /// If there aren't enough, returns how long until there will be.
+    fn take(&self, keys: &[Key], check: &[Key], cost: u64, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.lock(now);
+
+        // Requests larger than a bucket are allowed once it's full, and leave it in debt:
+        let needed = (cost as f64).min(self.capacity);
+        let mut wait = Duration::ZERO;
+        for key in keys.iter().chain(check) {
+            let tokens = match buckets.buckets.get(key) {
+                Some(bucket) => bucket.refilled(self.rate, self.capacity, now).tokens,
+                None => self.capacity,
+            };
+            if tokens < needed {
+                wait = wait.max(Duration::from_secs_f64((needed - tokens) / self.rate));
+            }
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+
+        for key in keys {
+            let bucket = buckets.buckets.entry(key.clone()).or_insert(Bucket{ tokens: self.capacity, updated: now });
+            *bucket = bucket.refilled(self.rate, self.capacity, now);
+            bucket.tokens -= cost as f64;
+        }
+        Ok(())
+    }
+
+    /// Take `cost` tokens from a key's bucket, even if that leaves it in debt.
+    fn charge(&self, key: &Key, cost: u64, now: Instant) {
+        let mut buckets = self.lock(now);
+        let bucket = buckets.buckets.entry(key.clone()).or_insert(Bucket{ tokens: self.capacity, updated: now });
+        *bucket = bucket.refilled(self.rate, self.capacity, now);
+        bucket.tokens -= cost as f64;
+    }
+
+    fn lock(&self, now: Instant) -> MutexGuard<'_, Buckets> {
+        let mut buckets = self.buckets.lock().expect("rate limit buckets lock");
+        if now.saturating_duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            let (rate, capacity) = (self.rate, self.capacity);
+            buckets.buckets.retain(|_, bucket| bucket.refilled(rate, capacity, now).tokens < capacity);
+            buckets.pruned = now;
+        }
+        buckets
+    }
+}
+
+impl Bucket {
+    fn refilled(self, rate: f64, capacity: f64, now: Instant) -> Self {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        Self {
+            tokens: (self.tokens + elapsed * rate).min(capacity),
+            updated: now,
+        }
+    }
+}
+
+/// Middleware limiting Item PUTs.
+pub(crate) fn item_puts<S>(req: ServiceRequest, srv: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    limit(req, srv, &[Method::PUT], |limits| limits.item_puts.as_ref(), |_| Some(1))
+}
+
+/// Middleware limiting attachment PUTs (and resumable PATCHes), by their size.
+pub(crate) fn attachment_puts<S>(req: ServiceRequest, srv: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    limit(req, srv, &[Method::PUT, Method::PATCH], |limits| limits.attachment_bytes.as_ref(), |req| {
+        // put_file() rejects uploads without a Content-Length anyway.
+        // patch_file() doesn't, but we can't tell what a chunked PATCH will cost.
+        req.headers().get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    })
+}
+
+/// Middleware limiting GETs of lists and feeds. (And POSTs for batches of items.)
+pub(crate) fn list_gets<S>(req: ServiceRequest, srv: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    limit(req, srv, &[Method::GET, Method::POST], |limits| limits.list_gets.as_ref(), |_| Some(1))
+}
+
+/// Middleware limiting POSTs to ActivityPub inboxes.
+pub(crate) fn inbox_posts<S>(req: ServiceRequest, srv: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    limit(req, srv, &[Method::POST], |limits| limits.inbox_posts.as_ref(), |_| Some(1))
+}
+
+type LimiterFn = fn(&RateLimits) -> Option<&Limiter>;
+
+fn limit<S>(
+    req: ServiceRequest,
+    srv: &S,
+    methods: &[Method],
+    limiter: LimiterFn,
+    cost: impl Fn(&ServiceRequest) -> Option<u64>,
+) -> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    let checked = match req.app_data::<Data<AppData>>() {
+        Some(data) if methods.contains(req.method()) => check(&data.rate_limits, &req, limiter, cost),
+        _ => Ok(None),
+    };
+
+    let pending = match checked {
+        Ok(pending) => pending,
+        Err(response) => return Either::Right(futures::future::ok(req.into_response(response))),
+    };
+
+    let response = srv.call(req);
+    Either::Left(async move {
+        let response = response.await?;
+        if let Some(pending) = pending {
+            pending.settle(&response);
+        }
+        Ok(response)
+    })
+}
+
+fn check(
+    limits: &Arc<RateLimits>,
+    req: &ServiceRequest,
+    limiter: LimiterFn,
+    cost: impl Fn(&ServiceRequest) -> Option<u64>,
+) -> Result<Option<Pending>, HttpResponse> {
+    let limiter_ref = match limiter(limits) {
+        Some(limiter) => limiter,
+        None => return Ok(None),
+    };
+
+    let cost = match cost(req) {
+        Some(cost) => cost,
+        None => return Err(
+            HttpResponse::LengthRequired()
+            .content_type(PLAINTEXT)
+            .body("Must include a Content-Length header.")
+        ),
+    };
+
+    let ips = limits.client_ip(req).map(Key::Ip).into_iter().collect::<Vec<_>>();
+    let mut users = vec![];
+    if req.method() == Method::PUT || req.method() == Method::PATCH {
+        // Item routes use {userID}, attachment routes use {user_id}:
+        let user = req.match_info().get("userID").or_else(|| req.match_info().get("user_id"));
+        if let Some(Ok(user)) = user.map(UserID::from_base58) {
+            users.push(Key::User(user));
+        }
+    }
+
+    limiter_ref.take(&ips, &users, cost, Instant::now()).map_err(too_many_requests)?;
+
+    Ok(users.pop().map(|user| Pending{
+        limits: limits.clone(),
+        limiter,
+        user,
+        cost,
+    }))
+}
+
+fn too_many_requests(wait: Duration) -> HttpResponse {
+    // Round up, so that clients don't come back too early:
+    let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+    HttpResponse::TooManyRequests()
+        .content_type(PLAINTEXT)
+        .insert_header((header::RETRY_AFTER, secs.to_string()))
+        .body(format!("Rate limit exceeded. Try again in {} seconds.", secs))
+}
+
+/// Tokens to take from a user's bucket, once we know whether their upload succeeded.
+struct Pending {
+    limits: Arc<RateLimits>,
+    limiter: LimiterFn,
+    user: Key,
+    cost: u64,
+}
+
+impl Pending {
+    fn settle(self, response: &ServiceResponse) {
+        // 202 Accepted means the item/file already existed. Anyone could re-send those.
+        let saved = matches!(response.status(), StatusCode::CREATED | StatusCode::NO_CONTENT);
+        if !saved {
+            return;
+        }
+        if let Some(limiter) = (self.limiter)(&self.limits) {
+            limiter.charge(&self.user, self.cost, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limiter = Limiter::new(60).unwrap(); // 1/sec
+        let start = Instant::now();
+        let ip = [Key::Ip("10.0.0.1".parse().unwrap())];
+        let other = [Key::Ip("10.0.0.2".parse().unwrap())];
+
+        // A full minute's worth may be used at once:
+        assert_eq!(limiter.take(&ip, &[], 60, start), Ok(()));
+        assert_eq!(limiter.take(&ip, &[], 1, start), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.take(&other, &[], 1, start), Ok(()));
+
+        // ... and refills over time:
+        let later = start + Duration::from_secs(5);
+        assert_eq!(limiter.take(&ip, &[], 5, later), Ok(()));
+        assert_eq!(limiter.take(&ip, &[], 1, later), Err(Duration::from_secs(1)));
+
+        // All keys must have enough tokens, and none are taken if one doesn't:
+        let both = [other[0].clone(), ip[0].clone()];
+        assert!(limiter.take(&both, &[], 1, later).is_err());
+        assert_eq!(limiter.take(&other, &[], 60, later), Ok(()));
+
+        // Something larger than the bucket is allowed once it's full, but leaves it in debt:
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(limiter.take(&ip, &[], 120, much_later), Ok(()));
+        assert_eq!(limiter.take(&ip, &[], 1, much_later), Err(Duration::from_secs(61)));
+
+        // Checked keys must have enough tokens, but only pay when charged:
+        let user = [Key::User(UserID::from_vec(vec![1; 32]).unwrap())];
+        assert_eq!(limiter.take(&other, &user, 60, much_later), Ok(()));
+        assert_eq!(limiter.take(&[], &user, 60, much_later), Ok(()));
+        limiter.charge(&user[0], 60, much_later);
+        assert_eq!(limiter.take(&[], &user, 1, much_later), Err(Duration::from_secs(1)));
+    }
+}
@@ -2,47 +2,121 @@
 //!
 //! Note: some endpoints are in attachments.rs, since they're used by both REST & HTML views.
 
-use actix_web::{HttpRequest, HttpResponse, web::{Data, Path, Payload, Query}, HttpResponseBuilder};
+use std::collections::HashMap;
+
+use actix_web::{HttpRequest, HttpResponse, http::header, web::{Data, Path, Payload, Query}, HttpResponseBuilder};
 use anyhow::{Context, format_err};
 use futures::StreamExt;
 use logging_timer::timer;
 use protobuf::{EnumOrUnknown, Message, MessageField};
+use serde::Deserialize;
+
+use crate::{backend::{ItemDisplayRow, ItemRow, Signature, Timestamp, UserID}, markdown, protos::{Item, ItemBatch, ItemBatchRequest, ItemList, ItemListEntry, ItemType, ProtoValid, SignedItem}, server::{PLAINTEXT, UnsafeMarkdownPolicy}};
+
+use super::{AppData, Error, activitypub, json, metrics::ItemPut, webhooks, pagination::{Pagination, Paginator}, attachments::drain};
 
-use crate::{backend::{ItemDisplayRow, ItemRow, Signature, Timestamp, UserID}, protos::{Item, ItemList, ItemListEntry, ItemType, ProtoValid}, server::{MAX_ITEM_SIZE, PLAINTEXT}};
 
-use super::{AppData, Error, pagination::{Pagination, Paginator}, attachments::drain};
+/// Query params that control what's in an ItemList. (See also: Pagination)
+#[derive(Deserialize, Debug)]
+pub(crate) struct ListOptions {
+    /// A comma-separated list of extras to include in each ItemListEntry.
+    /// Currently only `items`, which fills ItemListEntry.bytes.
+    include: Option<String>,
 
+    /// A comma-separated list of item types to list. ex: `post,comment`
+    types: Option<String>,
+}
+
+impl ListOptions {
+    fn include_items(&self) -> bool {
+        self.include.as_deref().is_some_and(|include| include.split(',').any(|i| i.trim() == "items"))
+    }
+
+    /// The ItemTypes to list, or `default` if none were given.
+    /// Returns a 400 response if `types` names a type we don't know.
+    fn item_types(&self, default: &[ItemType]) -> Result<Vec<ItemType>, HttpResponse> {
+        let types = match &self.types {
+            None => return Ok(default.to_vec()),
+            Some(types) => types,
+        };
+        let mut item_types = vec![];
+        for name in types.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match <ItemType as protobuf::Enum>::from_str(&name.to_uppercase()) {
+                Some(ItemType::UNKNOWN) | None => return Err(
+                    HttpResponse::BadRequest()
+                    .content_type(PLAINTEXT)
+                    .body(format!("Unknown item type: {}", name))
+                ),
+                Some(item_type) => item_types.push(item_type),
+            }
+        }
+        Ok(item_types)
+    }
+
+    /// Limit the size of a page, depending on what's in its entries.
+    fn limit<In, E, Mapper, Filter>(&self, data: &AppData, paginator: &mut Paginator<ItemListEntry, In, E, Mapper, Filter>)
+    where
+        Mapper: Fn(In) -> Result<ItemListEntry, E>,
+        Filter: Fn(&ItemListEntry) -> bool,
+    {
+        let limits = &data.config.limits;
+        if self.include_items() {
+            // Then we're holding whole Items in memory, so stick to the Paginator's default.
+            paginator.max_items = paginator.max_items.min(limits.max_list_items);
+            paginator.limit_bytes(limits.max_list_bytes, |entry| entry.bytes.len());
+        } else {
+            // We're only holding ItemListEntries in memory, so we can up this limit and save some round trips.
+            paginator.max_items = limits.max_list_items;
+        }
+    }
+}
+
+/// Query params for a user's feed.
+#[derive(Deserialize, Debug)]
+pub(crate) struct FeedOptions {
+    /// Only list items from follows in the FollowGroup with this name.
+    group: Option<String>,
+}
+
+/// An ItemListEntry for an ItemRow, with its bytes if they were asked for.
+fn row_to_entry(row: ItemRow, include_items: bool) -> Result<ItemListEntry, anyhow::Error> {
+    let mut item = Item::new();
+    item.merge_from_bytes(&row.item_bytes)?;
+    let mut entry = item_to_entry(&item, &row);
+    if include_items {
+        entry.bytes = row.item_bytes;
+    }
+    Ok(entry)
+}
 
 // Get the protobuf ItemList for items on the homepage.
 pub(crate) async fn homepage_item_list(
     data: Data<AppData>,
     Query(pagination): Query<Pagination>,
+    Query(options): Query<ListOptions>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
 
+    let item_types = match options.item_types(&[ItemType::POST]) {
+        Ok(item_types) => item_types,
+        Err(response) => return Ok(response),
+    };
+    let include_items = options.include_items();
     let mut paginator = Paginator::new(
         pagination,
-        |row: ItemDisplayRow| -> Result<ItemListEntry,anyhow::Error> {
-            let mut item = Item::new();
-            item.merge_from_bytes(&row.item.item_bytes)?;
-            Ok(item_to_entry(&item, &row.item.user, &row.item.signature))
-        }, 
-        |entry: &ItemListEntry| { 
-            entry.item_type == EnumOrUnknown::new(ItemType::POST)
-        }
+        |row: ItemDisplayRow| row_to_entry(row.item, include_items),
+        |_: &ItemListEntry| { true } // filtered by item_types
     );
-    // We're only holding ItemListEntries in memory, so we can up this limit and save some round trips.
-    paginator.max_items = 1000;
+    options.limit(&data, &mut paginator);
 
     let backend = data.backend_factory.open()?;
-    backend.homepage_items(paginator.time_span(), &mut paginator.callback())?;
+    backend.homepage_items(paginator.time_span(), &item_types, &mut paginator.callback())?;
     
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = paginator.into_items();
-    Ok(
-        proto_ok().body(list.write_to_bytes()?)
-    )
+    item_list_ok(&req, &list)
 }
 
 
@@ -50,105 +124,130 @@ pub(crate) async fn feed_item_list(
     data: Data<AppData>,
     path: Path<(UserID,)>,
     Query(pagination): Query<Pagination>,
+    Query(options): Query<ListOptions>,
+    Query(feed): Query<FeedOptions>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let (user_id,) = path.into_inner();
+    let item_types = match options.item_types(&[]) {
+        Ok(item_types) => item_types,
+        Err(response) => return Ok(response),
+    };
+    let include_items = options.include_items();
     let mut paginator = Paginator::new(
         pagination,
-        |row: ItemDisplayRow| -> Result<ItemListEntry,anyhow::Error> {
-            let mut item = Item::new();
-            item.merge_from_bytes(&row.item.item_bytes)?;
-            Ok(item_to_entry(&item, &row.item.user, &row.item.signature))
-        }, 
+        |row: ItemDisplayRow| row_to_entry(row.item, include_items),
         |_: &ItemListEntry| { true } // include all items
     );
-    // We're only holding ItemListEntries in memory, so we can up this limit and
-    // save some round trips.
-    paginator.max_items = 1000;
+    options.limit(&data, &mut paginator);
 
     let backend = data.backend_factory.open()?;
 
     // Note: user_feed_items is doing a little bit of extra work to fetch
     // display_name, which we then throw away. We *could* make a more efficient
     // version that we use for just this case, but eh, reuse is nice.
-    backend.user_feed_items(&user_id, paginator.time_span(), &mut paginator.callback())?;
+    backend.user_feed_items(&user_id, paginator.time_span(), &item_types, feed.group.as_deref(), &mut paginator.callback())?;
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = paginator.into_items();
-    Ok(
-        proto_ok()
-        .body(list.write_to_bytes()?)
-    )
+    item_list_ok(&req, &list)
 }
 
 pub(crate) async fn user_item_list(
     data: Data<AppData>,
     path: Path<(UserID,)>,
     Query(pagination): Query<Pagination>,
+    Query(options): Query<ListOptions>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let (user_id,) = path.into_inner();
+    let item_types = match options.item_types(&[]) {
+        Ok(item_types) => item_types,
+        Err(response) => return Ok(response),
+    };
+    let include_items = options.include_items();
     let mut paginator = Paginator::new(
         pagination,
-        |row: ItemRow| -> Result<ItemListEntry,anyhow::Error> {
-            let mut item = Item::new();
-            item.merge_from_bytes(&row.item_bytes)?;
-            Ok(item_to_entry(&item, &row.user, &row.signature))
-        }, 
+        |row: ItemRow| row_to_entry(row, include_items),
         |_| { true } // include all items
     );
-    // We're only holding ItemListEntries in memory, so we can up this limit and
-    // save some round trips.
-    paginator.max_items = 1000;
+    options.limit(&data, &mut paginator);
 
     let backend = data.backend_factory.open()?;
 
     // Note: user_feed_items is doing a little bit of extra work to fetch
     // display_name, which we then throw away. We *could* make a more efficient
     // version that we use for just this case, but eh, reuse is nice.
-    backend.user_items(&user_id, paginator.time_span(), &mut paginator.callback())?;
+    backend.user_items(&user_id, paginator.time_span(), &item_types, &mut paginator.callback())?;
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = paginator.into_items();
-    Ok(
-        proto_ok()
-        .body(list.write_to_bytes()?)
-    )
+    item_list_ok(&req, &list)
 }
 
 pub(crate) async fn item_reply_list(
     data: Data<AppData>,
     path: Path<(UserID, Signature)>,
     Query(pagination): Query<Pagination>,
+    Query(options): Query<ListOptions>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature) = path.into_inner();
+    let item_types = match options.item_types(&[]) {
+        Ok(item_types) => item_types,
+        Err(response) => return Ok(response),
+    };
+    let include_items = options.include_items();
     let mut paginator = Paginator::new(
         pagination,
-        |row: ItemRow| -> Result<ItemListEntry,anyhow::Error> {
-            let mut item = Item::new();
-            item.merge_from_bytes(&row.item_bytes)?;
-            Ok(item_to_entry(&item, &row.user, &row.signature))
-        }, 
+        |row: ItemRow| row_to_entry(row, include_items),
         |_| { true } // include all items
     );
-    // We're only holding ItemListEntries in memory, so we can up this limit and
-    // save some round trips.
-    paginator.max_items = 1000;
+    options.limit(&data, &mut paginator);
 
     let backend = data.backend_factory.open()?;
 
     // Note: user_feed_items is doing a little bit of extra work to fetch
     // display_name, which we then throw away. We *could* make a more efficient
     // version that we use for just this case, but eh, reuse is nice.
-    backend.reply_items(&user_id, &signature, paginator.before(), &mut paginator.callback())?;
+    backend.reply_items(&user_id, &signature, paginator.before(), &item_types, &mut paginator.callback())?;
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = paginator.into_items();
-    Ok(
-        proto_ok()
-        .body(list.write_to_bytes()?)
-    )
+    item_list_ok(&req, &list)
+}
+
+/// Replies to a user's items, and items that mention them, newest first.
+pub(crate) async fn notification_item_list(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+    Query(pagination): Query<Pagination>,
+    Query(options): Query<ListOptions>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    let item_types = match options.item_types(&[]) {
+        Ok(item_types) => item_types,
+        Err(response) => return Ok(response),
+    };
+    let include_items = options.include_items();
+    let mut paginator = Paginator::new(
+        pagination,
+        |row: ItemRow| row_to_entry(row, include_items),
+        |_| { true } // include all items
+    );
+    options.limit(&data, &mut paginator);
+
+    let backend = data.backend_factory.open()?;
+    backend.user_notification_items(&user_id, paginator.time_span(), &item_types, &mut paginator.callback())?;
+
+    let mut list = ItemList::new();
+    list.no_more_items = !paginator.has_more;
+    list.items = paginator.into_items();
+    item_list_ok(&req, &list)
 }
 
 /// Accepts a proto3 Item
@@ -173,6 +272,7 @@ pub(crate) async fn put_item(
     let length = match req.headers().get("content-length") {
         Some(length) => length,
         None => {
+            data.metrics.item_put(ItemPut::Invalid);
             return Ok(
                 HttpResponse::LengthRequired()
                 .content_type(PLAINTEXT)
@@ -185,6 +285,7 @@ pub(crate) async fn put_item(
     let length: usize = match length.to_str()?.parse() {
         Ok(length) => length,
         Err(_) => {
+            data.metrics.item_put(ItemPut::Invalid);
             return Ok(
                 HttpResponse::BadRequest()
                 .content_type(PLAINTEXT)
@@ -193,11 +294,13 @@ pub(crate) async fn put_item(
         },
     };
 
-    if length > MAX_ITEM_SIZE {
+    let max_item_bytes = data.config.limits.max_item_bytes;
+    if length > max_item_bytes {
+        data.metrics.item_put(ItemPut::Invalid);
         return Ok(
             HttpResponse::PayloadTooLarge()
             .content_type(PLAINTEXT)
-            .body(format!("Item must be <= {} bytes", MAX_ITEM_SIZE))
+            .body(format!("Item must be <= {} bytes", max_item_bytes))
         );
     }
 
@@ -207,7 +310,7 @@ pub(crate) async fn put_item(
     if backend.user_item_exists(&user, &signature)? {
         // *sigh* this bug again. Should I handle this in middleware?
         drain(body).await;
-        
+        data.metrics.item_put(ItemPut::Exists);
         return Ok(
             HttpResponse::Accepted()
             .content_type(PLAINTEXT)
@@ -216,6 +319,7 @@ pub(crate) async fn put_item(
     }
 
     if !backend.user_known(&user)? {
+        data.metrics.item_put(ItemPut::Forbidden);
         return Ok(
             HttpResponse::Forbidden()
             .content_type(PLAINTEXT)
@@ -230,15 +334,36 @@ pub(crate) async fn put_item(
     }
 
     if !signature.is_valid(&user, &bytes) {
+        data.metrics.item_put(ItemPut::InvalidSignature);
         // TODO: return invalid request here.
         Err(format_err!("Invalid signature"))?;
     }
 
     let mut item: Item = Item::new();
     item.merge_from_bytes(&bytes)?;
-    item.validate()?;
+    if let Err(err) = item.validate() {
+        data.metrics.item_put(ItemPut::Invalid);
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(err.to_string())
+        )
+    }
+
+    let unsafe_markdown = markdown::find_unsafe_in_item(&item);
+    if let Some(found) = &unsafe_markdown {
+        if data.unsafe_markdown == UnsafeMarkdownPolicy::Reject {
+            data.metrics.item_put(ItemPut::Invalid);
+            return Ok(
+                HttpResponse::BadRequest()
+                .content_type(PLAINTEXT)
+                .body(found.to_string())
+            )
+        }
+    }
 
     if item.timestamp_ms_utc > Timestamp::now().unix_utc_ms {
+        data.metrics.item_put(ItemPut::Invalid);
         return Ok(
             HttpResponse::BadRequest()
             .content_type(PLAINTEXT)
@@ -247,6 +372,7 @@ pub(crate) async fn put_item(
     }
 
     if let Some(deny_reason) = backend.quota_check_item(&user, &bytes, &item)? {
+        data.metrics.item_put(ItemPut::Quota);
         return Ok(
             HttpResponse::InsufficientStorage()
             .body(format!("{}", deny_reason))
@@ -256,16 +382,21 @@ pub(crate) async fn put_item(
     let message = format!("OK. Received {} bytes.", bytes.len());
     
     let row = ItemRow{
-        user: user,
-        signature: signature,
+        user,
+        signature,
         timestamp: Timestamp{ unix_utc_ms: item.timestamp_ms_utc},
         received: Timestamp::now(),
         item_bytes: bytes,
+        unsafe_markdown: unsafe_markdown.is_some(),
     };
 
     let timer = timer!("save_user_item");
     backend.save_user_item(&row, &item).context("Error saving user item")?;
     drop(timer);
+    data.metrics.item_put(ItemPut::Created);
+
+    webhooks::item_saved(&data, &row, &item);
+    activitypub::deliver_post(&data, row, item);
 
     let response = HttpResponse::Created()
         .content_type(PLAINTEXT)
@@ -282,6 +413,7 @@ pub(crate) async fn put_item(
 pub(crate) async fn get_item(
     data: Data<AppData>,
     path: Path<(UserID, Signature,)>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature) = path.into_inner();
     let backend = data.backend_factory.open()?;
@@ -295,6 +427,10 @@ pub(crate) async fn get_item(
         }
     };
 
+    if json::wants_json(&req) {
+        return item_json_ok(&item);
+    }
+
     // We could in theory validate the bytes ourselves, but if a client is directly fetching the 
     // protobuf bytes via this endpoint, it's probably going to be so that it can verify the bytes
     // for itself anyway.
@@ -305,11 +441,91 @@ pub(crate) async fn get_item(
 
 }
 
+/// Fetch many Items at once, so that clients needn't `get_item()` each entry of an ItemList.
+pub(crate) async fn get_item_batch(
+    data: Data<AppData>,
+    mut body: Payload,
+) -> Result<HttpResponse, Error> {
+    let max_items = data.config.limits.max_batch_items;
+
+    // An encoded ItemID is ~106 bytes:
+    let max_bytes = max_items * 128;
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Error parsing chunk")?;
+        if bytes.len() + chunk.len() > max_bytes {
+            return Ok(too_many_items(HttpResponse::PayloadTooLarge(), max_items));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let request = match ItemBatchRequest::parse_from_bytes(&bytes) {
+        Ok(request) => request,
+        Err(_) => return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body("Expected a protobuf ItemBatchRequest")
+        ),
+    };
+    if request.items.len() > max_items {
+        return Ok(too_many_items(HttpResponse::BadRequest(), max_items));
+    }
+
+    let mut ids = Vec::with_capacity(request.items.len());
+    for id in &request.items {
+        let user_id = UserID::from_vec(id.user_id.bytes.clone());
+        let signature = Signature::from_vec(id.signature.bytes.clone());
+        match (user_id, signature) {
+            (Ok(user_id), Ok(signature)) => ids.push((user_id, signature)),
+            _ => return Ok(
+                HttpResponse::BadRequest()
+                .content_type(PLAINTEXT)
+                .body("Each ItemID needs a valid user_id and signature")
+            ),
+        }
+    }
+
+    let mut found = HashMap::with_capacity(ids.len());
+    let backend = data.backend_factory.open()?;
+    backend.user_items_by_id(&ids, &mut |row| {
+        found.insert((row.user, row.signature.bytes().to_vec()), row.item_bytes);
+        Ok(true)
+    })?;
+    drop(backend);
+
+    // Respond in the requested order:
+    let mut batch = ItemBatch::new();
+    for (id, (user_id, signature)) in request.items.into_iter().zip(ids) {
+        match found.get(&(user_id, signature.bytes().to_vec())) {
+            None => batch.missing.push(id),
+            Some(bytes) => {
+                let mut item = SignedItem::new();
+                item.user_id = id.user_id;
+                item.signature = id.signature;
+                item.bytes = bytes.clone();
+                batch.items.push(item);
+            },
+        }
+    }
+
+    Ok(
+        proto_ok()
+        .body(batch.write_to_bytes()?)
+    )
+}
+
+fn too_many_items(mut response: HttpResponseBuilder, max_items: usize) -> HttpResponse {
+    response
+        .content_type(PLAINTEXT)
+        .body(format!("At most {} items may be fetched at once", max_items))
+}
+
 /// Get the latest profile we have for a user ID.
 /// returns the signature in a "signature" header so clients can verify it.
 pub(crate) async fn get_profile_item(
     data: Data<AppData>,
     path: Path<(UserID,)>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let (user_id,) = path.into_inner();
     let backend = data.backend_factory.open()?;
@@ -323,6 +539,10 @@ pub(crate) async fn get_profile_item(
         }
     };
 
+    if json::wants_json(&req) {
+        return item_json_ok(&item);
+    }
+
     // We could in theory validate the bytes ourselves, but if a client is directly fetching the 
     // protobuf bytes via this endpoint, it's probably going to be so that it can verify the bytes
     // for itself anyway.
@@ -340,31 +560,340 @@ pub(crate) async fn get_profile_item(
 fn proto_ok() -> HttpResponseBuilder {
     let mut builder = HttpResponse::Ok();
     builder.content_type("application/protobuf3");
+    // Clients may also ask for JSON:
+    builder.insert_header((header::VARY, "Accept"));
     builder
 }
 
-fn item_to_entry(item: &Item, user_id: &UserID, signature: &Signature) -> ItemListEntry {
+fn item_list_ok(req: &HttpRequest, list: &ItemList) -> Result<HttpResponse, Error> {
+    if json::wants_json(req) {
+        return Ok(
+            json::json_ok()
+            .body(json::item_list(list)?.to_string())
+        );
+    }
+
+    Ok(
+        proto_ok()
+        .body(list.write_to_bytes()?)
+    )
+}
+
+fn item_json_ok(row: &ItemRow) -> Result<HttpResponse, Error> {
+    let mut item = Item::new();
+    item.merge_from_bytes(&row.item_bytes)?;
+    Ok(
+        json::json_ok()
+        .body(json::item(row, &item)?.to_string())
+    )
+}
+
+pub(crate) fn item_to_entry(item: &Item, row: &ItemRow) -> ItemListEntry {
     let mut entry = ItemListEntry::new();
     entry.timestamp_ms_utc = item.timestamp_ms_utc;
     entry.signature = MessageField::some({
         let mut sig = crate::protos::Signature::new();
-        sig.bytes = signature.bytes().to_vec();
+        sig.bytes = row.signature.bytes().to_vec();
         sig
     });
     entry.user_id = MessageField::some({
         let mut uid = crate::protos::UserID::new();
-        uid.bytes = user_id.bytes().to_vec();
+        uid.bytes = row.user.bytes().to_vec();
         uid
     });
-    entry.item_type = EnumOrUnknown::new({
-        use crate::protos::item::Item_type::*;
-        match item.item_type {
-            Some(Post(_)) => ItemType::POST,
-            Some(Profile(_)) => ItemType::PROFILE,
-            Some(Comment(_)) => ItemType::COMMENT,
-            None => ItemType::UNKNOWN,
-        }
-    });
+    entry.unsafe_markdown = row.unsafe_markdown;
+    entry.item_type = EnumOrUnknown::new(item.get_type());
 
     entry
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{App, test};
+
+    use crate::backend::PruneOpts;
+    use crate::protos::ItemID;
+    use crate::server::test_util::{TestDb, comment, post, sign_item};
+    use super::super::{config::{Config, RateLimitsConfig}, rate_limit::RateLimits};
+    use super::*;
+
+    async fn item_list<S>(app: &S, uri: &str) -> ItemList
+    where S: actix_web::dev::Service<actix_http::Request, Response = actix_web::dev::ServiceResponse, Error = actix_web::Error>
+    {
+        let resp = test::call_service(app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(200, resp.status(), "{}", uri);
+        ItemList::parse_from_bytes(&test::read_body(resp).await).unwrap()
+    }
+
+    /// Signatures of the items in an ItemList response.
+    async fn list<S>(app: &S, uri: &str) -> Vec<Signature>
+    where S: actix_web::dev::Service<actix_http::Request, Response = actix_web::dev::ServiceResponse, Error = actix_web::Error>
+    {
+        let list = item_list(app, uri).await;
+        list.items.iter().map(|entry| Signature::from_vec(entry.signature.bytes.clone()).unwrap()).collect()
+    }
+
+    #[actix_web::test]
+    async fn notifications() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        let bob = db.new_user();
+
+        let post_sig = db.save(&alice, &post(100, "Hello"));
+        let reply_sig = db.save(&bob, &comment(200, &alice.0, &post_sig, "Hi!"));
+        let mention_sig = db.save(&bob, &post(300, &format!("Go see /u/{}/", alice.0)));
+        db.save(&alice, &comment(400, &alice.0, &post_sig, "Thanks!"));
+
+        let app = test::init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let uri = format!("/diskuto/users/{}/notifications", alice.0);
+        assert_eq!(vec![mention_sig.clone(), reply_sig.clone()], list(&app, &uri).await);
+        assert_eq!(vec![reply_sig.clone()], list(&app, &format!("{}?before=300", uri)).await);
+        assert_eq!(Vec::<Signature>::new(), list(&app, &format!("/diskuto/users/{}/notifications", bob.0)).await);
+
+        // Filtered by ?types=, in SQL:
+        assert_eq!(vec![mention_sig], list(&app, &format!("{}?types=post", uri)).await);
+        assert_eq!(vec![reply_sig], list(&app, &format!("{}?types=comment", uri)).await);
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("{}?types=bogus", uri)).to_request()).await;
+        assert_eq!(400, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn replies() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        let bob = db.new_user();
+
+        let post_sig = db.save(&alice, &post(100, "Hello"));
+        let reply_sig = db.save(&bob, &comment(200, &alice.0, &post_sig, "Hi!"));
+        let thanks_sig = db.save(&alice, &comment(300, &alice.0, &post_sig, "Thanks!"));
+        db.save(&bob, &comment(400, &alice.0, &reply_sig, "Not a direct reply"));
+
+        let app = test::init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let uri = format!("/diskuto/users/{}/items/{}/replies", alice.0, post_sig.to_base58());
+        assert_eq!(vec![thanks_sig.clone(), reply_sig.clone()], list(&app, &uri).await);
+        assert_eq!(vec![thanks_sig, reply_sig], list(&app, &format!("{}?types=comment", uri)).await);
+        assert_eq!(Vec::<Signature>::new(), list(&app, &format!("{}?types=post,profile", uri)).await);
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("{}?types=bogus", uri)).to_request()).await;
+        assert_eq!(400, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn include_items() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        let bob = db.new_user();
+        let post_sig = db.save(&alice, &post(100, "Hello"));
+        let reply = comment(200, &alice.0, &post_sig, "Hi!");
+        db.save(&bob, &reply);
+
+        let app = test::init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let uris = [
+            "/diskuto/homepage".to_string(),
+            format!("/diskuto/users/{}/items", bob.0),
+            format!("/diskuto/users/{}/items/{}/replies", alice.0, post_sig.to_base58()),
+            format!("/diskuto/users/{}/notifications", alice.0),
+        ];
+        for uri in uris {
+            let list = item_list(&app, &uri).await;
+            assert!(!list.items.is_empty(), "{}", uri);
+            assert!(list.items.iter().all(|entry| entry.bytes.is_empty()), "{}", uri);
+
+            let list = item_list(&app, &format!("{}?include=items", uri)).await;
+            assert!(!list.items.is_empty(), "{}", uri);
+            for entry in &list.items {
+                let user_id = UserID::from_vec(entry.user_id.bytes.clone()).unwrap();
+                let signature = Signature::from_vec(entry.signature.bytes.clone()).unwrap();
+                assert!(signature.is_valid(&user_id, &entry.bytes), "{}", uri);
+            }
+        }
+
+        let list = item_list(&app, &format!("/diskuto/users/{}/notifications?include=items", alice.0)).await;
+        assert_eq!(reply.write_to_bytes().unwrap(), list.items[0].bytes);
+    }
+
+    #[actix_web::test]
+    async fn max_list_bytes() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        for timestamp in [200, 300, 400] {
+            db.save(&alice, &post(timestamp, "Hello"));
+        }
+        let item_bytes = post(200, "Hello").write_to_bytes().unwrap().len();
+
+        let mut config = Config::default();
+        config.limits.max_list_bytes = item_bytes;
+        let data = AppData{ config: Arc::new(config), ..db.app_data() };
+        let app = test::init_service(App::new().app_data(Data::new(data)).configure(super::super::api_routes)).await;
+        let uri = format!("/diskuto/users/{}/items", alice.0);
+
+        // The second entry goes over the limit, and ends the page:
+        let list = item_list(&app, &format!("{}?include=items", uri)).await;
+        assert_eq!(2, list.items.len());
+        assert!(!list.no_more_items);
+        let list = item_list(&app, &format!("{}?include=items&before=300", uri)).await;
+        assert_eq!(1, list.items.len());
+        assert!(list.no_more_items);
+
+        // Without the bytes, they all fit:
+        let list = item_list(&app, &uri).await;
+        assert_eq!(3, list.items.len());
+        assert!(list.no_more_items);
+    }
+
+    #[actix_web::test]
+    async fn user_rate_limit() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        let limits = RateLimitsConfig{ item_puts_per_minute: 2, ..Default::default() };
+        let data = AppData{ rate_limits: Arc::new(RateLimits::new(&limits)), ..db.app_data() };
+        let app = test::init_service(App::new().app_data(Data::new(data)).configure(super::super::api_routes)).await;
+        let sign = |item: &Item| {
+            let (row, signature) = sign_item(&alice, item);
+            (signature, row.item_bytes)
+        };
+
+        let put = |ip: &str, (signature, bytes): &(Signature, Vec<u8>)| {
+            test::TestRequest::put()
+                .uri(&format!("/diskuto/users/{}/items/{}", alice.0, signature.to_base58()))
+                .peer_addr(format!("{}:1234", ip).parse().unwrap())
+                .insert_header((header::CONTENT_LENGTH, bytes.len()))
+                .set_payload(bytes.clone())
+                .to_request()
+        };
+
+        // Others' failed uploads don't count against alice's limit:
+        let (signature, _) = sign(&post(100, "Hello"));
+        let forged = (signature, post(100, "Goodbye").write_to_bytes().unwrap());
+        for _ in 0..2 {
+            assert!(!test::call_service(&app, put("10.0.0.2", &forged)).await.status().is_success());
+        }
+        assert_eq!(429, test::call_service(&app, put("10.0.0.2", &forged)).await.status());
+
+        let first = sign(&post(100, "Hello"));
+        assert_eq!(201, test::call_service(&app, put("10.0.0.1", &first)).await.status());
+
+        // ... nor do re-sent copies of items she's already posted:
+        assert_eq!(202, test::call_service(&app, put("10.0.0.3", &first)).await.status());
+
+        let second = sign(&post(200, "Hello again"));
+        assert_eq!(201, test::call_service(&app, put("10.0.0.4", &second)).await.status());
+
+        // ... but her own uploads do, from any IP:
+        let third = sign(&post(300, "Hello?"));
+        assert_eq!(429, test::call_service(&app, put("10.0.0.5", &third)).await.status());
+    }
+
+    #[actix_web::test]
+    async fn item_not_modified() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        let signature = db.save(&alice, &post(100, "Hello"));
+        let app = test::init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let uri = format!("/diskuto/users/{}/items/{}", alice.0, signature.to_base58());
+        let get = |etag: &header::EntityTag| test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_NONE_MATCH, etag.to_string()))
+            .to_request();
+
+        let etag = super::super::item_etag(&signature, false);
+        let resp = test::call_service(&app, get(&etag)).await;
+        assert_eq!(304, resp.status());
+        assert_eq!(etag.to_string(), resp.headers().get(header::ETAG).unwrap().to_str().unwrap());
+
+        // The JSON representation has a different ETag:
+        let resp = test::call_service(&app, get(&super::super::item_etag(&signature, true))).await;
+        assert_eq!(200, resp.status());
+        assert_eq!(etag.to_string(), resp.headers().get(header::ETAG).unwrap().to_str().unwrap());
+    }
+
+    fn item_id(user_id: &UserID, signature: &Signature) -> ItemID {
+        let mut id = ItemID::new();
+        id.user_id.mut_or_insert_default().bytes = user_id.bytes().to_vec();
+        id.signature.mut_or_insert_default().bytes = signature.bytes().to_vec();
+        id
+    }
+
+    fn batch_request(ids: &[ItemID]) -> actix_http::Request {
+        let mut request = ItemBatchRequest::new();
+        request.items = ids.to_vec();
+        test::TestRequest::post()
+            .uri("/diskuto/items/batch")
+            .set_payload(request.write_to_bytes().unwrap())
+            .to_request()
+    }
+
+    #[actix_web::test]
+    async fn item_batch() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        let first = db.save(&alice, &post(100, "Hello"));
+        let second = db.save(&alice, &post(200, "Hello again"));
+
+        // Items of users the server doesn't know are deleted by prune:
+        let (public_key, secret_key) = sodiumoxide::crypto::sign::gen_keypair();
+        let stranger = (UserID::from_vec(public_key.0.to_vec()).unwrap(), secret_key);
+        let (row, deleted) = sign_item(&stranger, &post(300, "Spam"));
+        db.factory.open().unwrap().save_user_item(&row, &post(300, "Spam")).unwrap();
+        db.factory.open().unwrap().prune(PruneOpts{ dry_run: false, attachments: false, items: true, thumbnails: false }).unwrap();
+        let (_, unsaved) = sign_item(&alice, &post(400, "Never sent"));
+
+        let app = test::init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let ids = [
+            item_id(&alice.0, &second),
+            item_id(&stranger.0, &deleted),
+            item_id(&alice.0, &first),
+            item_id(&alice.0, &unsaved),
+            item_id(&alice.0, &second),
+        ];
+        let resp = test::call_service(&app, batch_request(&ids)).await;
+        assert_eq!(200, resp.status());
+        let batch = ItemBatch::parse_from_bytes(&test::read_body(resp).await).unwrap();
+
+        // In the requested order, duplicates included:
+        let signatures: Vec<_> = batch.items.iter().map(|item| Signature::from_vec(item.signature.bytes.clone()).unwrap()).collect();
+        assert_eq!(vec![second.clone(), first.clone(), second.clone()], signatures);
+        for item in &batch.items {
+            let signature = Signature::from_vec(item.signature.bytes.clone()).unwrap();
+            assert!(signature.is_valid(&alice.0, &item.bytes));
+        }
+        assert_eq!(vec![ids[1].clone(), ids[3].clone()], batch.missing);
+    }
+
+    #[actix_web::test]
+    async fn item_batch_limits() {
+        let db = TestDb::new();
+        let alice = db.new_user();
+        let signature = db.save(&alice, &post(100, "Hello"));
+        let mut config = Config::default();
+        config.limits.max_batch_items = 10;
+        let data = AppData{ config: Arc::new(config), ..db.app_data() };
+        let app = test::init_service(App::new().app_data(Data::new(data)).configure(super::super::api_routes)).await;
+        let ids = vec![item_id(&alice.0, &signature); 11];
+
+        let resp = test::call_service(&app, batch_request(&ids[..10])).await;
+        assert_eq!(200, resp.status());
+        let batch = ItemBatch::parse_from_bytes(&test::read_body(resp).await).unwrap();
+        assert_eq!(10, batch.items.len());
+
+        let resp = test::call_service(&app, batch_request(&ids)).await;
+        assert_eq!(400, resp.status());
+        assert_eq!("At most 10 items may be fetched at once", test::read_body(resp).await);
+
+        // Too big to be a request for 10 items:
+        let resp = test::call_service(&app, test::TestRequest::post()
+            .uri("/diskuto/items/batch")
+            .set_payload(vec![0u8; 10 * 128 + 1])
+            .to_request()
+        ).await;
+        assert_eq!(413, resp.status());
+
+        let resp = test::call_service(&app, test::TestRequest::post()
+            .uri("/diskuto/items/batch")
+            .set_payload("not a protobuf")
+            .to_request()
+        ).await;
+        assert_eq!(400, resp.status());
+    }
+}
@@ -0,0 +1,122 @@
+//! `GET /diskuto/server-info`
+//!
+//! Describes what this server supports, so that clients don't have to hard-code it.
+//! Always JSON, since it's meant to be easy to read from anywhere.
+
+use actix_web::{HttpResponse, web::{Data, Query}};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::backend::UserID;
+
+use super::{AppData, Error, UnsafeMarkdownPolicy};
+
+#[derive(Deserialize)]
+pub(crate) struct InfoQuery {
+    /// Also report on whether this user can post here.
+    user: Option<UserID>,
+}
+
+pub(crate) async fn server_info(
+    data: Data<AppData>,
+    Query(query): Query<InfoQuery>,
+) -> Result<HttpResponse, Error> {
+    let mut features = vec!["json", "atom", "rss", "streams", "notifications", "html-preview", "batch"];
+    if data.activitypub_url.is_some() {
+        features.push("activitypub");
+    }
+    if !data.config.attachments.thumbnail_sizes.is_empty() {
+        features.push("thumbnails");
+    }
+
+    let mut info = json!({
+        "software": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "features": features,
+        "itemTypes": ["post", "profile", "comment"],
+        "deprecatedRoutes": true,
+        "limits": {
+            "maxItemBytes": data.config.limits.max_item_bytes,
+            "maxListItems": data.config.limits.max_list_items,
+            "maxBatchItems": data.config.limits.max_batch_items,
+            // Other attachment types can be uploaded, but are served as application/octet-stream:
+            "attachmentTypes": data.config.attachments.allowed_types,
+            "thumbnailSizes": data.config.attachments.thumbnail_sizes,
+        },
+        "postingPolicy": {
+            // Server users, and users they follow, may post here:
+            "serverUsers": true,
+            "followedByServerUsers": true,
+            "unsafeMarkdown": match data.unsafe_markdown {
+                UnsafeMarkdownPolicy::Flag => "flag",
+                UnsafeMarkdownPolicy::Reject => "reject",
+            },
+        },
+    });
+
+    if let Some(user_id) = &query.user {
+        let backend = data.backend_factory.open()?;
+        let server_user = backend.server_user(user_id)?.is_some();
+        let followed = backend.followed_by_server_user(user_id)?;
+        info["user"] = json!({
+            "userIdBase58": user_id.to_base58(),
+            "canPost": server_user || followed,
+            "serverUser": server_user,
+            "followedByServerUser": followed,
+        });
+    }
+
+    Ok(
+        HttpResponse::Ok()
+        .content_type("application/json")
+        .body(info.to_string())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test};
+    use serde_json::Value;
+
+    use std::sync::Arc;
+
+    use crate::server::{config::Config, test_util::TestDb};
+    use super::*;
+
+    #[actix_web::test]
+    async fn reflects_settings() {
+        let db = TestDb::new();
+        let user = db.new_user();
+        let stranger = TestDb::new().new_user();
+        let mut config = Config::default();
+        config.limits.max_item_bytes = 1234;
+        config.limits.max_list_items = 56;
+        config.attachments.allowed_types = vec!["text/plain".into()];
+        let app = test::init_service(
+            App::new()
+            .app_data(Data::new(AppData{
+                unsafe_markdown: UnsafeMarkdownPolicy::Reject,
+                activitypub_url: Some("https://diskuto.example/".parse().unwrap()),
+                config: Arc::new(config),
+                ..db.app_data()
+            }))
+            .configure(super::super::api_routes)
+        ).await;
+
+        let req = test::TestRequest::get().uri(&format!("/diskuto/server-info?user={}", user.0)).to_request();
+        let info: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(info["features"].as_array().unwrap().contains(&json!("activitypub")));
+        assert_eq!(info["limits"]["maxItemBytes"], json!(1234));
+        assert_eq!(info["limits"]["maxListItems"], json!(56));
+        assert_eq!(info["limits"]["attachmentTypes"], json!(["text/plain"]));
+        assert_eq!(info["postingPolicy"]["unsafeMarkdown"], json!("reject"));
+        assert_eq!(info["user"]["canPost"], json!(true));
+        assert_eq!(info["user"]["serverUser"], json!(true));
+
+        let req = test::TestRequest::get().uri(&format!("/diskuto/server-info?user={}", stranger.0)).to_request();
+        let info: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["user"]["canPost"], json!(false));
+    }
+}
@@ -0,0 +1,300 @@
+//! Server-Sent Events streams of new items.
+//!
+//! Instead of polling, clients can listen to `/diskuto/homepage/stream` or
+//! `/diskuto/users/{id}/feed/stream` and get an [`ItemListEntry`] as soon as a
+//! matching item is saved.
+//!
+//! Each event's `id` is the newest item timestamp the client has been sent.
+//! `EventSource` sends it back as `Last-Event-ID` when it reconnects, and we
+//! replay any items after it before resuming the live stream.
+
+use std::{collections::VecDeque, time::Duration};
+
+use actix_web::{HttpRequest, HttpResponse, http::header, rt::time::timeout, web::{Bytes, Data, Path}};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use log::warn;
+use protobuf::Message;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+
+use crate::{backend::{Backend, Factory, ItemDisplayRow, NewItem, RowCallback, TimeSpan, Timestamp, UserID}, protos::{Item, ItemListEntry, ItemType}};
+
+use super::{AppData, Error, PLAINTEXT, json, rest::item_to_entry};
+
+/// Send a comment this often so that proxies don't close idle connections.
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// How many items to replay (after `Last-Event-ID`) per query.
+const REPLAY_PAGE_SIZE: usize = 100;
+
+/// How many recently-sent signatures we remember.
+const RECENTLY_SENT: usize = 1000;
+
+pub(crate) async fn homepage_stream(
+    data: Data<AppData>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    stream(&data, &req, Source::Homepage)
+}
+
+pub(crate) async fn feed_stream(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+    stream(&data, &req, Source::Feed(user_id))
+}
+
+fn stream(data: &AppData, req: &HttpRequest, source: Source) -> Result<HttpResponse, Error> {
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        None => None,
+        Some(value) => match value.to_str().ok().and_then(|id| id.parse().ok()) {
+            Some(unix_utc_ms) => Some(Timestamp{ unix_utc_ms }),
+            None => return Ok(
+                HttpResponse::BadRequest()
+                .content_type(PLAINTEXT)
+                .body("Invalid Last-Event-ID")
+            ),
+        },
+    };
+
+    // Subscribe before we replay, so that we don't miss anything in between:
+    let listener = Listener {
+        events: data.backend_factory.item_events().subscribe(),
+        factory: data.backend_factory.dyn_clone(),
+        source,
+        json: json::wants_json(req),
+        cursor: last_event_id.unwrap_or_else(Timestamp::now),
+        replaying: last_event_id.is_some(),
+        recently_sent: VecDeque::new(),
+    };
+
+    let body = futures::stream::unfold(listener, |mut listener| async move {
+        match listener.next().await {
+            Ok(Some(bytes)) => Some((Ok::<_, actix_web::Error>(bytes), listener)),
+            Ok(None) => None,
+            Err(err) => {
+                warn!("Error in item stream: {}", err);
+                None
+            },
+        }
+    });
+
+    Ok(
+        HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header((header::VARY, "Accept"))
+        // Tell nginx not to buffer the stream:
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body)
+    )
+}
+
+/// Which items a client is listening for.
+#[derive(Clone)]
+enum Source {
+    Homepage,
+    Feed(UserID),
+}
+
+impl Source {
+    /// Entries in this source that are newer than `after`, oldest first.
+    fn entries_after(
+        &self,
+        backend: &dyn Backend,
+        after: Timestamp,
+        callback: RowCallback<'_, ItemListEntry>,
+    ) -> Result<(), anyhow::Error> {
+        let mut to_entry = |row: ItemDisplayRow| -> Result<bool, anyhow::Error> {
+            let mut item = Item::new();
+            item.merge_from_bytes(&row.item.item_bytes)?;
+            callback(item_to_entry(&item, &row.item))
+        };
+
+        match self {
+            // Same as the homepage list:
+            Source::Homepage => backend.homepage_items(TimeSpan::After(after), &[ItemType::POST], &mut to_entry),
+            Source::Feed(user_id) => backend.user_feed_items(user_id, TimeSpan::After(after), &[], None, &mut to_entry),
+        }
+    }
+
+    /// Up to one page of entries newer than `after`, and whether there are more.
+    fn replay(&self, backend: &dyn Backend, after: Timestamp) -> Result<(Vec<ItemListEntry>, bool), anyhow::Error> {
+        let mut entries = vec![];
+        let mut has_more = false;
+        self.entries_after(backend, after, &mut |entry| {
+            if entries.len() >= REPLAY_PAGE_SIZE {
+                has_more = true;
+                return Ok(false);
+            }
+            entries.push(entry);
+            Ok(true)
+        })?;
+        Ok((entries, has_more))
+    }
+
+    /// The entry for a newly-saved item, if it belongs in this source.
+    fn find(&self, backend: &dyn Backend, new_item: &NewItem) -> Result<Option<ItemListEntry>, anyhow::Error> {
+        let timestamp = new_item.timestamp.unix_utc_ms;
+        let mut found = None;
+        self.entries_after(backend, Timestamp{ unix_utc_ms: timestamp - 1 }, &mut |entry| {
+            if entry.timestamp_ms_utc > timestamp {
+                return Ok(false);
+            }
+            if entry.signature.bytes == new_item.signature.bytes() && entry.user_id.bytes == new_item.user.bytes() {
+                found = Some(entry);
+                return Ok(false);
+            }
+            Ok(true)
+        })?;
+        Ok(found)
+    }
+}
+
+/// State for a single client's stream.
+struct Listener {
+    events: Receiver<NewItem>,
+    factory: Box<dyn Factory>,
+    source: Source,
+    json: bool,
+
+    /// The newest item timestamp we've sent. (Or when we started listening.)
+    cursor: Timestamp,
+
+    /// Are we still sending items after `cursor` from the database?
+    replaying: bool,
+
+    /// Signatures of items we've sent, so we don't send them twice.
+    recently_sent: VecDeque<Vec<u8>>,
+}
+
+impl Listener {
+    /// The next chunk of the stream, or None if it's over.
+    async fn next(&mut self) -> Result<Option<Bytes>, Error> {
+        loop {
+            if self.replaying {
+                let after = self.cursor;
+                let (entries, has_more) = self.query(move |source, backend| source.replay(backend, after)).await?;
+                self.replaying = has_more;
+                if entries.is_empty() {
+                    continue;
+                }
+                return Ok(Some(self.send(entries)?));
+            }
+
+            let new_item = match timeout(KEEPALIVE, self.events.recv()).await {
+                Err(_elapsed) => return Ok(Some(Bytes::from_static(b": keepalive\n\n"))),
+                Ok(Err(RecvError::Closed)) => return Ok(None),
+                Ok(Err(RecvError::Lagged(_))) => {
+                    // We missed some events, but can catch up from the database:
+                    self.replaying = true;
+                    continue;
+                },
+                Ok(Ok(new_item)) => new_item,
+            };
+
+            if self.recently_sent.iter().any(|sig| sig.as_slice() == new_item.signature.bytes()) {
+                continue;
+            }
+
+            let found = self.query(move |source, backend| source.find(backend, &new_item)).await?;
+            if let Some(entry) = found {
+                return Ok(Some(self.send(vec![entry])?));
+            }
+        }
+    }
+
+    /// Run a (blocking) query against the backend, off of the main thread.
+    async fn query<T, F>(&self, query: F) -> Result<T, anyhow::Error>
+    where
+        F: FnOnce(&Source, &dyn Backend) -> Result<T, anyhow::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let factory = self.factory.dyn_clone();
+        let source = self.source.clone();
+        blocking::unblock(move || {
+            let backend = factory.open()?;
+            query(&source, backend.as_ref())
+        }).await
+    }
+
+    /// Format entries as SSE events.
+    fn send(&mut self, entries: Vec<ItemListEntry>) -> Result<Bytes, Error> {
+        let mut out = String::new();
+        for entry in entries {
+            // Items' timestamps can't be in the future, so this only moves forward to "now":
+            self.cursor.unix_utc_ms = self.cursor.unix_utc_ms.max(entry.timestamp_ms_utc);
+
+            let data = if self.json {
+                json::item_list_entry(&entry)?.to_string()
+            } else {
+                BASE64.encode(entry.write_to_bytes()?)
+            };
+            out.push_str(&format!("id: {}\nevent: item\ndata: {}\n\n", self.cursor.unix_utc_ms, data));
+
+            if self.recently_sent.len() >= RECENTLY_SENT {
+                self.recently_sent.pop_front();
+            }
+            self.recently_sent.push_back(entry.signature.bytes.clone());
+        }
+        Ok(Bytes::from(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+
+    use actix_web::{App, body::{BoxBody, MessageBody}, test};
+
+    use crate::server::test_util::{TestDb, post, sign_item};
+    use super::*;
+
+    async fn next_chunk(body: &mut BoxBody) -> String {
+        let chunk = futures::future::poll_fn(|cx| Pin::new(&mut *body).poll_next(cx)).await;
+        String::from_utf8(chunk.unwrap().unwrap().to_vec()).unwrap()
+    }
+
+    /// New posts are pushed to listeners, and replayed after Last-Event-ID.
+    #[actix_web::test]
+    async fn homepage_stream() {
+        let db = TestDb::new();
+        let user = db.new_user();
+
+        let app = test::init_service(
+            App::new()
+            .app_data(Data::new(db.app_data()))
+            .configure(super::super::api_routes)
+        ).await;
+
+        let req = test::TestRequest::get().uri("/diskuto/homepage/stream").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/event-stream");
+        let mut body = resp.into_body().boxed();
+
+        let item = post(Timestamp::now().unix_utc_ms, "Hello, stream!");
+        let (row, signature) = sign_item(&user, &item);
+        let req = test::TestRequest::put()
+            .uri(&format!("/diskuto/users/{}/items/{}", user.0, signature.to_base58()))
+            .insert_header(("content-length", row.item_bytes.len()))
+            .set_payload(row.item_bytes)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+        let event = next_chunk(&mut body).await;
+        let data = event.lines().find_map(|line| line.strip_prefix("data: ")).unwrap();
+        let entry = ItemListEntry::parse_from_bytes(&BASE64.decode(data).unwrap()).unwrap();
+        assert_eq!(entry.signature.bytes, signature.bytes());
+
+        // Reconnecting from just before that item replays it:
+        let req = test::TestRequest::get()
+            .uri("/diskuto/homepage/stream?format=json")
+            .insert_header(("Last-Event-ID", (item.timestamp_ms_utc - 1).to_string()))
+            .to_request();
+        let mut body = test::call_service(&app, req).await.into_body().boxed();
+        let event = next_chunk(&mut body).await;
+        assert!(event.starts_with(&format!("id: {}\nevent: item\n", item.timestamp_ms_utc)));
+        assert!(event.contains(&signature.to_base58()));
+    }
+}
@@ -0,0 +1,122 @@
+//! Setup shared by the backend and server tests.
+
+use std::sync::Arc;
+
+use protobuf::{Message, MessageField};
+use sodiumoxide::crypto::{hash::sha512, sign};
+
+use crate::backend::{Factory, FactoryBuilder, ItemRow, SHA512, ServerUser, Signature, Timestamp, UserID, sqlite};
+use crate::protos::{Comment, File, Item, Post, ReplyRef};
+
+use super::{AppData, UnsafeMarkdownPolicy};
+
+/// A new database in a temp. directory, which is deleted when dropped.
+pub(crate) struct TestDb {
+    _dir: tempfile::TempDir,
+    pub builder: sqlite::FactoryBuilder,
+    pub factory: Box<dyn Factory>,
+}
+
+impl TestDb {
+    pub fn new() -> Self {
+        sodiumoxide::init().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let builder = sqlite::FactoryBuilder::new(dir.path().join("test.sqlite3").to_string_lossy().into());
+        builder.db_create().unwrap();
+        let factory = builder.factory().unwrap();
+        Self { _dir: dir, builder, factory }
+    }
+
+    /// AppData for this database, with default settings.
+    pub fn app_data(&self) -> AppData {
+        AppData{
+            backend_factory: self.factory.dyn_clone(),
+            unsafe_markdown: UnsafeMarkdownPolicy::Flag,
+            activitypub_url: None,
+            webhooks: super::webhooks::start(self.factory.dyn_clone()).unwrap(),
+            metrics: Arc::new(super::metrics::Metrics::new().unwrap()),
+            config: Default::default(),
+            rate_limits: Default::default(),
+        }
+    }
+
+    /// A new server user, and their secret key.
+    pub fn new_user(&self) -> (UserID, sign::SecretKey) {
+        let (public_key, secret_key) = sign::gen_keypair();
+        let user_id = UserID::from_vec(public_key.0.to_vec()).unwrap();
+        self.factory.open().unwrap().add_server_user(&ServerUser{
+            user: user_id.clone(),
+            notes: "test".into(),
+            on_homepage: true,
+        }).unwrap();
+        (user_id, secret_key)
+    }
+
+    pub fn save(&self, user: &(UserID, sign::SecretKey), item: &Item) -> Signature {
+        let (row, signature) = sign_item(user, item);
+        self.factory.open().unwrap().save_user_item(&row, item).unwrap();
+        signature
+    }
+
+    /// Store the contents of an attachment, as if it had been uploaded.
+    pub fn save_file(&self, contents: &[u8]) {
+        let hash = SHA512::from_digest(sha512::hash(contents));
+        self.factory.open().unwrap().save_attachment(contents.len() as u64, &hash, &mut &contents[..]).unwrap();
+    }
+}
+
+/// Sign an item as `user`, returning the row to save and its signature.
+pub(crate) fn sign_item((user_id, secret_key): &(UserID, sign::SecretKey), item: &Item) -> (ItemRow, Signature) {
+    let bytes = item.write_to_bytes().unwrap();
+    let signature = sign::sign_detached(&bytes, secret_key);
+    let signature = Signature::from_vec(signature.to_bytes().to_vec()).unwrap();
+    let row = ItemRow{
+        user: user_id.clone(),
+        signature: signature.clone(),
+        timestamp: Timestamp{ unix_utc_ms: item.timestamp_ms_utc },
+        received: Timestamp::now(),
+        item_bytes: bytes,
+        unsafe_markdown: false,
+    };
+    (row, signature)
+}
+
+pub(crate) fn post(timestamp: i64, body: &str) -> Item {
+    let mut item = Item::new();
+    item.timestamp_ms_utc = timestamp;
+    item.set_post({
+        let mut post = Post::new();
+        post.body = body.into();
+        post
+    });
+    item
+}
+
+/// A Post with these files attached.
+pub(crate) fn post_with_files(timestamp: i64, files: &[(&str, &[u8])]) -> Item {
+    let mut item = post(timestamp, "See attached.");
+    let attachments = item.mut_post().attachments.mut_or_insert_default();
+    for (name, contents) in files {
+        let mut file = File::new();
+        file.name = name.to_string();
+        file.size = contents.len() as u64;
+        file.hash = sha512::hash(contents).0.to_vec();
+        attachments.file.push(file);
+    }
+    item
+}
+
+pub(crate) fn comment(timestamp: i64, user_id: &UserID, signature: &Signature, text: &str) -> Item {
+    let mut reply_to = ReplyRef::new();
+    reply_to.user_id.mut_or_insert_default().bytes = user_id.bytes().to_vec();
+    reply_to.signature.mut_or_insert_default().bytes = signature.bytes().to_vec();
+    let mut item = Item::new();
+    item.timestamp_ms_utc = timestamp;
+    item.set_comment({
+        let mut comment = Comment::new();
+        comment.reply_to = MessageField::some(reply_to);
+        comment.text = text.into();
+        comment
+    });
+    item
+}
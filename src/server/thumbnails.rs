@@ -0,0 +1,209 @@
+//! `GET /diskuto/users/{userID}/items/{signature}/files/{fileName}?thumb=<size>`
+//!
+//! Scaled-down copies of image attachments, so that clients needn't download
+//! full-size photos just to show a preview. Each is generated on first request,
+//! then cached in the database by (hash, size).
+
+use std::io::Cursor;
+
+use actix_web::{HttpRequest, HttpResponse, http::header::{self, EntityTag}, web::Data};
+use anyhow::format_err;
+use futures::StreamExt;
+use image::{ImageFormat, ImageReader, Limits};
+
+use crate::backend::{FileMeta, Signature, Thumbnail, UserID};
+
+use super::{AppData, Error, PLAINTEXT, etag_matches, html::not_found, http_not_modified};
+
+/// Attachment types we know how to make thumbnails of.
+const THUMBNAIL_TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/gif"];
+
+/// Don't bother decoding files larger than this.
+const MAX_SOURCE_BYTES: u64 = 32 * 1024 * 1024;
+
+pub(crate) async fn get_thumbnail(
+    data: Data<AppData>,
+    req: &HttpRequest,
+    user_id: UserID,
+    signature: Signature,
+    file_name: &str,
+    meta: FileMeta,
+    size: u32,
+) -> Result<HttpResponse, Error> {
+    let sizes = &data.config.attachments.thumbnail_sizes;
+    if !sizes.contains(&size) {
+        let sizes: Vec<_> = sizes.iter().map(|s| s.to_string()).collect();
+        return Ok(bad_request(format!("Thumbnail size must be one of: {}", sizes.join(", "))));
+    }
+
+    let mime_type = data.config.attachments.mime_type(file_name);
+    if !THUMBNAIL_TYPES.contains(&mime_type.essence_str()) {
+        return Ok(bad_request("Thumbnails are only available for JPEG, PNG and GIF images".into()));
+    }
+
+    let etag = EntityTag::new_strong(format!("{}-{}", bs58::encode(meta.hash.bytes()).into_string(), size));
+    if etag_matches(req, &etag) {
+        let mut response = http_not_modified();
+        response.headers_mut().insert(header::ETAG, etag.to_string().parse().expect("valid ETag"));
+        return Ok(response);
+    }
+
+    let backend = data.backend_factory.open()?;
+    let thumbnail = match backend.get_thumbnail(&meta.hash, size)? {
+        Some(thumbnail) => thumbnail,
+        None => {
+            if meta.size > MAX_SOURCE_BYTES {
+                return Ok(bad_request(format!("Image is too large to make a thumbnail of. (Max: {} bytes)", MAX_SOURCE_BYTES)));
+            }
+
+            let contents = match backend.get_contents(user_id, signature, file_name, None)? {
+                Some(contents) => contents,
+                None => return not_found().await,
+            };
+            let mut bytes = Vec::with_capacity(contents.size as usize);
+            let mut stream = contents.stream;
+            while let Some(chunk) = stream.next().await {
+                bytes.extend_from_slice(&chunk?);
+            }
+
+            // Decoding & resizing is CPU-heavy, so keep it off of the async threads:
+            let thumbnail = match blocking::unblock(move || render(&bytes, size)).await {
+                Ok(thumbnail) => thumbnail,
+                Err(err) => return Ok(bad_request(format!("Couldn't make a thumbnail: {}", err))),
+            };
+            backend.save_thumbnail(&meta.hash, size, &thumbnail)?;
+            thumbnail
+        }
+    };
+
+    data.metrics.attachment_bytes_out(thumbnail.bytes.len() as u64);
+
+    Ok(
+        HttpResponse::Ok()
+        .content_type(thumbnail.content_type)
+        .insert_header(header::ETag(etag))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(thumbnail.bytes)
+    )
+}
+
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type(PLAINTEXT)
+        .body(message)
+}
+
+/// Scale an image down to fit within `size` x `size` pixels.
+///
+/// JPEGs stay JPEGs. Everything else becomes a PNG, to keep transparency.
+/// (GIFs lose their animation.)
+fn render(bytes: &[u8], size: u32) -> Result<Thumbnail, anyhow::Error> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = match reader.format() {
+        Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif)) => format,
+        _ => return Err(format_err!("Not a JPEG, PNG or GIF image")),
+    };
+
+    // Don't let a small, highly-compressed file use up all our memory:
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(16 * 1024);
+    limits.max_image_height = Some(16 * 1024);
+    limits.max_alloc = Some(512 * 1024 * 1024);
+    reader.limits(limits);
+
+    let mut image = reader.decode()?;
+    // Don't scale up images that are already small:
+    if image.width() > size || image.height() > size {
+        image = image.thumbnail(size, size);
+    }
+
+    let (output, content_type) = match format {
+        ImageFormat::Jpeg => (ImageFormat::Jpeg, "image/jpeg"),
+        _ => (ImageFormat::Png, "image/png"),
+    };
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, output)?;
+
+    Ok(Thumbnail {
+        content_type: content_type.into(),
+        bytes: out.into_inner(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test};
+    use sodiumoxide::crypto::hash::sha512;
+
+    use crate::backend::{PruneOpts, SHA512};
+    use crate::server::test_util::{TestDb, post_with_files};
+
+    use super::*;
+
+    /// A 1x1 transparent PNG.
+    const PNG: &[u8] = &[
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+        0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x64, 0x60, 0xf8, 0x5f,
+        0x0f, 0x00, 0x02, 0x87, 0x01, 0x80, 0xeb, 0x47, 0xba, 0x92, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45,
+        0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+    const TEXT: &[u8] = b"Not an image.";
+
+    /// A server with an image and a text file, and the URL prefix for its files.
+    fn with_files() -> (TestDb, String) {
+        let db = TestDb::new();
+        let user = db.new_user();
+        let signature = db.save(&user, &post_with_files(100, &[("dot.png", PNG), ("notes.txt", TEXT)]));
+        db.save_file(PNG);
+        db.save_file(TEXT);
+        let url = format!("/diskuto/users/{}/items/{}/files/", user.0, signature.to_base58());
+        (db, url)
+    }
+
+    fn png_hash() -> SHA512 {
+        SHA512::from_digest(sha512::hash(PNG))
+    }
+
+    #[actix_web::test]
+    async fn rejects_bad_requests() {
+        let (db, url) = with_files();
+        let app = test::init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let get = |path: &str| test::TestRequest::get().uri(&format!("{}{}", url, path)).to_request();
+
+        let resp = test::call_service(&app, get("dot.png?thumb=100")).await;
+        assert_eq!(400, resp.status());
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).contains("128, 256, 512, 1024"));
+
+        let resp = test::call_service(&app, get("notes.txt?thumb=128")).await;
+        assert_eq!(400, resp.status());
+
+        assert!(db.factory.open().unwrap().get_thumbnail(&png_hash(), 100).unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn cached() {
+        let (db, url) = with_files();
+        let app = test::init_service(App::new().app_data(Data::new(db.app_data())).configure(super::super::api_routes)).await;
+        let get = || test::TestRequest::get().uri(&format!("{}dot.png?thumb=128", url)).to_request();
+
+        let resp = test::call_service(&app, get()).await;
+        assert_eq!(200, resp.status());
+        assert_eq!("image/png", resp.headers().get(header::CONTENT_TYPE).unwrap());
+        let first = test::read_body(resp).await;
+        let backend = db.factory.open().unwrap();
+        let saved = backend.get_thumbnail(&png_hash(), 128).unwrap().expect("cached thumbnail");
+        assert_eq!(saved.bytes, &first[..]);
+
+        // A second request is served from the cache, not re-rendered:
+        backend.save_thumbnail(&png_hash(), 128, &Thumbnail{ content_type: "image/png".into(), bytes: b"cached".to_vec() }).unwrap();
+        let resp = test::call_service(&app, get()).await;
+        assert_eq!(200, resp.status());
+        assert_eq!(&test::read_body(resp).await[..], b"cached");
+
+        // `db prune --thumbnails`:
+        backend.prune(PruneOpts{ dry_run: false, attachments: false, items: false, thumbnails: true }).unwrap();
+        assert!(backend.get_thumbnail(&png_hash(), 128).unwrap().is_none());
+    }
+}
@@ -0,0 +1,225 @@
+//! Outgoing webhooks.
+//!
+//! After an Item or attachment is saved, we queue a JSON payload for each
+//! webhook added with `diskuto webhook add`. A background thread POSTs them,
+//! and retries failures with exponential backoff. The queue lives in the
+//! database, so deliveries survive restarts. ActivityPub deliveries to
+//! followers' inboxes share it. (See: activitypub.rs)
+//!
+//! Each request has an `X-Diskuto-Event` header (`item`, `file`, or `test`),
+//! and an `X-Diskuto-Signature: sha256=<hex>` header, which is the
+//! HMAC-SHA256 of the body, keyed with the webhook's secret.
+
+use std::{sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel}, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde_json::{Value, json};
+use sha2::Sha256;
+
+use crate::{backend::{DeliveryTarget, Factory, FileMeta, ItemRow, Timestamp, Webhook}, protos::Item, util::AsHex};
+
+use super::{AppData, activitypub};
+
+const EVENT_HEADER: &str = "X-Diskuto-Event";
+const SIGNATURE_HEADER: &str = "X-Diskuto-Signature";
+
+/// Give up on a delivery after this many failures.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Wait this long before the first retry. Doubles after each failure.
+const FIRST_RETRY: Duration = Duration::from_secs(30);
+const MAX_RETRY: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Check for due retries at least this often.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many deliveries to load from the queue at a time.
+const BATCH_SIZE: usize = 100;
+
+/// Wakes the delivery thread when new deliveries are queued.
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    sender: SyncSender<()>,
+}
+
+impl Notifier {
+    pub(crate) fn notify(&self) {
+        // If the channel is full, the thread is already going to wake up.
+        let _ = self.sender.try_send(());
+    }
+}
+
+/// Start a thread that delivers queued webhook payloads.
+/// It exits once every Notifier has been dropped.
+pub(crate) fn start(factory: Box<dyn Factory>) -> Result<Notifier, anyhow::Error> {
+    let (sender, receiver) = sync_channel(1);
+    std::thread::Builder::new()
+        .name("webhooks".into())
+        .spawn(move || deliver_loop(factory, receiver))?;
+    Ok(Notifier { sender })
+}
+
+/// Queue an event for a newly-saved Item.
+pub(crate) fn item_saved(data: &AppData, row: &ItemRow, item: &Item) {
+    queue(data.backend_factory.as_ref(), &data.webhooks, "item", item_payload(row, item));
+}
+
+/// Queue an event for a newly-saved attachment.
+pub(crate) fn file_saved(data: &AppData, row: &ItemRow, item: &Item, file_name: &str, meta: &FileMeta) {
+    let mut payload = item_payload(row, item);
+    payload["file"] = json!({
+        "name": file_name,
+        "size": meta.size,
+        "sha512": meta.hash.bytes().as_hex().to_string(),
+    });
+    queue(data.backend_factory.as_ref(), &data.webhooks, "file", payload);
+}
+
+fn queue(factory: &dyn Factory, notifier: &Notifier, event: &str, payload: Value) {
+    let queued = factory.open().and_then(|backend| {
+        backend.queue_webhook_deliveries(event, payload.to_string().as_bytes())
+    });
+    match queued {
+        Ok(0) => {},
+        Ok(_) => notifier.notify(),
+        // The Item was still saved, so don't fail the request:
+        Err(err) => warn!("Couldn't queue webhook deliveries: {:?}", err),
+    }
+}
+
+fn item_payload(row: &ItemRow, item: &Item) -> Value {
+    use crate::protos::item::Item_type::*;
+    let item_type = match item.item_type {
+        Some(Post(_)) => "post",
+        Some(Profile(_)) => "profile",
+        Some(Comment(_)) => "comment",
+        None => "unknown",
+    };
+
+    json!({
+        "userIdBase58": row.user.to_base58(),
+        "signatureBase58": row.signature.to_base58(),
+        "itemType": item_type,
+        "itemBytes": BASE64.encode(&row.item_bytes),
+    })
+}
+
+/// Send a test event to a webhook, right now.
+pub(crate) fn send_test(webhook: &Webhook) -> Result<(), anyhow::Error> {
+    let payload = json!({ "webhookId": webhook.id });
+    post(&agent(), webhook, "test", payload.to_string().as_bytes())
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .user_agent(concat!("diskuto/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+fn post(agent: &ureq::Agent, webhook: &Webhook, event: &str, payload: &[u8]) -> Result<(), anyhow::Error> {
+    agent.post(&webhook.url)
+        .set("Content-Type", "application/json")
+        .set(EVENT_HEADER, event)
+        .set(SIGNATURE_HEADER, &sign(&webhook.secret, payload))
+        .send_bytes(payload)?;
+    Ok(())
+}
+
+/// The value of the signature header for a payload.
+fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    format!("sha256={}", mac.finalize().into_bytes().as_slice().as_hex())
+}
+
+fn deliver_loop(factory: Box<dyn Factory>, receiver: Receiver<()>) {
+    let agent = agent();
+    loop {
+        loop {
+            match deliver_due(factory.as_ref(), &agent) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(err) => {
+                    warn!("Error delivering webhooks: {:?}", err);
+                    break;
+                }
+            }
+        }
+
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Attempt one batch of due deliveries. Returns true if there may be more.
+fn deliver_due(factory: &dyn Factory, agent: &ureq::Agent) -> Result<bool, anyhow::Error> {
+    let mut due = vec![];
+    factory.open()?.due_webhook_deliveries(Timestamp::now(), &mut |delivery| {
+        due.push(delivery);
+        Ok(due.len() < BATCH_SIZE)
+    })?;
+    let has_more = due.len() >= BATCH_SIZE;
+
+    for delivery in due {
+        // Don't hold a pooled connection while we wait on the network:
+        let result = match &delivery.target {
+            DeliveryTarget::Webhook(webhook) => post(agent, webhook, &delivery.event, &delivery.payload),
+            DeliveryTarget::ActivityPub{user, inbox} => activitypub::deliver(factory, user, inbox, &delivery.payload),
+        };
+        let backend = factory.open()?;
+
+        let err = match result {
+            Ok(()) => {
+                backend.remove_webhook_delivery(delivery.id)?;
+                continue;
+            },
+            Err(err) => err,
+        };
+
+        let attempts = delivery.attempts + 1;
+        if attempts >= MAX_ATTEMPTS {
+            warn!("Giving up on {} delivery to {} after {} attempts: {}", delivery.event, delivery.target.url(), attempts, err);
+            backend.remove_webhook_delivery(delivery.id)?;
+            continue;
+        }
+
+        let delay = retry_delay(attempts);
+        info!("Delivery to {} failed, will retry in {}s: {}", delivery.target.url(), delay.as_secs(), err);
+        let next_attempt = Timestamp{ unix_utc_ms: Timestamp::now().unix_utc_ms + delay.as_millis() as i64 };
+        backend.retry_webhook_delivery(delivery.id, next_attempt, &err.to_string())?;
+    }
+
+    Ok(has_more)
+}
+
+/// How long to wait after a delivery has failed `attempts` times.
+fn retry_delay(attempts: u32) -> Duration {
+    let factor = 1u32 << attempts.saturating_sub(1).min(16);
+    (FIRST_RETRY * factor).min(MAX_RETRY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // From RFC 4231, test case 2:
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(60));
+        assert_eq!(retry_delay(20), MAX_RETRY);
+    }
+}